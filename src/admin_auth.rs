@@ -0,0 +1,699 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock, RwLock};
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::{debug, error, info, warn};
+
+use crate::settings::AuthSettings;
+
+pub(crate) const SESSION_COOKIE: &str = "poe2openai_session";
+/// 前端讀取後放在 X-CSRF-Token 標頭中送回
+pub(crate) const CSRF_COOKIE: &str = "poe2openai_csrf";
+pub(crate) const CSRF_HEADER: &str = "x-csrf-token";
+
+/// 舊版內建的預設密碼，仍在使用時停用管理介面
+const DEFAULT_PASSWORD: &str = "123456";
+const MIN_PASSWORD_LENGTH: usize = 8;
+const NO_PASSWORD: &str = "尚未設定管理員密碼，請設定 admin_password_hash 或 admin_password_hash_file";
+
+/// 管理員的權限，後者包含前者的所有權限
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    /// 只能查看配置及統計
+    Viewer,
+    /// 可以修改 models.yaml
+    Editor,
+    /// 可以管理代理金鑰、金鑰池及管理員帳號
+    Owner,
+}
+
+impl Role {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+}
+
+/// 設定檔中的管理員帳號，擁有 owner 權限
+pub(crate) struct Credentials {
+    username: String,
+    password_hash: Option<String>,
+    totp: Option<TOTP>,
+    /// 管理介面停用的原因
+    disabled: Option<&'static str>,
+}
+
+/// 由管理介面新增的管理員帳號
+#[derive(Serialize, Deserialize, Clone)]
+struct AdminUser {
+    username: String,
+    password_hash: String,
+    role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    totp_secret: Option<String>,
+    created_at: i64,
+}
+
+/// 管理介面顯示的帳號資訊，不含雜湊及 TOTP 密鑰
+#[derive(Serialize)]
+pub(crate) struct UserView {
+    username: String,
+    role: Role,
+    totp: bool,
+    created_at: Option<i64>,
+    /// 設定檔中的帳號，不能從管理介面修改
+    builtin: bool,
+}
+
+impl From<&AdminUser> for UserView {
+    fn from(user: &AdminUser) -> Self {
+        Self {
+            username: user.username.clone(),
+            role: user.role,
+            totp: user.totp_secret.is_some(),
+            created_at: Some(user.created_at),
+            builtin: false,
+        }
+    }
+}
+
+/// 新增管理員的參數
+#[derive(Deserialize)]
+pub(crate) struct NewUser {
+    username: String,
+    password: String,
+    role: Role,
+    /// Base32 的 TOTP 密鑰，設定後登入需輸入驗證碼
+    totp_secret: Option<String>,
+}
+
+/// 修改管理員的參數，未指定的欄位保持不變
+#[derive(Deserialize)]
+pub(crate) struct UserUpdate {
+    role: Option<Role>,
+    password: Option<String>,
+    totp_secret: Option<String>,
+}
+
+/// 登入時比對的帳號
+struct Account {
+    password_hash: Option<String>,
+    totp: Option<TOTP>,
+    role: Role,
+}
+
+/// 登入成功後的工作階段
+#[derive(Clone)]
+pub(crate) struct Session {
+    pub(crate) username: String,
+    pub(crate) role: Role,
+    pub(crate) csrf_token: String,
+    expires_at: i64,
+}
+
+#[derive(Debug)]
+pub(crate) enum LoginError {
+    Disabled(&'static str),
+    Invalid,
+    /// 密碼正確但未附上 TOTP 驗證碼
+    TotpRequired,
+    /// 剩餘的鎖定秒數
+    Locked(i64),
+}
+
+impl std::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginError::Disabled(reason) => write!(f, "管理介面已停用：{}", reason),
+            LoginError::Invalid => f.write_str("用戶名、密碼或驗證碼錯誤"),
+            LoginError::TotpRequired => f.write_str("請輸入兩步驟驗證碼"),
+            LoginError::Locked(seconds) => write!(f, "登入失敗次數過多，請於 {} 秒後再試", seconds),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Attempts {
+    failures: u32,
+    locked_until: Option<i64>,
+}
+
+fn credentials() -> &'static Credentials {
+    static CREDENTIALS: OnceLock<Credentials> = OnceLock::new();
+    CREDENTIALS.get_or_init(|| load(&crate::settings::get().auth).unwrap_or_else(|e| {
+        let reason: &'static str = Box::leak(e.into_boxed_str());
+        Credentials {
+            username: String::new(),
+            password_hash: None,
+            totp: None,
+            disabled: Some(reason),
+        }
+    }))
+}
+
+fn sessions() -> &'static Mutex<HashMap<String, Session>> {
+    static SESSIONS: OnceLock<Mutex<HashMap<String, Session>>> = OnceLock::new();
+    SESSIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn users() -> &'static RwLock<Vec<AdminUser>> {
+    static USERS: OnceLock<RwLock<Vec<AdminUser>>> = OnceLock::new();
+    USERS.get_or_init(|| RwLock::new(Vec::new()))
+}
+
+fn users_path() -> &'static Path {
+    &crate::settings::get().auth.users_file
+}
+
+fn attempts() -> &'static Mutex<HashMap<String, Attempts>> {
+    static ATTEMPTS: OnceLock<Mutex<HashMap<String, Attempts>>> = OnceLock::new();
+    ATTEMPTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// 每位管理員最後一次使用的 TOTP 時間步，同一個驗證碼不能重複使用
+fn used_totp_steps() -> &'static Mutex<HashMap<String, u64>> {
+    static STEPS: OnceLock<Mutex<HashMap<String, u64>>> = OnceLock::new();
+    STEPS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn read_secret_file(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map(|contents| contents.trim().to_string())
+        .map_err(|e| format!("無法讀取 {}: {}", path.display(), e))
+}
+
+pub(crate) fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).map_err(|e| e.to_string())?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+/// 找不到帳號時仍驗證一次，避免從回應時間判斷用戶名是否存在
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("poe2openai").unwrap_or_default())
+}
+
+fn parse_totp(secret: &str) -> Result<TOTP, String> {
+    let secret = secret.replace(' ', "").to_uppercase();
+    let bytes = Secret::Encoded(secret).to_bytes().map_err(|e| format!("totp_secret 必須是 Base32: {}", e))?;
+    TOTP::new(Algorithm::SHA1, 6, 1, 30, bytes).map_err(|e| format!("totp_secret 無效: {}", e))
+}
+
+/// 驗證碼在允許誤差內符合的時間步
+fn totp_step(totp: &TOTP, code: &str, now: u64) -> Option<u64> {
+    let current = now / totp.step;
+    let skew = u64::from(totp.skew);
+    (current.saturating_sub(skew)..=current + skew)
+        .find(|step| crate::utils::constant_time_eq(totp.generate(step * totp.step).as_bytes(), code.as_bytes()))
+}
+
+/// 驗證碼正確且時間步晚於該帳號上次使用的時間步時記錄並回傳 true
+fn accept_totp(username: &str, totp: &TOTP, code: &str, now: u64) -> bool {
+    let Some(step) = totp_step(totp, code, now) else {
+        return false;
+    };
+    let mut used = used_totp_steps().lock().unwrap();
+    if used.get(username).is_some_and(|last| step <= *last) {
+        return false;
+    }
+    used.insert(username.to_string(), step);
+    true
+}
+
+fn check_password_strength(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!("密碼至少需要 {} 個字元", MIN_PASSWORD_LENGTH));
+    }
+    if password == DEFAULT_PASSWORD {
+        return Err("不可使用預設密碼".to_string());
+    }
+    Ok(())
+}
+
+/// `hash-password` 子命令：從標準輸入讀取一行密碼
+pub(crate) fn hash_from_stdin() -> Result<String, String> {
+    eprint!("請輸入管理員密碼: ");
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password).map_err(|e| e.to_string())?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    check_password_strength(password)?;
+    hash_password(password)
+}
+
+/// 依設定載入密碼雜湊及 TOTP 密鑰；設定格式錯誤時回傳錯誤，仍使用預設密碼時停用管理介面
+pub(crate) fn load(auth: &AuthSettings) -> Result<Credentials, String> {
+    let password_hash = match (&auth.admin_password_hash, &auth.admin_password_hash_file, &auth.admin_password) {
+        (Some(hash), _, _) => Some(hash.trim().to_string()),
+        (None, Some(path), _) => Some(read_secret_file(path)?),
+        (None, None, Some(password)) if password == DEFAULT_PASSWORD => None,
+        (None, None, Some(password)) => Some(hash_password(password)?),
+        (None, None, None) => None,
+    };
+    if let Some(hash) = &password_hash {
+        PasswordHash::new(hash).map_err(|e| format!("admin_password_hash 不是有效的 Argon2 雜湊: {}", e))?;
+    }
+
+    let totp_secret = match (&auth.totp_secret, &auth.totp_secret_file) {
+        (Some(secret), _) => Some(secret.clone()),
+        (None, Some(path)) => Some(read_secret_file(path)?),
+        (None, None) => None,
+    };
+    let totp = totp_secret.as_deref().map(parse_totp).transpose()?;
+
+    // 未設定密碼時仍可使用管理介面新增的帳號登入，見 disabled_reason
+    let disabled = match &password_hash {
+        None if auth.admin_password.as_deref() == Some(DEFAULT_PASSWORD) => Some("仍在使用預設密碼，請設定 admin_password_hash"),
+        Some(hash) if verify_password(hash, DEFAULT_PASSWORD) => Some("仍在使用預設密碼，請更換 admin_password_hash"),
+        _ => None,
+    };
+    Ok(Credentials {
+        username: auth.admin_username.clone(),
+        password_hash,
+        totp,
+        disabled,
+    })
+}
+
+/// 啟動時載入管理員帳號並記錄管理介面狀態
+pub(crate) fn init() {
+    load_users();
+    let credentials = credentials();
+    match disabled_reason() {
+        Some(reason) => warn!("🔒 管理介面已停用: {}", reason),
+        None => info!("🔐 管理介面已啟用 | 用戶名: {} | 兩步驟驗證: {} | 其他管理員: {} 位",
+            credentials.username,
+            if credentials.totp.is_some() { "是" } else { "否" },
+            users().read().unwrap().len()),
+    }
+    if crate::settings::get().auth.admin_password.is_some() && crate::settings::get().auth.admin_password_hash.is_none() {
+        warn!("⚠️ 正在使用明文 admin_password，建議改用 admin_password_hash");
+    }
+}
+
+fn load_users() {
+    let path = users_path();
+    if !path.exists() {
+        debug!("👥 管理員帳號檔案不存在，只使用設定檔中的帳號");
+        return;
+    }
+    let loaded = std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| serde_json::from_str::<Vec<AdminUser>>(&contents).map_err(|e| e.to_string()));
+    match loaded {
+        Ok(loaded) => {
+            info!("👥 已載入管理員帳號 | 數量: {}", loaded.len());
+            *users().write().unwrap() = loaded;
+        },
+        Err(e) => error!("❌ 載入管理員帳號失敗: {}", e),
+    }
+}
+
+/// 檔案中有密碼雜湊及 TOTP 密鑰，只允許擁有者讀寫
+fn persist_users(users: &[AdminUser]) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(users).map_err(|e| e.to_string())?;
+    crate::utils::write_atomic_private(users_path(), json)
+        .map_err(|e| format!("寫入管理員帳號檔案失敗: {}", e))
+}
+
+/// 管理介面停用的原因，None 表示可以登入
+pub(crate) fn disabled_reason() -> Option<&'static str> {
+    let credentials = credentials();
+    if credentials.disabled.is_some() {
+        return credentials.disabled;
+    }
+    if credentials.password_hash.is_none() && users().read().unwrap().is_empty() {
+        return Some(NO_PASSWORD);
+    }
+    None
+}
+
+/// 是否有任何帳號需要輸入 TOTP 驗證碼
+pub(crate) fn totp_enabled() -> bool {
+    credentials().totp.is_some() || users().read().unwrap().iter().any(|user| user.totp_secret.is_some())
+}
+
+fn find_account(username: &str) -> Option<Account> {
+    let credentials = credentials();
+    if username == credentials.username {
+        return Some(Account {
+            password_hash: credentials.password_hash.clone(),
+            totp: credentials.totp.clone(),
+            role: Role::Owner,
+        });
+    }
+    let users = users().read().unwrap();
+    let user = users.iter().find(|user| user.username == username)?;
+    Some(Account {
+        password_hash: Some(user.password_hash.clone()),
+        // 密鑰在新增時已驗證過
+        totp: user.totp_secret.as_deref().and_then(|secret| parse_totp(secret).ok()),
+        role: user.role,
+    })
+}
+
+fn random_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// 尚在鎖定中的剩餘秒數
+fn locked_for(keys: &[String], now: i64) -> Option<i64> {
+    let attempts = attempts().lock().unwrap();
+    keys.iter()
+        .filter_map(|key| attempts.get(key)?.locked_until)
+        .filter(|until| *until > now)
+        .max()
+        .map(|until| until - now)
+}
+
+fn record_failure(keys: &[String], now: i64) {
+    let auth = &crate::settings::get().auth;
+    let mut attempts = attempts().lock().unwrap();
+    for key in keys {
+        let entry = attempts.entry(key.clone()).or_default();
+        entry.failures += 1;
+        if entry.failures >= auth.max_login_attempts {
+            entry.failures = 0;
+            entry.locked_until = Some(now + auth.lockout_duration as i64);
+            warn!("🔒 登入失敗次數過多，鎖定 {} 秒 | {}", auth.lockout_duration, key);
+        }
+    }
+}
+
+fn clear_failures(keys: &[String]) {
+    let mut attempts = attempts().lock().unwrap();
+    for key in keys {
+        attempts.remove(key);
+    }
+}
+
+/// 驗證帳號密碼及 TOTP，成功時建立工作階段；同一 IP 或用戶名連續失敗過多次會被鎖定
+pub(crate) fn login(username: &str, password: &str, totp_code: Option<&str>, ip: &str) -> Result<(String, Session), LoginError> {
+    if let Some(reason) = disabled_reason() {
+        return Err(LoginError::Disabled(reason));
+    }
+    let now = Utc::now().timestamp();
+    let keys = [format!("ip:{}", ip), format!("user:{}", username)];
+    if let Some(seconds) = locked_for(&keys, now) {
+        return Err(LoginError::Locked(seconds));
+    }
+
+    let account = find_account(username);
+    let password_hash = account.as_ref().and_then(|account| account.password_hash.as_deref());
+    let password_ok = verify_password(password_hash.unwrap_or(dummy_hash()), password) && password_hash.is_some();
+    let Some(account) = account.filter(|_| password_ok) else {
+        warn!("🚫 管理介面登入失敗 | 用戶名: {} | IP: {}", username, ip);
+        record_failure(&keys, now);
+        return Err(LoginError::Invalid);
+    };
+    if let Some(totp) = &account.totp {
+        let Some(code) = totp_code.map(str::trim).filter(|code| !code.is_empty()) else {
+            return Err(LoginError::TotpRequired);
+        };
+        if !accept_totp(username, totp, code, now as u64) {
+            warn!("🚫 兩步驟驗證碼錯誤或已使用過 | 用戶名: {} | IP: {}", username, ip);
+            record_failure(&keys, now);
+            return Err(LoginError::Invalid);
+        }
+    }
+    clear_failures(&keys);
+
+    let session_id = random_token();
+    let session = Session {
+        username: username.to_string(),
+        role: account.role,
+        csrf_token: random_token(),
+        expires_at: now + crate::settings::get().auth.session_ttl as i64,
+    };
+    let mut sessions = sessions().lock().unwrap();
+    sessions.retain(|_, session| session.expires_at > now);
+    sessions.insert(session_id.clone(), session.clone());
+    info!("🔓 管理介面登入成功 | 用戶名: {} | 權限: {} | IP: {}", username, account.role.name(), ip);
+    Ok((session_id, session))
+}
+
+/// 取得尚未過期的工作階段
+pub(crate) fn session(session_id: &str) -> Option<Session> {
+    let now = Utc::now().timestamp();
+    let mut sessions = sessions().lock().unwrap();
+    match sessions.get(session_id) {
+        Some(session) if session.expires_at > now => Some(session.clone()),
+        Some(_) => {
+            sessions.remove(session_id);
+            None
+        },
+        None => None,
+    }
+}
+
+pub(crate) fn logout(session_id: &str) {
+    if let Some(session) = sessions().lock().unwrap().remove(session_id) {
+        info!("🔒 管理介面已登出 | 用戶名: {}", session.username);
+    }
+}
+
+/// 帳號被刪除或修改後，讓其工作階段失效
+fn end_sessions(username: &str) {
+    sessions().lock().unwrap().retain(|_, session| session.username != username);
+}
+
+/// 列出所有管理員，包含設定檔中的帳號
+pub(crate) fn list_users() -> Vec<UserView> {
+    let credentials = credentials();
+    let mut views = Vec::new();
+    if credentials.password_hash.is_some() {
+        views.push(UserView {
+            username: credentials.username.clone(),
+            role: Role::Owner,
+            totp: credentials.totp.is_some(),
+            created_at: None,
+            builtin: true,
+        });
+    }
+    views.extend(users().read().unwrap().iter().map(UserView::from));
+    views
+}
+
+fn check_not_builtin(username: &str) -> Result<(), String> {
+    if username == credentials().username {
+        return Err(format!("`{}` 是設定檔中的帳號，請直接修改設定檔", username));
+    }
+    Ok(())
+}
+
+/// 沒有設定檔中的帳號時，至少要保留一位 owner，避免無法再管理帳號
+fn check_owner_remains(users: &[AdminUser]) -> Result<(), String> {
+    if credentials().password_hash.is_none() && !users.iter().any(|user| user.role == Role::Owner) {
+        return Err("至少需要保留一位 owner".to_string());
+    }
+    Ok(())
+}
+
+/// 新增管理員
+pub(crate) fn create_user(new_user: NewUser) -> Result<UserView, String> {
+    let username = new_user.username.trim().to_string();
+    if username.is_empty() {
+        return Err("用戶名不可為空".to_string());
+    }
+    check_not_builtin(&username)?;
+    check_password_strength(&new_user.password)?;
+    let totp_secret = new_user.totp_secret.filter(|secret| !secret.trim().is_empty());
+    if let Some(secret) = &totp_secret {
+        parse_totp(secret)?;
+    }
+    let user = AdminUser {
+        username,
+        password_hash: hash_password(&new_user.password)?,
+        role: new_user.role,
+        totp_secret,
+        created_at: Utc::now().timestamp(),
+    };
+
+    let mut users = users().write().unwrap();
+    if users.iter().any(|existing| existing.username == user.username) {
+        return Err(format!("管理員 `{}` 已存在", user.username));
+    }
+    let view = UserView::from(&user);
+    // 先寫入檔案再更新記憶體，寫入失敗時帳號不會生效
+    let mut updated = users.clone();
+    updated.push(user);
+    persist_users(&updated)?;
+    *users = updated;
+    info!("👥 已新增管理員 | 用戶名: {} | 權限: {}", view.username, view.role.name());
+    Ok(view)
+}
+
+/// 修改管理員的權限、密碼或 TOTP 密鑰；密鑰傳入空字串表示停用 TOTP
+pub(crate) fn update_user(username: &str, update: UserUpdate) -> Result<bool, String> {
+    check_not_builtin(username)?;
+    if let Some(password) = &update.password {
+        check_password_strength(password)?;
+    }
+    let totp_secret = update.totp_secret.map(|secret| Some(secret).filter(|secret| !secret.trim().is_empty()));
+    if let Some(Some(secret)) = &totp_secret {
+        parse_totp(secret)?;
+    }
+    let password_hash = update.password.as_deref().map(hash_password).transpose()?;
+
+    let mut users = users().write().unwrap();
+    let mut updated = users.clone();
+    let Some(user) = updated.iter_mut().find(|user| user.username == username) else {
+        return Ok(false);
+    };
+    if let Some(role) = update.role {
+        user.role = role;
+    }
+    if let Some(password_hash) = password_hash {
+        user.password_hash = password_hash;
+    }
+    if let Some(totp_secret) = totp_secret {
+        user.totp_secret = totp_secret;
+    }
+    check_owner_remains(&updated)?;
+    persist_users(&updated)?;
+    *users = updated;
+    drop(users);
+    end_sessions(username);
+    info!("👥 已修改管理員 | 用戶名: {}", username);
+    Ok(true)
+}
+
+/// 刪除管理員
+pub(crate) fn delete_user(username: &str) -> Result<bool, String> {
+    check_not_builtin(username)?;
+    let mut users = users().write().unwrap();
+    let remaining: Vec<AdminUser> = users.iter().filter(|user| user.username != username).cloned().collect();
+    if remaining.len() == users.len() {
+        return Ok(false);
+    }
+    check_owner_remains(&remaining)?;
+    persist_users(&remaining)?;
+    *users = remaining;
+    drop(users);
+    end_sessions(username);
+    info!("🗑️ 已刪除管理員 | 用戶名: {}", username);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOTP_SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+
+    fn session_with_expiry(username: &str, expires_at: i64) -> Session {
+        Session {
+            username: username.to_string(),
+            role: Role::Viewer,
+            csrf_token: random_token(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn password_hash_round_trip() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password(&hash, "correct horse"));
+        assert!(!verify_password(&hash, "wrong horse"));
+        assert!(!verify_password("not a hash", "correct horse"));
+        // 每次雜湊使用不同的 salt
+        assert_ne!(hash, hash_password("correct horse").unwrap());
+    }
+
+    #[test]
+    fn weak_passwords_are_rejected() {
+        assert!(check_password_strength("short").is_err());
+        assert!(check_password_strength("long enough").is_ok());
+    }
+
+    #[test]
+    fn lockout_applies_to_the_ip_and_the_username() {
+        let now = Utc::now().timestamp();
+        let max_attempts = crate::settings::get().auth.max_login_attempts;
+        let keys = ["ip:10.0.0.1".to_string(), "user:lockout".to_string()];
+        for _ in 1..max_attempts {
+            record_failure(&keys, now);
+        }
+        assert!(locked_for(&keys, now).is_none());
+        record_failure(&keys, now);
+
+        let lockout = crate::settings::get().auth.lockout_duration as i64;
+        // 換用戶名或換 IP 都仍被鎖定
+        assert_eq!(locked_for(&["ip:10.0.0.1".to_string(), "user:other".to_string()], now), Some(lockout));
+        assert_eq!(locked_for(&["ip:10.0.0.2".to_string(), "user:lockout".to_string()], now), Some(lockout));
+        assert!(locked_for(&["ip:10.0.0.2".to_string(), "user:other".to_string()], now).is_none());
+        // 鎖定時間結束後解除
+        assert!(locked_for(&keys, now + lockout).is_none());
+    }
+
+    #[test]
+    fn successful_login_clears_failures() {
+        let now = Utc::now().timestamp();
+        let max_attempts = crate::settings::get().auth.max_login_attempts;
+        let keys = ["ip:10.0.0.3".to_string(), "user:cleared".to_string()];
+        for _ in 1..max_attempts {
+            record_failure(&keys, now);
+        }
+        clear_failures(&keys);
+        record_failure(&keys, now);
+        assert!(locked_for(&keys, now).is_none());
+    }
+
+    #[test]
+    fn totp_accepts_codes_within_the_skew() {
+        let totp = parse_totp(TOTP_SECRET).unwrap();
+        let now = 1_700_000_000;
+        assert!(totp_step(&totp, &totp.generate(now), now).is_some());
+        assert!(totp_step(&totp, &totp.generate(now - 30), now).is_some());
+        assert!(totp_step(&totp, &totp.generate(now + 30), now).is_some());
+        assert!(totp_step(&totp, &totp.generate(now - 90), now).is_none());
+        assert!(totp_step(&totp, "000000x", now).is_none());
+        // 密鑰不分大小寫且可包含空白
+        assert!(parse_totp("jbsw y3dp ehpk 3pxp jbsw y3dp ehpk 3pxp").is_ok());
+        assert!(parse_totp("not base32!").is_err());
+    }
+
+    #[test]
+    fn totp_codes_cannot_be_reused() {
+        let totp = parse_totp(TOTP_SECRET).unwrap();
+        let now = 1_700_000_000;
+        let code = totp.generate(now);
+        assert!(accept_totp("totp-replay", &totp, &code, now));
+        assert!(!accept_totp("totp-replay", &totp, &code, now));
+        // 已使用較新的時間步後，較舊但仍在誤差內的驗證碼也不接受
+        assert!(!accept_totp("totp-replay", &totp, &totp.generate(now - 30), now));
+        assert!(accept_totp("totp-replay", &totp, &totp.generate(now + 30), now + 30));
+        // 每位管理員分開記錄
+        assert!(accept_totp("totp-other", &totp, &code, now));
+    }
+
+    #[test]
+    fn expired_sessions_are_removed() {
+        let now = Utc::now().timestamp();
+        sessions().lock().unwrap().insert("session-valid".to_string(), session_with_expiry("alice", now + 60));
+        sessions().lock().unwrap().insert("session-expired".to_string(), session_with_expiry("bob", now - 1));
+        assert_eq!(session("session-valid").map(|session| session.username), Some("alice".to_string()));
+        assert!(session("session-expired").is_none());
+        assert!(!sessions().lock().unwrap().contains_key("session-expired"));
+
+        logout("session-valid");
+        assert!(session("session-valid").is_none());
+    }
+}
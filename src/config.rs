@@ -0,0 +1,204 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info, warn};
+
+use crate::history::{Action, Actor};
+use crate::routing::ModelIndex;
+use crate::types::Config;
+
+/// 儲存與重新載入互斥，避免 ETag 檢查後配置被其他請求改掉
+static SAVE_LOCK: Mutex<()> = Mutex::new(());
+
+/// 儲存配置失敗的原因
+#[derive(Debug)]
+pub(crate) enum SaveError {
+    /// 配置已被其他人修改，附上目前的 ETag
+    Conflict(String),
+    Invalid(String),
+    Io(String),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Conflict(_) => f.write_str("配置已被其他管理員修改，請重新讀取後再儲存"),
+            SaveError::Invalid(message) => write!(f, "配置無效: {}", message),
+            SaveError::Io(message) => write!(f, "寫入 models.yaml 失敗: {}", message),
+        }
+    }
+}
+
+fn models_path() -> &'static Path {
+    &crate::settings::get().models.file
+}
+
+/// 目前生效的 models.yaml 配置，讀取端只需複製 Arc，更新時整個替換
+fn store() -> &'static RwLock<Arc<Config>> {
+    static STORE: OnceLock<RwLock<Arc<Config>>> = OnceLock::new();
+    STORE.get_or_init(|| RwLock::new(Arc::new(disabled_config())))
+}
+
+fn disabled_config() -> Config {
+    Config {
+        enable: Some(false),
+        ..Config::default()
+    }
+}
+
+/// 建立模型查詢索引；公開名稱重複或規則無效時拒絕此配置
+fn with_index(mut config: Config) -> Result<Config, String> {
+    config.index = ModelIndex::build(&config).map_err(|errors| {
+        errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+    })?;
+    Ok(config)
+}
+
+/// 檢查配置內容，回傳所有錯誤訊息
+pub(crate) fn validate(config: &Config) -> Vec<String> {
+    crate::settings::check_models(config).into_iter().map(|issue| issue.message).collect()
+}
+
+/// 取得目前的配置快照
+pub(crate) fn current() -> Arc<Config> {
+    store().read().unwrap().clone()
+}
+
+fn swap(config: Config) {
+    *store().write().unwrap() = Arc::new(config);
+}
+
+/// 從磁碟重新載入 models.yaml；讀取、解析或驗證失敗時保留目前的配置
+///
+/// 讀檔前就取得 SAVE_LOCK，避免讀到舊檔後覆蓋同時儲存的新配置
+pub(crate) fn reload() -> Result<(), String> {
+    let _guard = SAVE_LOCK.lock().unwrap();
+    let path = models_path();
+    if !path.exists() {
+        debug!("⚠️ models.yaml 不存在，預設為不啟用");
+        swap(disabled_config());
+        return Ok(());
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("讀取 models.yaml 失敗: {}", e))?;
+    let config = serde_yaml::from_str::<Config>(&contents)
+        .map_err(|e| format!("解析 models.yaml 失敗: {}", e))?;
+    let errors = validate(&config);
+    if !errors.is_empty() {
+        return Err(format!("models.yaml 無效: {}", errors.join("; ")));
+    }
+    let config = with_index(config).map_err(|e| format!("models.yaml 無效: {}", e))?;
+    info!("📦 已載入 models.yaml | 模型設定數量: {}", config.models.len());
+    let previous = crate::history::snapshot(&current());
+    let snapshot = crate::history::snapshot(&config);
+    swap(config);
+    crate::history::record(&previous, &snapshot, Action::Reload, None, None);
+    Ok(())
+}
+
+/// 未指定或指定 `*` 時不檢查；不符時回傳目前的 ETag
+fn check_etag(previous: &serde_json::Value, expected_etag: Option<&str>) -> Result<(), String> {
+    let Some(expected) = expected_etag.filter(|expected| *expected != "*") else {
+        return Ok(());
+    };
+    let current_etag = crate::history::etag(previous);
+    if expected == current_etag {
+        Ok(())
+    } else {
+        Err(current_etag)
+    }
+}
+
+/// 寫入 models.yaml、立即更新快取並記錄版本，回傳新的 ETag
+///
+/// expected_etag 與目前配置不符時拒絕寫入；rollback_from 為回復時的來源版本
+pub(crate) fn save(config: Config, expected_etag: Option<&str>, actor: Actor, rollback_from: Option<u64>) -> Result<String, SaveError> {
+    let _guard = SAVE_LOCK.lock().unwrap();
+    let previous = crate::history::snapshot(&current());
+    if let Err(current_etag) = check_etag(&previous, expected_etag) {
+        warn!("⚠️ 配置 ETag 不符，拒絕儲存 | 管理員: {} | 預期: {} | 目前: {}", actor.user, expected_etag.unwrap_or_default(), current_etag);
+        return Err(SaveError::Conflict(current_etag));
+    }
+
+    let errors = validate(&config);
+    if !errors.is_empty() {
+        return Err(SaveError::Invalid(errors.join("; ")));
+    }
+    let config = with_index(config).map_err(SaveError::Invalid)?;
+    let yaml = serde_yaml::to_string(&config).map_err(|e| SaveError::Invalid(e.to_string()))?;
+    crate::utils::write_atomic(models_path(), &yaml).map_err(|e| SaveError::Io(e.to_string()))?;
+    info!("💾 已儲存 models.yaml | 模型設定數量: {} | 管理員: {}", config.models.len(), actor.user);
+    let snapshot = crate::history::snapshot(&config);
+    let etag = crate::history::etag(&snapshot);
+    swap(config);
+
+    let action = if rollback_from.is_some() { Action::Rollback } else { Action::Save };
+    crate::history::record(&previous, &snapshot, action, Some(actor), rollback_from);
+    Ok(etag)
+}
+
+/// 啟動時載入配置，並在背景輪詢檔案變更
+pub(crate) fn init() {
+    if let Err(e) = reload() {
+        error!("❌ {}，暫時停用模型設定", e);
+    }
+
+    let interval = Duration::from_secs(crate::settings::get().models.reload_interval);
+    if interval.is_zero() {
+        debug!("⏸️ 已停用 models.yaml 自動重新載入");
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut last_seen = file_signature();
+        loop {
+            tokio::time::sleep(interval).await;
+            let signature = file_signature();
+            if signature == last_seen {
+                continue;
+            }
+            last_seen = signature;
+            info!("🔄 偵測到 models.yaml 變更，重新載入");
+            if let Err(e) = reload() {
+                warn!("⚠️ {}，保留先前的配置", e);
+            }
+        }
+    });
+}
+
+/// 以修改時間與檔案大小判斷檔案是否變更
+fn file_signature() -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(models_path()).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{etag, snapshot};
+
+    fn actor() -> Actor {
+        Actor { user: "tester".to_string(), ip: "127.0.0.1".to_string() }
+    }
+
+    #[test]
+    fn etag_check_accepts_the_current_etag_or_a_wildcard() {
+        let previous = snapshot(&disabled_config());
+        assert!(check_etag(&previous, None).is_ok());
+        assert!(check_etag(&previous, Some("*")).is_ok());
+        assert!(check_etag(&previous, Some(&etag(&previous))).is_ok());
+        assert_eq!(check_etag(&previous, Some("\"stale\"")), Err(etag(&previous)));
+    }
+
+    #[test]
+    fn save_with_a_stale_etag_is_a_conflict() {
+        let current_etag = etag(&snapshot(&current()));
+        match save(Config::default(), Some("\"stale\""), actor(), None) {
+            Err(SaveError::Conflict(etag)) => assert_eq!(etag, current_etag),
+            Err(e) => panic!("預期 ETag 衝突，實際為: {}", e),
+            Ok(_) => panic!("預期 ETag 衝突，實際已儲存"),
+        }
+    }
+}
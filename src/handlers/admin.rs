@@ -1,82 +1,522 @@
 use salvo::prelude::*;
-use salvo::basic_auth::{BasicAuth, BasicAuthValidator};
+use salvo::http::cookie::{time::Duration, Cookie, SameSite};
+use salvo::http::header::{self, HeaderValue};
 use askama::Template;
+use serde::Deserialize;
 use serde_json::json;
-use std::fs;
-use std::path::Path;
+use tracing::warn;
 
+use crate::admin_auth::{LoginError, Role, CSRF_COOKIE, CSRF_HEADER, SESSION_COOKIE};
+use crate::config::SaveError;
+use crate::history::Actor;
 use crate::types::Config;
+use super::admin_models::{
+    bulk_update_models, delete_model_config, export_config, get_model_config, import_config,
+    list_model_configs, patch_config, patch_model_config, put_model_config,
+};
 
 #[derive(Template)]
 #[template(path = "admin.html")]
 struct AdminTemplate;
 
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginTemplate {
+    totp_enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    username: String,
+    password: String,
+    totp: Option<String>,
+}
+
 #[handler]
 async fn admin_page(res: &mut Response) {
     let template = AdminTemplate;
     res.render(Text::Html(template.render().unwrap()));
 }
 
+pub(super) fn set_etag(res: &mut Response, etag: &str) {
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        res.headers_mut().insert(header::ETAG, value);
+    }
+}
+
+/// 記錄在配置歷史中的管理員及來源 IP
+pub(super) fn actor(req: &Request, depot: &Depot) -> Actor {
+    Actor {
+        user: depot.get::<String>("admin_user").cloned().unwrap_or_default(),
+        ip: client_ip(req),
+    }
+}
+
+pub(super) fn render_save_result(res: &mut Response, result: Result<String, SaveError>) {
+    match result {
+        Ok(etag) => {
+            set_etag(res, &etag);
+            res.render(Json(json!({ "status": "success", "etag": etag })));
+        },
+        Err(e) => {
+            let status = match e {
+                SaveError::Conflict(_) => StatusCode::PRECONDITION_FAILED,
+                SaveError::Invalid(_) => StatusCode::BAD_REQUEST,
+                SaveError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let mut body = json!({ "error": e.to_string() });
+            if let SaveError::Conflict(etag) = &e {
+                body["etag"] = json!(etag);
+            }
+            res.status_code(status);
+            res.render(Json(body));
+        }
+    }
+}
+
 #[handler]
 async fn get_config(res: &mut Response) {
-    let config = load_config().unwrap_or_default();
-    res.render(Json(config));
+    let config = crate::config::current();
+    set_etag(res, &crate::history::etag(&crate::history::snapshot(&config)));
+    res.render(Json((*config).clone()));
 }
 
+/// 帶有 If-Match 時，只有在配置未被其他人修改的情況下才會儲存
 #[handler]
-async fn save_config(req: &mut Request, res: &mut Response) {
+async fn save_config(req: &mut Request, depot: &mut Depot, res: &mut Response) {
     match req.parse_json::<Config>().await {
         Ok(config) => {
-            if let Err(e) = save_config_to_file(&config) {
-                res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
-                res.render(Json(json!({ "error": e.to_string() })));
-            } else {
-                res.render(Json(json!({ "status": "success" })));
+            let expected_etag = req.header::<String>("if-match");
+            let result = crate::config::save(config, expected_etag.as_deref(), actor(req, depot), None);
+            render_save_result(res, result);
+        }
+        Err(e) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(json!({ "error": e.to_string() })));
+        }
+    }
+}
+
+#[handler]
+async fn list_history(res: &mut Response) {
+    res.render(Json(json!({ "data": crate::history::list() })));
+}
+
+fn find_version(req: &Request, res: &mut Response) -> Option<crate::history::Version> {
+    let version = req.param::<u64>("version").unwrap_or_default();
+    let found = crate::history::get(version);
+    if found.is_none() {
+        res.status_code(StatusCode::NOT_FOUND);
+        res.render(Json(json!({ "error": format!("找不到配置版本 {}", version) })));
+    }
+    found
+}
+
+#[handler]
+async fn get_version(req: &mut Request, res: &mut Response) {
+    if let Some(version) = find_version(req, res) {
+        res.render(Json(version));
+    }
+}
+
+/// 比較指定版本與 against 版本的差異，未指定 against 時與目前的配置比較
+#[handler]
+async fn version_diff(req: &mut Request, res: &mut Response) {
+    let Some(version) = find_version(req, res) else {
+        return;
+    };
+    let against = match req.query::<u64>("against") {
+        Some(against) => match crate::history::get(against) {
+            Some(other) => other.config,
+            None => {
+                res.status_code(StatusCode::NOT_FOUND);
+                res.render(Json(json!({ "error": format!("找不到配置版本 {}", against) })));
+                return;
             }
+        },
+        None => crate::history::snapshot(&crate::config::current()),
+    };
+    res.render(Json(json!({
+        "version": version.version,
+        "against": req.query::<u64>("against"),
+        "changes": crate::history::diff(&version.config, &against),
+    })));
+}
+
+#[handler]
+async fn rollback_version(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let Some(version) = find_version(req, res) else {
+        return;
+    };
+    let config = match serde_json::from_value::<Config>(version.config) {
+        Ok(config) => config,
+        Err(e) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render(Json(json!({ "error": format!("無法解析配置版本 {}: {}", version.version, e) })));
+            return;
         }
+    };
+    let expected_etag = req.header::<String>("if-match");
+    let result = crate::config::save(config, expected_etag.as_deref(), actor(req, depot), Some(version.version));
+    render_save_result(res, result);
+}
+
+#[handler]
+async fn list_keys(res: &mut Response) {
+    res.render(Json(json!({ "data": crate::keys::list() })));
+}
+
+#[handler]
+async fn create_key(req: &mut Request, res: &mut Response) {
+    let request = match req.parse_json::<crate::keys::NewKey>().await {
+        Ok(request) => request,
         Err(e) => {
             res.status_code(StatusCode::BAD_REQUEST);
             res.render(Json(json!({ "error": e.to_string() })));
+            return;
+        }
+    };
+    match crate::keys::create(request) {
+        Ok((secret, key)) => {
+            res.status_code(StatusCode::CREATED);
+            res.render(Json(json!({ "key": secret, "data": key })));
+        },
+        Err(e) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(json!({ "error": e })));
         }
     }
 }
 
-fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
-    let path = Path::new("models.yaml");
-    if path.exists() {
-        let contents = fs::read_to_string(path)?;
-        Ok(serde_yaml::from_str(&contents)?)
+#[handler]
+async fn revoke_key(req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    render_key_result(res, crate::keys::revoke(&id), &id);
+}
+
+#[handler]
+async fn delete_key(req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    render_key_result(res, crate::keys::delete(&id), &id);
+}
+
+fn render_key_result(res: &mut Response, result: Result<bool, String>, id: &str) {
+    match result {
+        Ok(true) => res.render(Json(json!({ "status": "success" }))),
+        Ok(false) => {
+            res.status_code(StatusCode::NOT_FOUND);
+            res.render(Json(json!({ "error": format!("找不到金鑰 '{}'", id) })));
+        },
+        Err(e) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render(Json(json!({ "error": e })));
+        }
+    }
+}
+
+#[handler]
+async fn pool_status(res: &mut Response) {
+    let settings = &crate::settings::get().pool;
+    res.render(Json(json!({
+        "strategy": settings.strategy,
+        "cooldown": settings.cooldown,
+        "data": crate::key_pool::status(),
+    })));
+}
+
+#[handler]
+async fn reset_pool_key(req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    if crate::key_pool::reset(&id) {
+        res.render(Json(json!({ "status": "success" })));
     } else {
-        Ok(Config {
-            enable: Some(false),
-            models: std::collections::HashMap::new(),
-        })
+        res.status_code(StatusCode::NOT_FOUND);
+        res.render(Json(json!({ "error": format!("找不到金鑰 '{}' 的使用紀錄", id) })));
     }
 }
 
-fn save_config_to_file(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-    let yaml = serde_yaml::to_string(config)?;
-    fs::write("models.yaml", yaml)?;
-    Ok(())
+#[handler]
+async fn list_usage(res: &mut Response) {
+    res.render(Json(json!({ "data": crate::quota::usage() })));
+}
+
+#[handler]
+async fn reset_usage(req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    render_key_result(res, Ok(crate::quota::reset(&id)), &id);
+}
+
+/// 客戶端的 IP，用於登入失敗鎖定及日誌
+pub(crate) fn client_ip(req: &Request) -> String {
+    req.remote_addr()
+        .clone()
+        .into_std()
+        .map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string())
 }
 
-// 定義驗證器結構
-pub struct AdminAuthValidator;
+fn session_cookie(name: &'static str, value: String, http_only: bool, max_age: Duration) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .path("/")
+        .http_only(http_only)
+        .same_site(SameSite::Strict)
+        .secure(crate::settings::get().auth.secure_cookie)
+        .max_age(max_age)
+        .build()
+}
 
-impl BasicAuthValidator for AdminAuthValidator {
-    async fn validate(&self, username: &str, password: &str, _depot: &mut Depot) -> bool {
-        let valid_username = std::env::var("ADMIN_USERNAME").unwrap_or_else(|_| "admin".to_string());
-        let valid_password = std::env::var("ADMIN_PASSWORD").unwrap_or_else(|_| "123456".to_string());
-        username == valid_username && password == valid_password
+fn render_disabled(res: &mut Response, reason: &str) {
+    res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+    res.render(Json(json!({ "error": format!("管理介面已停用：{}", reason) })));
+}
+
+#[handler]
+async fn login_page(res: &mut Response) {
+    if let Some(reason) = crate::admin_auth::disabled_reason() {
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+        res.render(Text::Plain(format!("管理介面已停用：{}", reason)));
+        return;
+    }
+    let template = LoginTemplate {
+        totp_enabled: crate::admin_auth::totp_enabled(),
+    };
+    res.render(Text::Html(template.render().unwrap()));
+}
+
+#[handler]
+async fn login(req: &mut Request, res: &mut Response) {
+    let request = match req.parse_json::<LoginRequest>().await {
+        Ok(request) => request,
+        Err(e) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(json!({ "error": e.to_string() })));
+            return;
+        }
+    };
+    let ip = client_ip(req);
+    // Argon2 驗證較耗時，避免阻塞其他請求
+    let result = tokio::task::spawn_blocking(move || {
+        crate::admin_auth::login(&request.username, &request.password, request.totp.as_deref(), &ip)
+    })
+    .await
+    .unwrap_or(Err(LoginError::Invalid));
+
+    match result {
+        Ok((session_id, session)) => {
+            let max_age = Duration::seconds(crate::settings::get().auth.session_ttl as i64);
+            res.add_cookie(session_cookie(SESSION_COOKIE, session_id, true, max_age));
+            res.add_cookie(session_cookie(CSRF_COOKIE, session.csrf_token, false, max_age));
+            res.render(Json(json!({ "status": "success" })));
+        },
+        Err(e) => {
+            let status = match e {
+                LoginError::Disabled(_) => StatusCode::SERVICE_UNAVAILABLE,
+                LoginError::Locked(seconds) => {
+                    res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+                    StatusCode::TOO_MANY_REQUESTS
+                },
+                LoginError::Invalid | LoginError::TotpRequired => StatusCode::UNAUTHORIZED,
+            };
+            res.status_code(status);
+            res.render(Json(json!({
+                "error": e.to_string(),
+                "totp_required": matches!(e, LoginError::TotpRequired),
+            })));
+        }
+    }
+}
+
+#[handler]
+async fn logout(req: &mut Request, res: &mut Response) {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        crate::admin_auth::logout(cookie.value());
+    }
+    res.add_cookie(session_cookie(SESSION_COOKIE, String::new(), true, Duration::ZERO));
+    res.add_cookie(session_cookie(CSRF_COOKIE, String::new(), false, Duration::ZERO));
+    res.render(Json(json!({ "status": "success" })));
+}
+
+/// 檢查登入狀態；會修改資料的請求必須附上與工作階段相符的 CSRF token
+#[handler]
+async fn require_session(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    if let Some(reason) = crate::admin_auth::disabled_reason() {
+        render_disabled(res, reason);
+        ctrl.skip_rest();
+        return;
+    }
+    let Some(session) = req.cookie(SESSION_COOKIE).and_then(|cookie| crate::admin_auth::session(cookie.value())) else {
+        if req.uri().path().starts_with("/api/") {
+            res.status_code(StatusCode::UNAUTHORIZED);
+            res.render(Json(json!({ "error": "請先登入管理介面" })));
+        } else {
+            res.render(Redirect::found("/admin/login"));
+        }
+        ctrl.skip_rest();
+        return;
+    };
+    if req.method() != salvo::http::Method::GET {
+        let token = req.header::<String>(CSRF_HEADER).unwrap_or_default();
+        if !crate::utils::constant_time_eq(token.as_bytes(), session.csrf_token.as_bytes()) {
+            warn!("🚫 CSRF token 驗證失敗 | 用戶名: {} | IP: {}", session.username, client_ip(req));
+            res.status_code(StatusCode::FORBIDDEN);
+            res.render(Json(json!({ "error": "CSRF token 無效，請重新整理頁面" })));
+            ctrl.skip_rest();
+            return;
+        }
+    }
+    depot.insert("admin_user", session.username);
+    depot.insert("admin_role", session.role);
+}
+
+/// 要求登入者至少具有指定的權限
+struct RequireRole(Role);
+
+#[handler]
+impl RequireRole {
+    async fn handle(&self, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        let role = depot.get::<Role>("admin_role").ok().copied();
+        if role.is_some_and(|role| role >= self.0) {
+            return;
+        }
+        res.status_code(StatusCode::FORBIDDEN);
+        res.render(Json(json!({ "error": format!("需要 {} 權限", self.0.name()) })));
+        ctrl.skip_rest();
+    }
+}
+
+#[handler]
+async fn current_user(depot: &mut Depot, res: &mut Response) {
+    let username = depot.get::<String>("admin_user").cloned().unwrap_or_default();
+    let role = depot.get::<Role>("admin_role").ok().copied();
+    res.render(Json(json!({ "username": username, "role": role })));
+}
+
+#[handler]
+async fn list_users(res: &mut Response) {
+    res.render(Json(json!({ "data": crate::admin_auth::list_users() })));
+}
+
+#[handler]
+async fn create_user(req: &mut Request, res: &mut Response) {
+    let request = match req.parse_json::<crate::admin_auth::NewUser>().await {
+        Ok(request) => request,
+        Err(e) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(json!({ "error": e.to_string() })));
+            return;
+        }
+    };
+    // Argon2 雜湊較耗時，避免阻塞其他請求
+    match tokio::task::spawn_blocking(move || crate::admin_auth::create_user(request)).await {
+        Ok(Ok(user)) => {
+            res.status_code(StatusCode::CREATED);
+            res.render(Json(json!({ "data": user })));
+        },
+        Ok(Err(e)) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(json!({ "error": e })));
+        },
+        Err(e) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render(Json(json!({ "error": e.to_string() })));
+        }
+    }
+}
+
+#[handler]
+async fn update_user(req: &mut Request, res: &mut Response) {
+    let username = req.param::<String>("username").unwrap_or_default();
+    let request = match req.parse_json::<crate::admin_auth::UserUpdate>().await {
+        Ok(request) => request,
+        Err(e) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(json!({ "error": e.to_string() })));
+            return;
+        }
+    };
+    let target = username.clone();
+    let result = tokio::task::spawn_blocking(move || crate::admin_auth::update_user(&target, request))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    render_user_result(res, result, &username);
+}
+
+#[handler]
+async fn delete_user(req: &mut Request, res: &mut Response) {
+    let username = req.param::<String>("username").unwrap_or_default();
+    render_user_result(res, crate::admin_auth::delete_user(&username), &username);
+}
+
+fn render_user_result(res: &mut Response, result: Result<bool, String>, username: &str) {
+    match result {
+        Ok(true) => res.render(Json(json!({ "status": "success" }))),
+        Ok(false) => {
+            res.status_code(StatusCode::NOT_FOUND);
+            res.render(Json(json!({ "error": format!("找不到管理員 '{}'", username) })));
+        },
+        Err(e) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(json!({ "error": e })));
+        }
     }
 }
 
-// 修改路由函數
 pub fn admin_routes() -> Router {
-    let auth_handler = BasicAuth::new(AdminAuthValidator);
-    
-    Router::new()
-        .hoop(auth_handler) // 加入認證中間件
+    // 登入即可查看配置及統計
+    let viewer = Router::new()
         .push(Router::with_path("admin").get(admin_page))
-        .push(Router::with_path("api/admin/config").get(get_config).post(save_config))
-}
\ No newline at end of file
+        .push(Router::with_path("api/admin/me").get(current_user))
+        .push(Router::with_path("api/admin/logout").post(logout))
+        .push(Router::with_path("api/admin/config").get(get_config))
+        .push(Router::with_path("api/admin/config/export").get(export_config))
+        .push(
+            Router::with_path("api/admin/models")
+                .get(list_model_configs)
+                .push(Router::with_path("<id>").get(get_model_config))
+        )
+        .push(
+            Router::with_path("api/admin/config/history")
+                .get(list_history)
+                .push(Router::with_path("<version>").get(get_version))
+                .push(Router::with_path("<version>/diff").get(version_diff))
+        )
+        .push(Router::with_path("api/admin/keys").get(list_keys))
+        .push(Router::with_path("api/admin/usage").get(list_usage))
+        .push(Router::with_path("api/admin/pool").get(pool_status));
+    let editor = Router::new()
+        .hoop(RequireRole(Role::Editor))
+        .push(Router::with_path("api/admin/config").post(save_config).patch(patch_config))
+        .push(Router::with_path("api/admin/config/import").post(import_config))
+        .push(
+            Router::with_path("api/admin/models")
+                .push(Router::with_path("bulk").post(bulk_update_models))
+                .push(Router::with_path("<id>").put(put_model_config).patch(patch_model_config).delete(delete_model_config))
+        )
+        .push(Router::with_path("api/admin/config/history/<version>/rollback").post(rollback_version));
+    let owner = Router::new()
+        .hoop(RequireRole(Role::Owner))
+        .push(
+            Router::with_path("api/admin/keys")
+                .post(create_key)
+                .push(Router::with_path("<id>").delete(delete_key))
+                .push(Router::with_path("<id>/revoke").post(revoke_key))
+        )
+        .push(Router::with_path("api/admin/usage/<id>/reset").post(reset_usage))
+        .push(Router::with_path("api/admin/pool/<id>/reset").post(reset_pool_key))
+        .push(
+            Router::with_path("api/admin/users")
+                .get(list_users)
+                .post(create_user)
+                .push(Router::with_path("<username>").patch(update_user).delete(delete_user))
+        );
+
+    Router::new()
+        .push(Router::with_path("admin/login").get(login_page))
+        .push(Router::with_path("api/admin/login").post(login))
+        .push(
+            Router::new()
+                .hoop(require_session)
+                .push(viewer)
+                .push(editor)
+                .push(owner)
+        )
+}
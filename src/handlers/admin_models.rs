@@ -0,0 +1,321 @@
+use salvo::prelude::*;
+use salvo::http::header::{self, HeaderValue};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use super::admin::{actor, render_save_result, set_etag};
+use super::models::{public_models, upstream_models};
+use crate::types::{Config, ModelConfig};
+
+#[derive(Deserialize)]
+struct BulkRequest {
+    /// 不分大小寫的 glob，比對 models.yaml 項目及 Poe 模型名稱
+    pattern: String,
+    enable: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Yaml,
+    Json,
+}
+
+/// 查詢參數 format 優先，其次依 Content-Type 判斷，預設為 YAML
+fn request_format(req: &Request) -> Result<Format, String> {
+    match req.query::<String>("format").as_deref() {
+        Some("yaml") | Some("yml") => Ok(Format::Yaml),
+        Some("json") => Ok(Format::Json),
+        Some(other) => Err(format!("不支援的格式 `{}`，可用的格式: yaml, json", other)),
+        None => {
+            let is_json = req.content_type().is_some_and(|mime| mime.subtype() == "json");
+            Ok(if is_json { Format::Json } else { Format::Yaml })
+        },
+    }
+}
+
+fn render_bad_request(res: &mut Response, message: String) {
+    res.status_code(StatusCode::BAD_REQUEST);
+    res.render(Json(json!({ "error": message })));
+}
+
+fn render_not_found(res: &mut Response, id: &str) {
+    res.status_code(StatusCode::NOT_FOUND);
+    res.render(Json(json!({ "error": format!("models.yaml 中沒有名為 '{}' 的模型", id) })));
+}
+
+/// models.yaml 中的項目名稱，先找完全相同的，再不分大小寫比對
+fn find_key(config: &Config, id: &str) -> Option<String> {
+    if config.models.contains_key(id) {
+        return Some(id.to_string());
+    }
+    config.models.keys().find(|key| key.eq_ignore_ascii_case(id)).cloned()
+}
+
+/// 依 RFC 7396 套用 JSON Merge Patch，null 表示移除欄位
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = json!({});
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// 沒有任何設定的項目等同未列出
+fn is_empty_entry(model_config: &ModelConfig) -> bool {
+    serde_json::to_value(model_config).is_ok_and(|value| value.as_object().is_some_and(|object| object.is_empty()))
+}
+
+/// 儲存修改後的配置；查詢參數 dry_run=true 時只回傳驗證結果、差異及 /v1/models 的預覽
+///
+/// 未帶 If-Match 時以讀取時的 ETag 確認配置在修改期間沒有被其他請求改掉
+async fn commit(req: &Request, depot: &Depot, res: &mut Response, base: &Config, candidate: Config, status: StatusCode) {
+    let base_snapshot = crate::history::snapshot(base);
+    if req.query::<bool>("dry_run").unwrap_or(false) {
+        let errors = crate::config::validate(&candidate);
+        let changes = crate::history::diff(&base_snapshot, &crate::history::snapshot(&candidate));
+        let (models, models_error) = match upstream_models().await {
+            Ok(upstream) => (Some(json!({ "object": "list", "data": public_models(&candidate, upstream) })), None),
+            Err(e) => {
+                warn!("⚠️ 預覽模型列表時無法取得 Poe 模型列表: {}", e);
+                (None, Some(e.to_string()))
+            },
+        };
+        res.render(Json(json!({
+            "dry_run": true,
+            "valid": errors.is_empty(),
+            "errors": errors,
+            "changes": changes,
+            "models": models,
+            "models_error": models_error,
+        })));
+        return;
+    }
+
+    let expected_etag = req.header::<String>("if-match").unwrap_or_else(|| crate::history::etag(&base_snapshot));
+    let result = crate::config::save(candidate, Some(&expected_etag), actor(req, depot), None);
+    if result.is_ok() {
+        res.status_code(status);
+    }
+    render_save_result(res, result);
+}
+
+#[handler]
+pub(super) async fn list_model_configs(res: &mut Response) {
+    let config = crate::config::current();
+    let mut entries: Vec<(&String, &ModelConfig)> = config.models.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    set_etag(res, &crate::history::etag(&crate::history::snapshot(&config)));
+    res.render(Json(json!({
+        "enable": config.enable.unwrap_or(false),
+        "data": entries.into_iter()
+            .map(|(id, model_config)| json!({ "id": id, "config": model_config }))
+            .collect::<Vec<_>>(),
+    })));
+}
+
+#[handler]
+pub(super) async fn get_model_config(req: &mut Request, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    let config = crate::config::current();
+    match find_key(&config, &id) {
+        Some(key) => {
+            set_etag(res, &crate::history::etag(&crate::history::snapshot(&config)));
+            res.render(Json(json!({ "id": key, "config": config.models[&key] })));
+        },
+        None => render_not_found(res, &id),
+    }
+}
+
+/// 以請求內容取代（或新增）模型設定
+#[handler]
+pub(super) async fn put_model_config(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    let model_config = match req.parse_json::<ModelConfig>().await {
+        Ok(model_config) => model_config,
+        Err(e) => return render_bad_request(res, e.to_string()),
+    };
+    let base = crate::config::current();
+    let mut candidate = (*base).clone();
+    let (key, status) = match find_key(&base, &id) {
+        Some(key) => (key, StatusCode::OK),
+        None => (id, StatusCode::CREATED),
+    };
+    candidate.models.insert(key, model_config);
+    commit(req, depot, res, &base, candidate, status).await;
+}
+
+/// 以 JSON Merge Patch 修改模型設定的部分欄位
+#[handler]
+pub(super) async fn patch_model_config(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    let patch = match req.parse_json::<Value>().await {
+        Ok(patch) => patch,
+        Err(e) => return render_bad_request(res, e.to_string()),
+    };
+    let base = crate::config::current();
+    let Some(key) = find_key(&base, &id) else {
+        return render_not_found(res, &id);
+    };
+    let mut value = serde_json::to_value(&base.models[&key]).unwrap_or_default();
+    merge_patch(&mut value, &patch);
+    let model_config = match serde_json::from_value::<ModelConfig>(value) {
+        Ok(model_config) => model_config,
+        Err(e) => return render_bad_request(res, format!("模型設定無效: {}", e)),
+    };
+    let mut candidate = (*base).clone();
+    candidate.models.insert(key, model_config);
+    commit(req, depot, res, &base, candidate, StatusCode::OK).await;
+}
+
+#[handler]
+pub(super) async fn delete_model_config(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = req.param::<String>("id").unwrap_or_default();
+    let base = crate::config::current();
+    let Some(key) = find_key(&base, &id) else {
+        return render_not_found(res, &id);
+    };
+    let mut candidate = (*base).clone();
+    candidate.models.remove(&key);
+    commit(req, depot, res, &base, candidate, StatusCode::OK).await;
+}
+
+/// 依 glob 批次啟用或停用模型；啟用時移除 enable 設定，沒有其他設定的項目一併移除
+#[handler]
+pub(super) async fn bulk_update_models(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let request = match req.parse_json::<BulkRequest>().await {
+        Ok(request) => request,
+        Err(e) => return render_bad_request(res, e.to_string()),
+    };
+    let regex = match crate::routing::glob_regex(&request.pattern) {
+        Ok(regex) => regex,
+        Err(e) => return render_bad_request(res, format!("pattern 無效: {}", e)),
+    };
+
+    let base = crate::config::current();
+    let mut names: Vec<String> = base.models.keys().cloned().collect();
+    // 尚未列在 models.yaml 中的 Poe 模型也可以被停用
+    match upstream_models().await {
+        Ok(upstream) => names.extend(
+            upstream.into_iter()
+                .map(|model| model.id)
+                .filter(|id| find_key(&base, id).is_none())
+        ),
+        Err(e) => warn!("⚠️ 無法取得 Poe 模型列表，只比對 models.yaml 中的項目: {}", e),
+    }
+    let matched: Vec<String> = names.into_iter().filter(|name| regex.is_match(name)).collect();
+    if matched.is_empty() {
+        res.status_code(StatusCode::NOT_FOUND);
+        res.render(Json(json!({ "error": format!("沒有符合 `{}` 的模型", request.pattern) })));
+        return;
+    }
+
+    let mut candidate = (*base).clone();
+    for name in matched {
+        let model_config = candidate.models.entry(name.clone()).or_default();
+        model_config.enable = if request.enable { None } else { Some(false) };
+        if is_empty_entry(model_config) {
+            candidate.models.remove(&name);
+        }
+    }
+    commit(req, depot, res, &base, candidate, StatusCode::OK).await;
+}
+
+/// 以 JSON Merge Patch 修改整份配置，例如 `{"enable": true}`
+#[handler]
+pub(super) async fn patch_config(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let patch = match req.parse_json::<Value>().await {
+        Ok(patch) => patch,
+        Err(e) => return render_bad_request(res, e.to_string()),
+    };
+    let base = crate::config::current();
+    let mut value = crate::history::snapshot(&base);
+    merge_patch(&mut value, &patch);
+    let candidate = match serde_json::from_value::<Config>(value) {
+        Ok(candidate) => candidate,
+        Err(e) => return render_bad_request(res, format!("配置無效: {}", e)),
+    };
+    commit(req, depot, res, &base, candidate, StatusCode::OK).await;
+}
+
+/// 匯出目前的配置，format 為 yaml（預設）或 json
+#[handler]
+pub(super) async fn export_config(req: &mut Request, res: &mut Response) {
+    let format = match request_format(req) {
+        Ok(format) => format,
+        Err(e) => return render_bad_request(res, e),
+    };
+    let config = crate::config::current();
+    let (body, content_type, filename) = match format {
+        Format::Yaml => (serde_yaml::to_string(&*config).map_err(|e| e.to_string()), "application/yaml; charset=utf-8", "models.yaml"),
+        Format::Json => (serde_json::to_string_pretty(&*config).map_err(|e| e.to_string()), "application/json; charset=utf-8", "models.json"),
+    };
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+            res.render(Json(json!({ "error": e })));
+            return;
+        }
+    };
+    set_etag(res, &crate::history::etag(&crate::history::snapshot(&config)));
+    res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Ok(value) = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)) {
+        res.headers_mut().insert(header::CONTENT_DISPOSITION, value);
+    }
+    res.write_body(body).ok();
+}
+
+/// 匯入配置；mode=replace（預設）取代整份配置，mode=merge 依名稱合併 models 與 groups，有提供 rules 時取代
+#[handler]
+pub(super) async fn import_config(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let format = match request_format(req) {
+        Ok(format) => format,
+        Err(e) => return render_bad_request(res, e),
+    };
+    let merge = match req.query::<String>("mode").as_deref() {
+        None | Some("replace") => false,
+        Some("merge") => true,
+        Some(other) => return render_bad_request(res, format!("不支援的模式 `{}`，可用的模式: replace, merge", other)),
+    };
+    let body = match req.payload().await {
+        Ok(body) => String::from_utf8_lossy(body).into_owned(),
+        Err(e) => return render_bad_request(res, e.to_string()),
+    };
+    let imported = match format {
+        Format::Yaml => serde_yaml::from_str::<Config>(&body).map_err(|e| e.to_string()),
+        Format::Json => serde_json::from_str::<Config>(&body).map_err(|e| e.to_string()),
+    };
+    let imported = match imported {
+        Ok(imported) => imported,
+        Err(e) => return render_bad_request(res, format!("無法解析匯入的配置: {}", e)),
+    };
+
+    let base = crate::config::current();
+    let candidate = if merge {
+        let mut candidate = (*base).clone();
+        if imported.enable.is_some() {
+            candidate.enable = imported.enable;
+        }
+        candidate.models.extend(imported.models);
+        candidate.groups.extend(imported.groups);
+        if !imported.rules.is_empty() {
+            candidate.rules = imported.rules;
+        }
+        candidate
+    } else {
+        imported
+    };
+    commit(req, depot, res, &base, candidate, StatusCode::OK).await;
+}
@@ -0,0 +1,459 @@
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use nanoid::nanoid;
+use salvo::http::header;
+use salvo::prelude::*;
+use serde_json::{json, Value};
+use std::time::Instant;
+use tracing::{debug, error, info};
+
+use crate::handlers::chat::{
+    answered_model, collect_response, convert_poe_error_to_openai, start_text_stream, TextEvent,
+};
+use crate::limits::OutputLimits;
+use crate::poe_client::{PoeClientWrapper, apply_system_prompt, create_query_request, resolve_max_tokens, stream_with_fallbacks};
+use crate::routing::{key_access, route, Caller, ResolvedModel};
+use crate::rate_limit;
+use crate::shadow;
+use crate::tokenizer::Tokenizer;
+use crate::tools::{self, ToolCallDetector, ToolChoice, ToolConfig};
+use crate::types::*;
+use crate::utils::format_duration;
+
+#[handler]
+pub async fn anthropic_messages(req: &mut Request, res: &mut Response) {
+    let start_time = Instant::now();
+    info!("📝 收到新的 Anthropic Messages 請求");
+
+    let max_size = crate::settings::get().server.max_request_size;
+
+    let config = crate::config::current();
+
+    // Anthropic SDK 使用 x-api-key，同時相容 Authorization: Bearer
+    let credential = match extract_access_key(req).map(|key| crate::keys::authenticate(&key)) {
+        Some(Ok(credential)) => credential,
+        Some(Err(e)) => {
+            error!("❌ 驗證失敗: {}", e);
+            render_error(res, StatusCode::UNAUTHORIZED, &e.to_string());
+            return;
+        },
+        None => {
+            error!("❌ 缺少授權標頭");
+            render_error(res, StatusCode::UNAUTHORIZED, "缺少 x-api-key 或 Authorization");
+            return;
+        }
+    };
+
+    let request = match req.payload_with_max_size(max_size).await {
+        Ok(bytes) => match serde_json::from_slice::<AnthropicMessagesRequest>(&bytes) {
+            Ok(request) => {
+                debug!("📊 請求解析成功 | 模型: {} | 訊息數量: {} | 是否串流: {:?}",
+                    request.model,
+                    request.messages.len(),
+                    request.stream
+                );
+                request
+            },
+            Err(e) => {
+                error!("❌ JSON 解析失敗: {}", e);
+                render_error(res, StatusCode::BAD_REQUEST, &format!("JSON 解析失敗: {}", e));
+                return;
+            }
+        },
+        Err(e) => {
+            error!("❌ 請求大小超過限制或讀取失敗: {}", e);
+            render_error(res, StatusCode::PAYLOAD_TOO_LARGE, &format!("請求大小超過限制 ({} bytes) 或讀取失敗: {}", max_size, e));
+            return;
+        }
+    };
+
+    let access = key_access(&config, credential.key.as_ref());
+    let caller = Caller { client_id: &credential.client_id, user: request.metadata.as_ref().and_then(|metadata| metadata.user_id.as_deref()), access: access.as_ref() };
+    let (resolved, chain) = match route(&config, &request.model, &caller) {
+        Ok(routed) => routed,
+        Err(message) => {
+            error!("❌ 模型存取被拒: {}", message);
+            render_error(res, StatusCode::NOT_FOUND, &message);
+            return;
+        }
+    };
+    let ResolvedModel { display: display_model, bot: original_model, config_key } = resolved;
+    info!("🤖 使用模型: {} (原始: {})", display_model, original_model);
+
+    let client = PoeClientWrapper::new(&original_model, &credential.upload_key());
+    let tool_config = tool_config_from_request(&request);
+    let tokenizer = Tokenizer::for_model(&original_model, config.models.get(&config_key));
+    let limits = OutputLimits::new(
+        request.stop_sequences.unwrap_or_default(),
+        resolve_max_tokens(&config_key, Some(request.max_tokens)),
+        tokenizer,
+    );
+
+    let mut messages = convert_messages(request.system, request.messages);
+    if let Err(e) = client.upload_data_images(&mut messages).await {
+        error!("❌ 處理圖片附件失敗: {}", e);
+        render_error(res, StatusCode::BAD_REQUEST, &e);
+        return;
+    }
+    let mut messages = tools::prepare_messages(messages, tool_config.as_ref());
+    apply_system_prompt(&config_key, &mut messages);
+    let input_tokens = tokenizer.count_messages(&messages);
+    debug!("🧮 估算 input token 數量: {} | tokenizer: {:?}", input_tokens, tokenizer);
+
+    let build_request = || create_query_request(&config_key, messages.clone(), request.temperature, limits.stop.clone());
+    let (client, event_stream) = match stream_with_fallbacks(&chain, &credential.poe_keys, &build_request).await {
+        Ok(result) => result,
+        Err(e) => {
+            error!("❌ 建立串流請求失敗: {}", e);
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+            return;
+        }
+    };
+    let display_model = answered_model(res, display_model, &original_model, &client);
+    let event_stream = shadow::mirror(&config, &config_key, &client, &build_request, event_stream);
+
+    let id = format!("msg_{}", nanoid!(24));
+    let mut limiter = limits.limiter();
+
+    if request.stream.unwrap_or(false) {
+        debug!("🌊 開始處理 Anthropic 串流響應 | ID: {}", id);
+        let text_stream = match start_text_stream(event_stream, limiter).await {
+            Ok(text_stream) => text_stream,
+            Err(error) => {
+                let (status, _) = convert_poe_error_to_openai(&error);
+                render_error(res, status, &error.text);
+                return;
+            }
+        };
+
+        res.headers_mut().insert(header::CONTENT_TYPE, "text/event-stream".parse().unwrap());
+        res.headers_mut().insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+        res.headers_mut().insert(header::CONNECTION, "keep-alive".parse().unwrap());
+
+        let message_start = sse_event("message_start", json!({
+            "type": "message_start",
+            "message": {
+                "id": id,
+                "type": "message",
+                "role": "assistant",
+                "model": display_model,
+                "content": [],
+                "stop_reason": null,
+                "stop_sequence": null,
+                "usage": { "input_tokens": input_tokens, "output_tokens": 0 }
+            }
+        }));
+
+        let mut state = BlockState {
+            tool_detector: tool_config.as_ref().map(|_| ToolCallDetector::default()),
+            text_open: false,
+            index: 0,
+        };
+        let events = text_stream.map(move |event| {
+            let message = match event {
+                TextEvent::Delta(text) => {
+                    let text = match state.tool_detector.as_mut() {
+                        Some(detector) => detector.push(&text),
+                        None => text,
+                    };
+                    state.text_delta(&text)
+                },
+                TextEvent::Error(message) => {
+                    sse_event("error", json!({
+                        "type": "error",
+                        "error": { "type": "api_error", "message": message }
+                    }))
+                },
+                TextEvent::Finished { finish_reason, stop_sequence, completion_tokens } => {
+                    let (leftover, tool_calls) = match state.tool_detector.take() {
+                        Some(detector) => detector.finish(),
+                        None => (String::new(), Vec::new()),
+                    };
+                    let mut message = state.text_delta(&leftover);
+                    if state.text_open {
+                        message.push_str(&state.close_block());
+                    }
+                    let stop_reason = stop_reason(finish_reason, stop_sequence.is_some(), !tool_calls.is_empty());
+                    for call in tool_calls {
+                        message.push_str(&state.tool_use_block(call));
+                    }
+                    message.push_str(&sse_event("message_delta", json!({
+                        "type": "message_delta",
+                        "delta": {
+                            "stop_reason": stop_reason,
+                            "stop_sequence": if stop_reason == "stop_sequence" { stop_sequence } else { None }
+                        },
+                        "usage": { "output_tokens": completion_tokens }
+                    })));
+                    message.push_str(&sse_event("message_stop", json!({ "type": "message_stop" })));
+                    message
+                },
+            };
+            Ok::<_, std::convert::Infallible>(message)
+        });
+
+        let events = rate_limit::hold_permit(res, stream::once(future::ready(Ok::<_, std::convert::Infallible>(message_start))).chain(events));
+        res.stream(events);
+    } else {
+        let content = match collect_response(event_stream, &mut limiter).await {
+            Ok(content) => content,
+            Err(error) => {
+                let (status, _) = convert_poe_error_to_openai(&error);
+                render_error(res, status, &error.text);
+                return;
+            }
+        };
+
+        let (text, tool_calls) = match tool_config.as_ref().and_then(|_| tools::parse_tool_calls(&content)) {
+            Some((text, tool_calls)) => (text, tool_calls),
+            None => (content, Vec::new()),
+        };
+
+        let mut blocks = Vec::new();
+        if !text.is_empty() {
+            blocks.push(json!({ "type": "text", "text": text }));
+        }
+        let stop_reason = stop_reason(limiter.finish_reason(), limiter.stop_sequence().is_some(), !tool_calls.is_empty());
+        blocks.extend(tool_calls.into_iter().map(tool_use_json));
+
+        res.render(Json(json!({
+            "id": id,
+            "type": "message",
+            "role": "assistant",
+            "model": display_model,
+            "content": blocks,
+            "stop_reason": stop_reason,
+            "stop_sequence": if stop_reason == "stop_sequence" { limiter.stop_sequence() } else { None },
+            "usage": {
+                "input_tokens": input_tokens,
+                "output_tokens": limiter.emitted_tokens()
+            }
+        })));
+    }
+
+    let duration = start_time.elapsed();
+    info!("✅ 請求處理完成 | 耗時: {}", format_duration(duration));
+}
+
+fn extract_access_key(req: &Request) -> Option<String> {
+    if let Some(key) = req.headers().get("x-api-key").and_then(|value| value.to_str().ok()) {
+        debug!("🔑 驗證令牌長度: {}", key.len());
+        return Some(key.to_string());
+    }
+    let auth_str = req.headers().get("Authorization")?.to_str().ok()?;
+    let key = auth_str.strip_prefix("Bearer ")?;
+    debug!("🔑 驗證令牌長度: {}", key.len());
+    Some(key.to_string())
+}
+
+/// 以 Anthropic 的錯誤格式回應
+fn render_error(res: &mut Response, status: StatusCode, message: &str) {
+    let error_type = match status {
+        StatusCode::UNAUTHORIZED => "authentication_error",
+        StatusCode::NOT_FOUND => "not_found_error",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+        StatusCode::PAYLOAD_TOO_LARGE => "request_too_large",
+        status if status.is_client_error() => "invalid_request_error",
+        _ => "api_error",
+    };
+    res.status_code(status);
+    res.render(Json(AnthropicErrorResponse {
+        r#type: "error".to_string(),
+        error: AnthropicError {
+            r#type: error_type.to_string(),
+            message: message.to_string(),
+        },
+    }));
+}
+
+/// 將 Anthropic 的 tools / tool_choice 轉為共用的工具設定
+fn tool_config_from_request(request: &AnthropicMessagesRequest) -> Option<ToolConfig> {
+    let tools = request.tools.as_ref().filter(|tools| !tools.is_empty())?;
+    let choice = request.tool_choice.as_ref();
+    let choice_type = choice.and_then(|choice| choice.get("type")).and_then(Value::as_str);
+    let choice = match choice_type {
+        Some("none") => {
+            debug!("🔧 tool_choice 為 none，不注入工具定義");
+            return None;
+        },
+        Some("any") => ToolChoice::Required,
+        Some("tool") => match choice.and_then(|choice| choice.get("name")).and_then(Value::as_str) {
+            Some(name) => ToolChoice::Function(name.to_string()),
+            None => ToolChoice::Required,
+        },
+        _ => ToolChoice::Auto,
+    };
+    let disable_parallel = request.tool_choice.as_ref()
+        .and_then(|choice| choice.get("disable_parallel_tool_use"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+
+    debug!("🔧 啟用工具呼叫 | 工具數量: {}", tools.len());
+
+    Some(ToolConfig {
+        tools: tools.iter()
+            .map(|tool| ChatTool {
+                r#type: "function".to_string(),
+                function: FunctionDefinition {
+                    name: tool.name.clone(),
+                    description: tool.description.clone(),
+                    parameters: tool.input_schema.clone(),
+                },
+            })
+            .collect(),
+        choice,
+        parallel: !disable_parallel,
+        legacy: false,
+    })
+}
+
+/// 將 Anthropic 的 system 與訊息轉為 OpenAI 格式的訊息，以沿用既有的處理流程
+fn convert_messages(system: Option<AnthropicContent>, messages: Vec<AnthropicMessage>) -> Vec<Message> {
+    let mut converted = Vec::with_capacity(messages.len() + 1);
+    if let Some(system) = system {
+        let text = system.text();
+        if !text.is_empty() {
+            converted.push(Message::text("system", text));
+        }
+    }
+
+    for message in messages {
+        let blocks = match message.content {
+            AnthropicContent::Text(text) => {
+                converted.push(Message::text(&message.role, text));
+                continue;
+            },
+            AnthropicContent::Blocks(blocks) => blocks,
+        };
+
+        let mut parts = Vec::new();
+        let mut tool_calls = Vec::new();
+        for block in blocks {
+            match block {
+                AnthropicContentBlock::Text { text } => parts.push(ContentPart::Text { text }),
+                AnthropicContentBlock::Image { source } => {
+                    let url = match source {
+                        AnthropicImageSource::Base64 { media_type, data } => format!("data:{};base64,{}", media_type, data),
+                        AnthropicImageSource::Url { url } => url,
+                    };
+                    parts.push(ContentPart::ImageUrl { image_url: ImageUrl { url, detail: None } });
+                },
+                AnthropicContentBlock::ToolUse { id, name, input } => {
+                    tool_calls.push(ToolCall {
+                        id,
+                        r#type: "function".to_string(),
+                        function: FunctionCall { name, arguments: input.to_string() },
+                    });
+                },
+                AnthropicContentBlock::ToolResult { tool_use_id, content, is_error } => {
+                    let mut text = content.map(|content| content.text()).unwrap_or_default();
+                    if is_error.unwrap_or(false) {
+                        text = format!("Error: {}", text);
+                    }
+                    let mut result = Message::text("tool", text);
+                    result.tool_call_id = Some(tool_use_id);
+                    converted.push(result);
+                },
+                AnthropicContentBlock::Unsupported => {
+                    debug!("⏭️ 忽略不支援的內容區塊");
+                },
+            }
+        }
+
+        if parts.is_empty() && tool_calls.is_empty() {
+            continue;
+        }
+        let mut converted_message = Message::text(&message.role, "");
+        converted_message.content = MessageContent::Parts(parts);
+        if !tool_calls.is_empty() {
+            converted_message.tool_calls = Some(tool_calls);
+        }
+        converted.push(converted_message);
+    }
+
+    converted
+}
+
+/// 將 finish_reason 轉為 Anthropic 的 stop_reason
+fn stop_reason(finish_reason: &str, matched_stop_sequence: bool, has_tool_calls: bool) -> &'static str {
+    if has_tool_calls {
+        return "tool_use";
+    }
+    match finish_reason {
+        "length" => "max_tokens",
+        _ if matched_stop_sequence => "stop_sequence",
+        _ => "end_turn",
+    }
+}
+
+fn tool_use_json(call: ToolCall) -> Value {
+    let input = serde_json::from_str::<Value>(&call.function.arguments).unwrap_or_else(|_| json!({}));
+    json!({
+        "type": "tool_use",
+        "id": format!("toolu_{}", nanoid!(24)),
+        "name": call.function.name,
+        "input": input
+    })
+}
+
+fn sse_event(name: &str, data: Value) -> String {
+    format!("event: {}\ndata: {}\n\n", name, serde_json::to_string(&data).unwrap())
+}
+
+/// 串流模式下的內容區塊狀態，文字區塊在收到第一段文字時才開啟
+struct BlockState {
+    tool_detector: Option<ToolCallDetector>,
+    text_open: bool,
+    index: usize,
+}
+
+impl BlockState {
+    fn text_delta(&mut self, text: &str) -> String {
+        if text.is_empty() {
+            return String::new();
+        }
+        let mut message = String::new();
+        if !self.text_open {
+            self.text_open = true;
+            message.push_str(&sse_event("content_block_start", json!({
+                "type": "content_block_start",
+                "index": self.index,
+                "content_block": { "type": "text", "text": "" }
+            })));
+        }
+        message.push_str(&sse_event("content_block_delta", json!({
+            "type": "content_block_delta",
+            "index": self.index,
+            "delta": { "type": "text_delta", "text": text }
+        })));
+        message
+    }
+
+    fn close_block(&mut self) -> String {
+        let message = sse_event("content_block_stop", json!({
+            "type": "content_block_stop",
+            "index": self.index
+        }));
+        self.text_open = false;
+        self.index += 1;
+        message
+    }
+
+    fn tool_use_block(&mut self, call: ToolCall) -> String {
+        let mut block = tool_use_json(call);
+        let input = block["input"].take();
+        block["input"] = json!({});
+        let mut message = sse_event("content_block_start", json!({
+            "type": "content_block_start",
+            "index": self.index,
+            "content_block": block
+        }));
+        message.push_str(&sse_event("content_block_delta", json!({
+            "type": "content_block_delta",
+            "index": self.index,
+            "delta": { "type": "input_json_delta", "partial_json": input.to_string() }
+        })));
+        message.push_str(&self.close_block());
+        message
+    }
+}
@@ -6,78 +6,39 @@ use salvo::http::header;
 use salvo::prelude::*;
 use serde_json::json;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
-use std::path::Path;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use chrono::Utc;
 
-use crate::poe_client::{PoeClientWrapper, create_query_request};
+use crate::keys::Credential;
+use crate::limits::{OutputLimiter, OutputLimits};
+use crate::poe_client::{PoeClientWrapper, apply_system_prompt, create_query_request, resolve_max_tokens, stream_with_fallbacks};
+use crate::quota::Meter;
+use crate::routing::{key_access, route, Caller, ResolvedModel};
+use crate::rate_limit;
+use crate::shadow;
+use crate::structured::{self, StructuredOutput};
+use crate::tokenizer::Tokenizer;
+use crate::tools::{self, ToolCallDetector, ToolConfig};
 use crate::types::*;
 use crate::utils::{format_bytes_length, format_duration, truncate_text};
 
+pub(crate) type EventStream = Pin<Box<dyn Stream<Item = Result<EventResponse, PoeError>> + Send>>;
+type SseStream = Pin<Box<dyn Stream<Item = Result<String, std::convert::Infallible>> + Send>>;
+
 #[handler]
 pub async fn chat_completions(req: &mut Request, res: &mut Response) {
     let start_time = Instant::now();
     info!("📝 收到新的聊天完成請求");
 
-    let max_size:usize = std::env::var("MAX_REQUEST_SIZE")
-        .unwrap_or_else(|_| "1073741824".to_string()) // 預設 1GB
-        .parse()
-        .unwrap_or(1024 * 1024 * 1024);
+    let max_size = crate::settings::get().server.max_request_size;
     
-    // 讀取並解析 models.yaml 配置
-    let config = match Path::new("models.yaml").exists() {
-        true => {
-            match std::fs::read_to_string("models.yaml") {
-                Ok(contents) => {
-                    match serde_yaml::from_str::<Config>(&contents) {
-                        Ok(config) => config,
-                        Err(e) => {
-                            error!("❌ 解析 models.yaml 失敗: {}", e);
-                            Config {
-                                enable: Some(false),
-                                models: std::collections::HashMap::new(),
-                            }
-                        }
-                    }
-                },
-                Err(e) => {
-                    error!("❌ 讀取 models.yaml 失敗: {}", e);
-                    Config {
-                        enable: Some(false),
-                        models: std::collections::HashMap::new(),
-                    }
-                }
-            }
-        },
-        false => {
-            debug!("⚠️ models.yaml 不存在，預設為不啟用");
-            Config {
-                enable: Some(false),
-                models: std::collections::HashMap::new(),
-            }
-        }
-    };
+    let config = crate::config::current();
 
-    let access_key = match req.headers().get("Authorization") {
-        Some(auth) => {
-            let auth_str = auth.to_str().unwrap_or("");
-            if auth_str.starts_with("Bearer ") {
-                debug!("🔑 驗證令牌長度: {}", auth_str[7..].len());
-                auth_str[7..].to_string()
-            } else {
-                error!("❌ 無效的授權格式");
-                res.status_code(StatusCode::UNAUTHORIZED);
-                res.render(Json(json!({ "error": "無效的 Authorization" })));
-                return;
-            }
-        },
-        None => {
-            error!("❌ 缺少授權標頭");
-            res.status_code(StatusCode::UNAUTHORIZED);
-            res.render(Json(json!({ "error": "缺少 Authorization" })));
-            return;
-        }
+    let Some(credential) = authenticate(req, res) else {
+        return;
     };
 
     let chat_request = match req.payload_with_max_size(max_size).await {
@@ -121,72 +82,204 @@ pub async fn chat_completions(req: &mut Request, res: &mut Response) {
         }
     };
     
-    // 尋找映射的原始模型名稱
-    let (display_model, original_model) = if config.enable.unwrap_or(false) {
-        let requested_model = chat_request.model.clone();
-
-        // 檢查當前請求的模型是否是某個映射的目標
-        let mapping_entry = config.models.iter().find(|(_, cfg)| {
-            if let Some(mapping) = &cfg.mapping {
-                mapping.to_lowercase() == requested_model.to_lowercase()
-            } else {
-                false
+    let access = key_access(&config, credential.key.as_ref());
+    let caller = Caller { client_id: &credential.client_id, user: chat_request.user.as_deref(), access: access.as_ref() };
+    let (resolved, chain) = match route(&config, &chat_request.model, &caller) {
+        Ok(routed) => routed,
+        Err(message) => {
+            error!("❌ 模型存取被拒: {}", message);
+            res.status_code(StatusCode::NOT_FOUND);
+            res.render(Json(OpenAIErrorResponse {
+                error: OpenAIError {
+                    message,
+                    r#type: "invalid_request_error".to_string(),
+                    code: "model_not_found".to_string(),
+                    param: Some("model".to_string()),
+                }
+            }));
+            return;
+        }
+    };
+    let ResolvedModel { display: display_model, bot: original_model, config_key } = resolved;
+
+    info!("🤖 使用模型: {} (原始: {})", display_model, original_model);
+
+    let client = PoeClientWrapper::new(&original_model, &credential.upload_key());
+
+    // 每個請求最多可產生的回應數量，避免意外大量消耗 Poe 點數
+    let choice_limit = max_choices();
+    let choice_count = chat_request.n.unwrap_or(1) as usize;
+    if choice_count == 0 || choice_count > choice_limit {
+        error!("❌ n 超出允許範圍: {} (上限: {})", choice_count, choice_limit);
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Json(OpenAIErrorResponse {
+            error: OpenAIError {
+                message: format!("n 必須介於 1 到 {} 之間", choice_limit),
+                r#type: "invalid_request_error".to_string(),
+                code: "invalid_value".to_string(),
+                param: Some("n".to_string()),
             }
-        });
+        }));
+        return;
+    }
 
-        if let Some((original_name, _)) = mapping_entry {
-            // 如果找到映射，使用原始模型名稱
-            debug!("🔄 反向模型映射: {} -> {}", requested_model, original_name);
-            (requested_model, original_name.clone())
-        } else {
-            // 如果沒找到映射，檢查是否有直接映射配置
-            if let Some(model_config) = config.models.get(&requested_model) {
-                if let Some(mapped_name) = &model_config.mapping {
-                    debug!("🔄 直接模型映射: {} -> {}", requested_model, mapped_name);
-                    (requested_model.clone(), requested_model)
-                } else {
-                    // 沒有映射配置，使用原始名稱
-                    (requested_model.clone(), requested_model)
+    let tool_config = ToolConfig::from_request(&chat_request);
+    let structured_output = match StructuredOutput::from_request(&chat_request) {
+        Ok(structured_output) => structured_output,
+        Err(e) => {
+            error!("❌ response_format 無效: {}", e);
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(OpenAIErrorResponse {
+                error: OpenAIError {
+                    message: e,
+                    r#type: "invalid_request_error".to_string(),
+                    code: "invalid_response_format".to_string(),
+                    param: Some("response_format".to_string()),
                 }
-            } else {
-                // 完全沒有相關配置，使用原始名稱
-                (requested_model.clone(), requested_model)
-            }
+            }));
+            return;
         }
-    } else {
-        // 配置未啟用，直接使用原始名稱
-        (chat_request.model.clone(), chat_request.model.clone())
     };
 
-    info!("🤖 使用模型: {} (原始: {})", display_model, original_model);
+    let tokenizer = Tokenizer::for_model(&original_model, config.models.get(&config_key));
+    let limits = OutputLimits::new(
+        chat_request.stop.map(StopSequences::into_vec).unwrap_or_default(),
+        resolve_max_tokens(&config_key, chat_request.max_completion_tokens.or(chat_request.max_tokens)),
+        tokenizer,
+    );
+    let include_usage = chat_request.stream_options
+        .and_then(|options| options.include_usage)
+        .unwrap_or(false);
 
-    let client = PoeClientWrapper::new(&original_model, &access_key);
+    let mut messages = chat_request.messages;
+    if let Err(e) = client.upload_data_images(&mut messages).await {
+        error!("❌ 處理圖片附件失敗: {}", e);
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Json(OpenAIErrorResponse {
+            error: OpenAIError {
+                message: e,
+                r#type: "invalid_request_error".to_string(),
+                code: "invalid_image_url".to_string(),
+                param: Some("messages".to_string()),
+            }
+        }));
+        return;
+    }
 
-    let query_request = create_query_request(&original_model, chat_request.messages, chat_request.temperature);
+    let mut messages = tools::prepare_messages(messages, tool_config.as_ref());
+    if let Some(structured_output) = &structured_output {
+        structured_output.inject_instruction(&mut messages);
+    }
+    apply_system_prompt(&config_key, &mut messages);
+
+    let usage = UsageContext {
+        prompt_tokens: tokenizer.count_messages(&messages),
+        include_usage,
+    };
+    debug!("🧮 估算 prompt token 數量: {} | tokenizer: {:?}", usage.prompt_tokens, tokenizer);
 
     let stream = chat_request.stream.unwrap_or(false);
-    debug!("🔄 請求模式: {}", if stream { "串流" } else { "非串流" });
+    debug!("🔄 請求模式: {} | 回應數量: {}", if stream { "串流" } else { "非串流" }, choice_count);
 
-    match client.stream_request(query_request).await {
-        Ok(event_stream) => {
-            if stream {
-                handle_stream_response(res, event_stream, &display_model).await;
-            } else {
-                handle_non_stream_response(res, event_stream, &display_model).await;
-            }
-        },
+    // 第一個回應依 fallback 順序決定回答的 bot，其餘回應使用同一個 bot
+    let build_request = || create_query_request(&config_key, messages.clone(), chat_request.temperature, limits.stop.clone());
+    let (client, first_stream) = match stream_with_fallbacks(&chain, &credential.poe_keys, &build_request).await {
+        Ok(result) => result,
         Err(e) => {
             error!("❌ 建立串流請求失敗: {}", e);
             res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
             res.render(Json(json!({ "error": e.to_string() })));
+            return;
+        }
+    };
+    let display_model = answered_model(res, display_model, &original_model, &client);
+    let first_stream = shadow::mirror(&config, &config_key, &client, &build_request, first_stream);
+
+    // n > 1 時同時向 Poe 發出其餘請求
+    let requests = (1..choice_count).map(|_| client.stream_request(build_request()));
+    let mut event_streams = Vec::with_capacity(choice_count);
+    event_streams.push(first_stream);
+    for result in future::join_all(requests).await {
+        match result {
+            Ok(event_stream) => event_streams.push(event_stream),
+            Err(e) => {
+                error!("❌ 建立串流請求失敗: {}", e);
+                res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+                res.render(Json(json!({ "error": e.to_string() })));
+                return;
+            }
         }
     }
 
+    if let Some(structured_output) = structured_output {
+        // 結構化輸出驗證失敗時需要以相同的對話重新詢問
+        let context = StructuredContext {
+            client: &client,
+            model: &config_key,
+            messages,
+            temperature: chat_request.temperature,
+            limits,
+            structured_output,
+        };
+        handle_structured_response(res, event_streams, &display_model, tool_config, context, usage, stream).await;
+    } else if stream {
+        handle_stream_response(res, event_streams, &display_model, tool_config, limits, usage).await;
+    } else {
+        handle_non_stream_response(res, event_streams, &display_model, tool_config, limits, usage).await;
+    }
+
     let duration = start_time.elapsed();
     info!("✅ 請求處理完成 | 耗時: {}", format_duration(duration));
 }
 
-fn convert_poe_error_to_openai(error: &poe_api_process::types::ErrorResponse) -> (StatusCode, OpenAIErrorResponse) {
+/// 驗證 OpenAI 格式端點的 Bearer 金鑰，失敗時以 OpenAI 的錯誤格式回應
+pub(crate) fn authenticate(req: &Request, res: &mut Response) -> Option<Credential> {
+    let token = req.headers().get("Authorization")
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "));
+    let result = match token {
+        Some(token) => {
+            debug!("🔑 驗證令牌長度: {}", token.len());
+            crate::keys::authenticate(token).map_err(|e| e.to_string())
+        },
+        None => Err("缺少或無效的 Authorization".to_string()),
+    };
+    match result {
+        Ok(credential) => Some(credential),
+        Err(message) => {
+            error!("❌ 驗證失敗: {}", message);
+            res.status_code(StatusCode::UNAUTHORIZED);
+            res.render(Json(OpenAIErrorResponse {
+                error: OpenAIError {
+                    message,
+                    r#type: "invalid_request_error".to_string(),
+                    code: "invalid_api_key".to_string(),
+                    param: None,
+                }
+            }));
+            None
+        }
+    }
+}
+
+/// 在回應標頭標示實際回答的 bot；改用 fallback 時回應中的 model 也改為該 bot
+pub(crate) fn answered_model(res: &mut Response, display_model: String, primary_bot: &str, client: &PoeClientWrapper) -> String {
+    if let Ok(value) = header::HeaderValue::from_str(client.model()) {
+        res.headers_mut().insert("x-poe-bot", value);
+    }
+    if client.model() == primary_bot {
+        display_model
+    } else {
+        client.model().to_string()
+    }
+}
+
+/// 單一請求允許的最大 n 值
+pub(crate) fn max_choices() -> usize {
+    crate::settings::get().limits.max_choices
+}
+
+pub(crate) fn convert_poe_error_to_openai(error: &poe_api_process::types::ErrorResponse) -> (StatusCode, OpenAIErrorResponse) {
     debug!("🔄 轉換錯誤響應 | 錯誤文本: {}", error.text);
     
     let (status, error_type, code) = if error.text.contains("Internal server error") {
@@ -235,20 +328,136 @@ fn convert_poe_error_to_openai(error: &poe_api_process::types::ErrorResponse) ->
 
 async fn handle_stream_response(
     res: &mut Response,
-    mut event_stream: Pin<Box<dyn Stream<Item = Result<EventResponse, PoeError>> + Send>>,
-    model: &str
+    event_streams: Vec<EventStream>,
+    model: &str,
+    tool_config: Option<ToolConfig>,
+    limits: OutputLimits,
+    usage: UsageContext,
 ) {
     let start_time = Instant::now();
     let id = nanoid!(10);
     let created = Utc::now().timestamp();
-    let model = model.to_string(); // 轉換為擁有的 String
     
-    info!("🌊 開始處理串流響應 | ID: {} | 模型: {}", id, model);
+    info!("🌊 開始處理串流響應 | ID: {} | 模型: {} | 回應數量: {}", id, model, event_streams.len());
 
     res.headers_mut().insert(header::CONTENT_TYPE, "text/event-stream".parse().unwrap());
     res.headers_mut().insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
     res.headers_mut().insert(header::CONNECTION, "keep-alive".parse().unwrap());
 
+    // 所有回應共用的輸出 token 計數，最後一併回報用量
+    let completion_tokens = Arc::new(AtomicUsize::new(0));
+    let mut choice_streams = Vec::with_capacity(event_streams.len());
+
+    for (index, event_stream) in event_streams.into_iter().enumerate() {
+        let meta = ChunkMeta {
+            id: id.clone(),
+            created,
+            model: model.to_string(),
+            index: index as u32,
+        };
+        match start_choice_stream(event_stream, meta, tool_config.clone(), limits.limiter(), Arc::clone(&completion_tokens)).await {
+            Ok(choice_stream) => choice_streams.push(choice_stream),
+            Err(error) => {
+                let (status, error_response) = convert_poe_error_to_openai(&error);
+                res.status_code(status);
+                res.render(Json(error_response));
+                return;
+            }
+        }
+    }
+
+    let done_meta = ChunkMeta {
+        id: id.clone(),
+        created,
+        model: model.to_string(),
+        index: 0,
+    };
+    let done = stream::once(async move {
+        let usage = usage.stream_usage(completion_tokens.load(Ordering::Relaxed));
+        Ok::<_, std::convert::Infallible>(create_stream_done(&done_meta, usage))
+    });
+
+    // 多個回應的片段依到達順序交錯輸出，以 choices[].index 區分
+    let chunks = rate_limit::hold_permit(res, stream::select_all(choice_streams).chain(done));
+    res.stream(chunks);
+
+    let duration = start_time.elapsed();
+    info!("✅ 串流響應處理完成 | ID: {} | 耗時: {}", id, format_duration(duration));
+}
+
+/// 建立單一回應的 OpenAI 串流片段；初始事件即為錯誤時回傳該錯誤
+async fn start_choice_stream(
+    event_stream: EventStream,
+    meta: ChunkMeta,
+    tool_config: Option<ToolConfig>,
+    limiter: OutputLimiter,
+    completion_tokens: Arc<AtomicUsize>,
+) -> Result<SseStream, poe_api_process::types::ErrorResponse> {
+    let text_stream = start_text_stream(event_stream, limiter).await?;
+    let legacy_functions = tool_config.as_ref().map(|config| config.legacy).unwrap_or(false);
+    let mut tool_detector = tool_config.as_ref().map(|_| ToolCallDetector::default());
+
+    let role_chunk = create_stream_chunk(&meta, "", None);
+    let role_message = format!("data: {}\n\n", serde_json::to_string(&role_chunk).unwrap());
+
+    Ok(Box::pin(stream::once(future::ready(Ok::<_, std::convert::Infallible>(role_message)))
+        .chain(text_stream.map(move |event| {
+            let message = match event {
+                TextEvent::Delta(text) => {
+                    let text = match tool_detector.as_mut() {
+                        Some(detector) => detector.push(&text),
+                        None => text,
+                    };
+                    if text.is_empty() {
+                        String::new()
+                    } else {
+                        let chunk = create_stream_chunk(&meta, &text, None);
+                        format!("data: {}\n\n", serde_json::to_string(&chunk).unwrap())
+                    }
+                },
+                TextEvent::Error(message) => {
+                    let error_chunk = json!({
+                        "error": {
+                            "message": message,
+                            "type": "stream_error",
+                            "code": "stream_error"
+                        }
+                    });
+                    format!("data: {}\n\n", serde_json::to_string(&error_chunk).unwrap())
+                },
+                TextEvent::Finished { finish_reason, completion_tokens: tokens, .. } => {
+                    completion_tokens.fetch_add(tokens, Ordering::Relaxed);
+                    let (leftover, tool_calls) = match tool_detector.take() {
+                        Some(detector) => detector.finish(),
+                        None => (String::new(), Vec::new()),
+                    };
+                    create_stream_ending(&meta, &leftover, tool_calls, legacy_functions, finish_reason)
+                },
+            };
+            Ok::<_, std::convert::Infallible>(message)
+        }))))
+}
+
+/// 從 Poe 事件轉換而來、已套用輸出限制的文字事件
+pub(crate) enum TextEvent {
+    Delta(String),
+    Error(String),
+    Finished {
+        finish_reason: &'static str,
+        stop_sequence: Option<String>,
+        completion_tokens: usize,
+    },
+}
+
+pub(crate) type TextStream = Pin<Box<dyn Stream<Item = TextEvent> + Send>>;
+
+/// 檢查初始事件並將 Poe 事件流轉為文字事件流，供各種 API 格式共用；初始事件即為錯誤時回傳該錯誤
+pub(crate) async fn start_text_stream(
+    mut event_stream: EventStream,
+    mut limiter: OutputLimiter,
+) -> Result<TextStream, poe_api_process::types::ErrorResponse> {
+    // 串流在端點返回後才輸出，先取得計量以便結束時記錄輸出 token
+    let meter = Meter::current();
     let mut replace_response = false;
     let mut full_content = String::new();
     let mut first_two_events = Vec::new();
@@ -283,135 +492,172 @@ async fn handle_stream_response(
                 if !replace_response {
                     if let Some(error) = event.error {
                         error!("❌ 串流處理錯誤: {}", error.text);
-                        let (status, error_response) = convert_poe_error_to_openai(&error);
-                        res.status_code(status);
-                        res.render(Json(error_response));
-                        return;
+                        return Err(error);
                     }
                 }
             },
             EventType::Done => {
                 debug!("✅ 初始事件處理完成");
                 break;
+            },
+            _ => {
+                debug!("⏭️ 忽略其他事件類型");
             }
         }
     }
 
-    let id_for_log = id.clone(); // 為最後的日誌克隆一個副本
-
     if replace_response {
         debug!("🔄 使用 ReplaceResponse 處理模式");
-        let processed_stream = {
-            let id = id.clone(); // 為閉包克隆一個副本
-            let model = model.clone(); // 為閉包克隆一個副本
-            
-            stream::once(async move {
-                let content = handle_replace_response(event_stream).await;
-                debug!("📤 處理完成 | 內容長度: {}", format_bytes_length(content.len()));
-                
-                let content_chunk = create_stream_chunk(&id, created, &model, &content, None);
-                let content_json = serde_json::to_string(&content_chunk).unwrap();
-                let content_message = format!("data: {}\n\n", content_json);
-                
-                let final_chunk = create_stream_chunk(&id, created, &model, "", Some("stop".to_string()));
-                let final_json = serde_json::to_string(&final_chunk).unwrap();
-                let final_message = format!("{}data: {}\n\ndata: [DONE]\n\n", 
-                    content_message, final_json);
-                
-                Ok::<_, std::convert::Infallible>(final_message)
-            })
-        };
+        return Ok(Box::pin(stream::once(async move {
+            let content = handle_replace_response(event_stream).await;
+            debug!("📤 處理完成 | 內容長度: {}", format_bytes_length(content.len()));
+            let content = limiter.apply(&content);
+            meter.complete(limiter.emitted_tokens());
+            stream::iter(vec![
+                TextEvent::Delta(content),
+                TextEvent::Finished {
+                    finish_reason: limiter.finish_reason(),
+                    stop_sequence: limiter.stop_sequence().map(str::to_string),
+                    completion_tokens: limiter.emitted_tokens(),
+                },
+            ])
+        }).flatten()));
+    }
 
-        res.stream(processed_stream);
-    } else {
-        debug!("🔄 使用標準串流處理模式");
-        let initial_chunk = create_stream_chunk(&id, created, &model, &full_content, None);
-        let initial_chunk_json = serde_json::to_string(&initial_chunk).unwrap();
-        let initial_message = format!("data: {}\n\n", initial_chunk_json);
-
-        let processed_stream = {
-            let id = id.clone(); // 為閉包克隆一個副本
-            let model = model.clone(); // 為閉包克隆一個副本
-            
-            stream::once(future::ready(Ok::<_, std::convert::Infallible>(initial_message)))
-                .chain(stream::unfold(
-                    (event_stream, false),
-                    move |(mut event_stream, mut is_done)| {
-                        let id = id.clone();
-                        let model = model.clone();
-                        
-                        async move {
-                            if is_done {
-                                debug!("✅ 串流處理完成");
-                                return None;
-                            }
-                            match event_stream.next().await {
-                                Some(Ok(event)) => {
-                                    match event.event {
-                                        EventType::Text => {
-                                            if let Some(data) = event.data {
-                                                debug!("📝 處理文本片段: {}", truncate_text(&data.text, 50));
-                                                let chunk = create_stream_chunk(&id, created, &model, &data.text, None);
-                                                let chunk_json = serde_json::to_string(&chunk).unwrap();
-                                                Some((Ok(format!("data: {}\n\n", chunk_json)), (event_stream, is_done)))
-                                            } else {
-                                                Some((Ok(String::new()), (event_stream, is_done)))
-                                            }
-                                        },
-                                        EventType::Error => {
-                                            if let Some(error) = event.error {
-                                                error!("❌ 串流處理錯誤: {}", error.text);
-                                                let error_chunk = json!({
-                                                    "error": {
-                                                        "message": error.text,
-                                                        "type": "stream_error",
-                                                        "code": "stream_error"
-                                                    }
-                                                });
-                                                let error_message = format!("data: {}\n\ndata: [DONE]\n\n", 
-                                                    serde_json::to_string(&error_chunk).unwrap());
-                                                Some((Ok(error_message), (event_stream, true)))
-                                            } else {
-                                                Some((Ok(String::new()), (event_stream, true)))
-                                            }
-                                        },
-                                        EventType::Done => {
-                                            debug!("✅ 串流完成");
-                                            is_done = true;
-                                            let final_chunk = create_stream_chunk(&id, created, &model, "", Some("stop".to_string()));
-                                            let final_chunk_json = serde_json::to_string(&final_chunk).unwrap();
-                                            Some((Ok(format!("data: {}\n\ndata: [DONE]\n\n", final_chunk_json)), (event_stream, is_done)))
-                                        },
-                                        _ => {
-                                            debug!("⏭️ 忽略其他事件類型");
-                                            Some((Ok(String::new()), (event_stream, is_done)))
-                                        },
+    debug!("🔄 使用標準串流處理模式");
+    let initial_content = limiter.push(&full_content);
+    let state = TextStreamState {
+        event_stream,
+        limiter,
+        meter,
+        finishing: false,
+        is_done: false,
+    };
+
+    Ok(Box::pin(stream::once(future::ready(TextEvent::Delta(initial_content)))
+        .chain(stream::unfold(state, |mut state| async move {
+            loop {
+                if state.is_done {
+                    debug!("✅ 串流處理完成");
+                    return None;
+                }
+                if state.finishing || state.limiter.is_finished() {
+                    if state.limiter.is_finished() && !state.finishing {
+                        // 已觸發 stop 序列或 token 上限，不再讀取上游串流
+                        debug!("✂️ 提前結束串流 | 原因: {}", state.limiter.finish_reason());
+                    }
+                    state.finishing = true;
+                    let leftover = state.limiter.flush();
+                    if !leftover.is_empty() {
+                        return Some((TextEvent::Delta(leftover), state));
+                    }
+                    state.is_done = true;
+                    state.meter.complete(state.limiter.emitted_tokens());
+                    let finished = TextEvent::Finished {
+                        finish_reason: state.limiter.finish_reason(),
+                        stop_sequence: state.limiter.stop_sequence().map(str::to_string),
+                        completion_tokens: state.limiter.emitted_tokens(),
+                    };
+                    return Some((finished, state));
+                }
+                match state.event_stream.next().await {
+                    Some(Ok(event)) => {
+                        match event.event {
+                            EventType::Text => {
+                                if let Some(data) = event.data {
+                                    debug!("📝 處理文本片段: {}", truncate_text(&data.text, 50));
+                                    let text = state.limiter.push(&data.text);
+                                    if !text.is_empty() {
+                                        return Some((TextEvent::Delta(text), state));
                                     }
-                                },
-                                _ => None,
-                            }
+                                }
+                            },
+                            EventType::Error => {
+                                state.is_done = true;
+                                if let Some(error) = event.error {
+                                    error!("❌ 串流處理錯誤: {}", error.text);
+                                    return Some((TextEvent::Error(error.text), state));
+                                }
+                            },
+                            EventType::Done => {
+                                debug!("✅ 串流完成");
+                                state.finishing = true;
+                            },
+                            _ => {
+                                debug!("⏭️ 忽略其他事件類型");
+                            },
                         }
                     },
-                ))
-        };
-
-        res.stream(processed_stream);
-    }
+                    _ => {
+                        debug!("⚠️ 事件流結束但未收到完成信號");
+                        state.finishing = true;
+                    },
+                }
+            }
+        }))))
+}
 
-    let duration = start_time.elapsed();
-    info!("✅ 串流響應處理完成 | ID: {} | 耗時: {}", id_for_log, format_duration(duration));
+/// 文字事件流的處理狀態
+struct TextStreamState {
+    event_stream: EventStream,
+    limiter: OutputLimiter,
+    meter: Meter,
+    /// 上游已結束或已達輸出限制，正在輸出保留中的文字
+    finishing: bool,
+    is_done: bool,
 }
 
 async fn handle_non_stream_response(
     res: &mut Response,
-    mut event_stream: Pin<Box<dyn Stream<Item = Result<EventResponse, PoeError>> + Send>>,
-    model: &str
+    event_streams: Vec<EventStream>,
+    model: &str,
+    tool_config: Option<ToolConfig>,
+    limits: OutputLimits,
+    usage: UsageContext,
 ) {
     let start_time = Instant::now();
     let id = nanoid!(10);
     
-    info!("📦 開始處理非串流響應 | ID: {} | 模型: {}", id, model);
+    info!("📦 開始處理非串流響應 | ID: {} | 模型: {} | 回應數量: {}", id, model, event_streams.len());
+
+    let results = future::join_all(event_streams.into_iter().map(|event_stream| {
+        let mut limiter = limits.limiter();
+        async move {
+            let content = collect_response(event_stream, &mut limiter).await?;
+            Ok::<_, poe_api_process::types::ErrorResponse>((content, limiter))
+        }
+    })).await;
+
+    let mut choices = Vec::with_capacity(results.len());
+    let mut completion_tokens = 0;
+    for (index, result) in results.into_iter().enumerate() {
+        match result {
+            Ok((content, limiter)) => {
+                debug!("📤 回應收集完成 | 索引: {} | 內容長度: {}", index, format_bytes_length(content.len()));
+                completion_tokens += limiter.emitted_tokens();
+                choices.push(create_completion_choice(index as u32, content, tool_config.as_ref(), limiter.finish_reason()));
+            },
+            Err(error) => {
+                let (status, error_response) = convert_poe_error_to_openai(&error);
+                res.status_code(status);
+                res.render(Json(error_response));
+                return;
+            }
+        }
+    }
 
+    let response = create_completion_response(&id, model, choices, usage.usage(completion_tokens));
+    res.render(Json(response));
+
+    let duration = start_time.elapsed();
+    info!("✅ 非串流響應處理完成 | ID: {} | 耗時: {}", id, format_duration(duration));
+}
+
+/// 收集完整的回應內容並套用輸出限制，遇到 Poe 錯誤事件時回傳該錯誤
+pub(crate) async fn collect_response(
+    mut event_stream: EventStream,
+    limiter: &mut OutputLimiter,
+) -> Result<String, poe_api_process::types::ErrorResponse> {
     let mut replace_response = false;
     let mut full_content = String::new();
     let mut first_two_events = Vec::new();
@@ -445,15 +691,15 @@ async fn handle_non_stream_response(
             EventType::Error => {
                 if let Some(error) = event.error {
                     error!("❌ 處理錯誤: {}", error.text);
-                    let (status, error_response) = convert_poe_error_to_openai(&error);
-                    res.status_code(status);
-                    res.render(Json(error_response));
-                    return;
+                    return Err(error);
                 }
             },
             EventType::Done => {
                 debug!("✅ 初始事件處理完成");
                 break;
+            },
+            _ => {
+                debug!("⏭️ 忽略其他事件類型");
             }
         }
     }
@@ -462,85 +708,237 @@ async fn handle_non_stream_response(
         debug!("🔄 使用 ReplaceResponse 處理模式");
         let content = handle_replace_response(event_stream).await;
         debug!("📤 最終內容長度: {}", format_bytes_length(content.len()));
+        let content = limiter.apply(&content);
+        Meter::current().complete(limiter.emitted_tokens());
+        return Ok(content);
+    }
 
-        let response = ChatCompletionResponse {
-            id: format!("chatcmpl-{}", nanoid!(10)),
-            object: "chat.completion".to_string(),
-            created: Utc::now().timestamp(),
-            model: model.to_string(),
-            choices: vec![CompletionChoice {
-                index: 0,
-                message: CompletionMessage {
-                    role: "assistant".to_string(),
-                    content,
-                    refusal: None,
-                },
-                logprobs: None,
-                finish_reason: Some("stop".to_string()),
-            }],
-            usage: None,
+    debug!("🔄 使用標準非串流處理模式");
+    let mut response_content = limiter.push(&full_content);
+
+    while !limiter.is_finished() {
+        let Some(Ok(event)) = event_stream.next().await else {
+            break;
         };
+        match event.event {
+            EventType::Text => {
+                if let Some(data) = event.data {
+                    debug!("📝 處理文本片段: {}", truncate_text(&data.text, 50));
+                    response_content.push_str(&limiter.push(&data.text));
+                }
+            },
+            EventType::Error => {
+                if let Some(error) = event.error {
+                    error!("❌ 處理錯誤: {}", error.text);
+                    return Err(error);
+                }
+            },
+            EventType::Done => {
+                debug!("✅ 回應收集完成");
+                break;
+            },
+            _ => {
+                debug!("⏭️ 忽略其他事件類型");
+            }
+        }
+    }
 
-        res.render(Json(response));
+    if limiter.is_finished() {
+        debug!("✂️ 已達輸出限制，提前結束上游串流 | 原因: {}", limiter.finish_reason());
+    }
+    response_content.push_str(&limiter.flush());
+    Meter::current().complete(limiter.emitted_tokens());
+    Ok(response_content)
+}
+
+/// 結構化輸出重新詢問所需的資訊
+struct StructuredContext<'a> {
+    client: &'a PoeClientWrapper,
+    model: &'a str,
+    messages: Vec<Message>,
+    temperature: Option<f32>,
+    limits: OutputLimits,
+    structured_output: StructuredOutput,
+}
+
+/// 單一回應通過驗證後的結構化輸出
+struct StructuredChoice {
+    content: String,
+    finish_reason: &'static str,
+    /// 重新詢問額外送出的 prompt token 數量
+    retry_prompt_tokens: usize,
+    completion_tokens: usize,
+    attempts: usize,
+}
+
+/// 收集完整輸出並驗證 JSON，失敗時附上錯誤重新詢問；串流模式在驗證通過後一次輸出
+async fn handle_structured_response(
+    res: &mut Response,
+    event_streams: Vec<EventStream>,
+    model: &str,
+    tool_config: Option<ToolConfig>,
+    context: StructuredContext<'_>,
+    usage: UsageContext,
+    stream: bool,
+) {
+    let start_time = Instant::now();
+    let id = nanoid!(10);
+
+    info!("🧩 開始處理結構化輸出 | ID: {} | 模型: {} | 最大重試次數: {}", id, model, structured::max_retries());
+
+    let results = future::join_all(event_streams.into_iter()
+        .map(|event_stream| generate_structured_choice(event_stream, &context, tool_config.as_ref()))).await;
+
+    let mut choices = Vec::with_capacity(results.len());
+    for result in results {
+        match result {
+            Ok(choice) => choices.push(choice),
+            Err((status, error_response)) => {
+                res.status_code(status);
+                res.render(Json(error_response));
+                return;
+            }
+        }
+    }
+
+    let attempts: usize = choices.iter().map(|choice| choice.attempts).sum();
+    let usage = UsageContext {
+        prompt_tokens: usage.prompt_tokens + choices.iter().map(|choice| choice.retry_prompt_tokens).sum::<usize>(),
+        include_usage: usage.include_usage,
+    };
+    let completion_tokens = choices.iter().map(|choice| choice.completion_tokens).sum();
+
+    if stream {
+        render_buffered_stream(res, &id, model, choices, tool_config.as_ref(), usage.stream_usage(completion_tokens));
     } else {
-        debug!("🔄 使用標準非串流處理模式");
-        let mut response_content = full_content;
-
-        while let Some(Ok(event)) = event_stream.next().await {
-            match event.event {
-                EventType::Text => {
-                    if let Some(data) = event.data {
-                        debug!("📝 處理文本片段: {}", truncate_text(&data.text, 50));
-                        response_content.push_str(&data.text);
-                    }
-                },
-                EventType::Error => {
-                    if let Some(error) = event.error {
-                        error!("❌ 處理錯誤: {}", error.text);
-                        let (status, error_response) = convert_poe_error_to_openai(&error);
-                        res.status_code(status);
-                        res.render(Json(error_response));
-                        return;
-                    }
-                },
-                EventType::Done => {
-                    debug!("✅ 回應收集完成");
-                    break;
-                },
-                _ => {
-                    debug!("⏭️ 忽略其他事件類型");
+        let choices = choices.into_iter()
+            .enumerate()
+            .map(|(index, choice)| create_completion_choice(index as u32, choice.content, tool_config.as_ref(), choice.finish_reason))
+            .collect();
+        let response = create_completion_response(&id, model, choices, usage.usage(completion_tokens));
+        res.render(Json(response));
+    }
+
+    let duration = start_time.elapsed();
+    info!("✅ 結構化輸出處理完成 | ID: {} | 重試次數: {} | 耗時: {}", id, attempts, format_duration(duration));
+}
+
+/// 產生單一通過驗證的結構化輸出，驗證失敗時附上錯誤重新詢問直到達到重試上限
+async fn generate_structured_choice(
+    mut event_stream: EventStream,
+    context: &StructuredContext<'_>,
+    tool_config: Option<&ToolConfig>,
+) -> Result<StructuredChoice, (StatusCode, OpenAIErrorResponse)> {
+    let max_retries = structured::max_retries();
+    let mut messages = context.messages.clone();
+    let mut attempt = 0;
+    // 重新詢問也會消耗用量，累計每次嘗試的 token 數量
+    let mut retry_prompt_tokens = 0;
+    let mut completion_tokens = 0;
+
+    loop {
+        let mut limiter = context.limits.limiter();
+        let content = collect_response(event_stream, &mut limiter).await
+            .map_err(|error| convert_poe_error_to_openai(&error))?;
+        completion_tokens += limiter.emitted_tokens();
+
+        let done = |content: String| StructuredChoice {
+            content,
+            finish_reason: limiter.finish_reason(),
+            retry_prompt_tokens,
+            completion_tokens,
+            attempts: attempt,
+        };
+
+        // 模型選擇呼叫工具時不需要驗證 JSON
+        if tool_config.is_some() && tools::parse_tool_calls(&content).is_some() {
+            return Ok(done(content));
+        }
+
+        // 被 max_tokens 截斷的輸出不可能通過驗證，照 OpenAI 的行為直接回傳
+        if limiter.finish_reason() == "length" {
+            warn!("⚠️ 結構化輸出被 max_tokens 截斷，略過驗證");
+            return Ok(done(content));
+        }
+
+        let validation_error = match context.structured_output.validate(&content) {
+            Ok(json) => return Ok(done(json)),
+            Err(validation_error) => validation_error,
+        };
+
+        if attempt >= max_retries {
+            error!("❌ 結構化輸出驗證失敗且已達重試上限 | 錯誤: {}", validation_error);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, OpenAIErrorResponse {
+                error: OpenAIError {
+                    message: format!("模型輸出在 {} 次重試後仍不符合 response_format: {}", max_retries, validation_error),
+                    r#type: "server_error".to_string(),
+                    code: "invalid_structured_output".to_string(),
+                    param: Some("response_format".to_string()),
                 }
-            }
+            }));
         }
 
-        debug!("📤 準備發送回應 | 內容長度: {}", format_bytes_length(response_content.len()));
-        let response = ChatCompletionResponse {
-            id: format!("chatcmpl-{}", id),
-            object: "chat.completion".to_string(),
-            created: Utc::now().timestamp(),
+        attempt += 1;
+        warn!("⚠️ 結構化輸出驗證失敗，重新詢問 | 第 {} 次 | 錯誤: {}", attempt, validation_error);
+        messages.push(Message::text("assistant", content));
+        messages.push(Message::text("user", StructuredOutput::retry_instruction(&validation_error)));
+
+        retry_prompt_tokens += context.limits.tokenizer.count_messages(&messages);
+        let query_request = create_query_request(context.model, messages.clone(), context.temperature, context.limits.stop.clone());
+        event_stream = context.client.stream_request(query_request).await
+            .map_err(|e| {
+                error!("❌ 重新詢問失敗: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, OpenAIErrorResponse {
+                    error: OpenAIError {
+                        message: e.to_string(),
+                        r#type: "server_error".to_string(),
+                        code: "upstream_error".to_string(),
+                        param: None,
+                    }
+                })
+            })?;
+    }
+}
+
+/// 將已收集完成的內容以串流格式一次輸出
+fn render_buffered_stream(res: &mut Response, id: &str, model: &str, choices: Vec<StructuredChoice>, tool_config: Option<&ToolConfig>, usage: Option<Usage>) {
+    let created = Utc::now().timestamp();
+    let legacy_functions = tool_config.map(|config| config.legacy).unwrap_or(false);
+    let mut message = String::new();
+
+    for (index, choice) in choices.into_iter().enumerate() {
+        let meta = ChunkMeta {
+            id: id.to_string(),
+            created,
             model: model.to_string(),
-            choices: vec![CompletionChoice {
-                index: 0,
-                message: CompletionMessage {
-                    role: "assistant".to_string(),
-                    content: response_content,
-                    refusal: None,
-                },
-                logprobs: None,
-                finish_reason: Some("stop".to_string()),
-            }],
-            usage: None,
+            index: index as u32,
+        };
+        let (content, tool_calls) = match tool_config.and_then(|_| tools::parse_tool_calls(&choice.content)) {
+            Some((text, calls)) => (text, calls),
+            None => (choice.content, Vec::new()),
         };
 
-        res.render(Json(response));
+        let role_chunk = create_stream_chunk(&meta, "", None);
+        message.push_str(&format!("data: {}\n\n", serde_json::to_string(&role_chunk).unwrap()));
+        message.push_str(&create_stream_ending(&meta, &content, tool_calls, legacy_functions, choice.finish_reason));
     }
 
-    let duration = start_time.elapsed();
-    info!("✅ 非串流響應處理完成 | ID: {} | 耗時: {}", id, format_duration(duration));
+    let done_meta = ChunkMeta {
+        id: id.to_string(),
+        created,
+        model: model.to_string(),
+        index: 0,
+    };
+    message.push_str(&create_stream_done(&done_meta, usage));
+
+    res.headers_mut().insert(header::CONTENT_TYPE, "text/event-stream".parse().unwrap());
+    res.headers_mut().insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+    res.headers_mut().insert(header::CONNECTION, "keep-alive".parse().unwrap());
+    res.stream(stream::once(future::ready(Ok::<_, std::convert::Infallible>(message))));
 }
 
 async fn handle_replace_response(
-    mut event_stream: Pin<Box<dyn Stream<Item = Result<EventResponse, PoeError>> + Send>>,
+    mut event_stream: EventStream,
 ) -> String {
     let start_time = Instant::now();
     debug!("🔄 開始處理 ReplaceResponse");
@@ -627,21 +1025,178 @@ async fn handle_replace_response(
     final_content
 }
 
-fn create_stream_chunk(id: &str, created: i64, model: &str, content: &str, finish_reason: Option<String>) -> ChatCompletionChunk {
-    let mut delta = Delta {
-        role: None,
-        content: None,
-        refusal: None,
+/// 用量估算所需的資訊
+#[derive(Clone, Copy)]
+pub(crate) struct UsageContext {
+    pub(crate) prompt_tokens: usize,
+    /// 串流模式是否要求回傳用量（stream_options.include_usage）
+    pub(crate) include_usage: bool,
+}
+
+impl UsageContext {
+    pub(crate) fn usage(&self, completion_tokens: usize) -> Option<Usage> {
+        Some(Usage {
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens,
+            total_tokens: self.prompt_tokens + completion_tokens,
+        })
+    }
+
+    pub(crate) fn stream_usage(&self, completion_tokens: usize) -> Option<Usage> {
+        if self.include_usage {
+            self.usage(completion_tokens)
+        } else {
+            None
+        }
+    }
+}
+
+fn create_completion_choice(index: u32, content: String, tool_config: Option<&ToolConfig>, finish_reason: &str) -> CompletionChoice {
+    let parsed = tool_config.and_then(|_| tools::parse_tool_calls(&content));
+    let legacy_functions = tool_config.map(|config| config.legacy).unwrap_or(false);
+
+    let (message, finish_reason) = match parsed {
+        Some((text, mut calls)) => {
+            let content = if text.is_empty() { None } else { Some(text) };
+            if legacy_functions {
+                let function_call = calls.remove(0).function;
+                (CompletionMessage {
+                    role: "assistant".to_string(),
+                    content,
+                    refusal: None,
+                    tool_calls: None,
+                    function_call: Some(function_call),
+                }, "function_call")
+            } else {
+                (CompletionMessage {
+                    role: "assistant".to_string(),
+                    content,
+                    refusal: None,
+                    tool_calls: Some(calls),
+                    function_call: None,
+                }, "tool_calls")
+            }
+        },
+        None => (CompletionMessage {
+            role: "assistant".to_string(),
+            content: Some(content),
+            refusal: None,
+            tool_calls: None,
+            function_call: None,
+        }, finish_reason),
+    };
+
+    CompletionChoice {
+        index,
+        message,
+        logprobs: None,
+        finish_reason: Some(finish_reason.to_string()),
+    }
+}
+
+fn create_completion_response(id: &str, model: &str, choices: Vec<CompletionChoice>, usage: Option<Usage>) -> ChatCompletionResponse {
+    ChatCompletionResponse {
+        id: format!("chatcmpl-{}", id),
+        object: "chat.completion".to_string(),
+        created: Utc::now().timestamp(),
+        model: model.to_string(),
+        choices,
+        usage,
+    }
+}
+
+/// 串流片段共用的識別資訊
+struct ChunkMeta {
+    id: String,
+    created: i64,
+    model: String,
+    index: u32,
+}
+
+/// 產生串流結尾：剩餘文字、工具呼叫片段與結束片段
+fn create_stream_ending(meta: &ChunkMeta, leftover: &str, mut tool_calls: Vec<ToolCall>, legacy_functions: bool, finish_reason: &str) -> String {
+    let mut message = String::new();
+
+    if !leftover.is_empty() {
+        let chunk = create_stream_chunk(meta, leftover, None);
+        message.push_str(&format!("data: {}\n\n", serde_json::to_string(&chunk).unwrap()));
+    }
+
+    let finish_reason = if tool_calls.is_empty() {
+        finish_reason
+    } else {
+        debug!("🔧 輸出工具呼叫片段 | 數量: {}", tool_calls.len());
+        let delta = if legacy_functions {
+            Delta {
+                function_call: Some(tool_calls.remove(0).function),
+                ..Default::default()
+            }
+        } else {
+            Delta {
+                tool_calls: Some(tool_calls.into_iter()
+                    .enumerate()
+                    .map(|(index, call)| ToolCallDelta {
+                        index: index as u32,
+                        id: call.id,
+                        r#type: call.r#type,
+                        function: call.function,
+                    })
+                    .collect()),
+                ..Default::default()
+            }
+        };
+        let chunk = ChatCompletionChunk {
+            id: format!("chatcmpl-{}", meta.id),
+            object: "chat.completion.chunk".to_string(),
+            created: meta.created,
+            model: meta.model.clone(),
+            choices: vec![Choice {
+                index: meta.index,
+                delta,
+                finish_reason: None,
+            }],
+            usage: None,
+        };
+        message.push_str(&format!("data: {}\n\n", serde_json::to_string(&chunk).unwrap()));
+        if legacy_functions { "function_call" } else { "tool_calls" }
     };
 
+    let final_chunk = create_stream_chunk(meta, "", Some(finish_reason.to_string()));
+    message.push_str(&format!("data: {}\n\n", serde_json::to_string(&final_chunk).unwrap()));
+    message
+}
+
+/// 產生用量片段（有要求時）與 [DONE]
+fn create_stream_done(meta: &ChunkMeta, usage: Option<Usage>) -> String {
+    match usage {
+        Some(usage) => {
+            debug!("🧮 輸出用量片段 | prompt: {} | completion: {}", usage.prompt_tokens, usage.completion_tokens);
+            let usage_chunk = ChatCompletionChunk {
+                id: format!("chatcmpl-{}", meta.id),
+                object: "chat.completion.chunk".to_string(),
+                created: meta.created,
+                model: meta.model.clone(),
+                choices: Vec::new(),
+                usage: Some(usage),
+            };
+            format!("data: {}\n\ndata: [DONE]\n\n", serde_json::to_string(&usage_chunk).unwrap())
+        },
+        None => "data: [DONE]\n\n".to_string(),
+    }
+}
+
+fn create_stream_chunk(meta: &ChunkMeta, content: &str, finish_reason: Option<String>) -> ChatCompletionChunk {
+    let mut delta = Delta::default();
+
     if content.is_empty() && finish_reason.is_none() {
         delta.role = Some("assistant".to_string());
     } else {
         delta.content = Some(content.to_string());
     }
 
-    debug!("🔧 創建串流片段 | ID: {} | 內容長度: {}", 
-        id,
+    debug!("🔧 創建串流片段 | ID: {} | 索引: {} | 內容長度: {}", 
+        meta.id,
+        meta.index,
         if let Some(content) = &delta.content {
             format_bytes_length(content.len())
         } else {
@@ -650,14 +1205,15 @@ fn create_stream_chunk(id: &str, created: i64, model: &str, content: &str, finis
     );
 
     ChatCompletionChunk {
-        id: format!("chatcmpl-{}", id),
+        id: format!("chatcmpl-{}", meta.id),
         object: "chat.completion.chunk".to_string(),
-        created,
-        model: model.to_string(),
+        created: meta.created,
+        model: meta.model.clone(),
         choices: vec![Choice {
-            index: 0,
+            index: meta.index,
             delta,
             finish_reason,
         }],
+        usage: None,
     }
 }
\ No newline at end of file
@@ -0,0 +1,298 @@
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use nanoid::nanoid;
+use salvo::http::header;
+use salvo::prelude::*;
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, error, info};
+use chrono::Utc;
+
+use crate::handlers::chat::{
+    answered_model, authenticate, collect_response, convert_poe_error_to_openai, max_choices, start_text_stream,
+    TextEvent, UsageContext,
+};
+use crate::limits::OutputLimits;
+use crate::poe_client::{apply_system_prompt, create_query_request, resolve_max_tokens, stream_with_fallbacks};
+use crate::routing::{key_access, route, Caller, ResolvedModel};
+use crate::rate_limit;
+use crate::shadow;
+use crate::tokenizer::Tokenizer;
+use crate::types::*;
+use crate::utils::format_duration;
+
+#[handler]
+pub async fn completions(req: &mut Request, res: &mut Response) {
+    let start_time = Instant::now();
+    info!("📝 收到新的文字完成請求");
+
+    let max_size = crate::settings::get().server.max_request_size;
+
+    let config = crate::config::current();
+
+    let Some(credential) = authenticate(req, res) else {
+        return;
+    };
+
+    let request = match req.payload_with_max_size(max_size).await {
+        Ok(bytes) => match serde_json::from_slice::<CompletionRequest>(&bytes) {
+            Ok(request) => {
+                debug!("📊 請求解析成功 | 模型: {} | 是否串流: {:?}", request.model, request.stream);
+                request
+            },
+            Err(e) => {
+                error!("❌ JSON 解析失敗: {}", e);
+                render_error(res, StatusCode::BAD_REQUEST, format!("JSON 解析失敗: {}", e), "parse_error", None);
+                return;
+            }
+        },
+        Err(e) => {
+            error!("❌ 請求大小超過限制或讀取失敗: {}", e);
+            render_error(res, StatusCode::PAYLOAD_TOO_LARGE,
+                format!("請求大小超過限制 ({} bytes) 或讀取失敗: {}", max_size, e), "payload_too_large", None);
+            return;
+        }
+    };
+
+    let prompts = match request.prompt {
+        None => vec![String::new()],
+        Some(CompletionPrompt::Single(prompt)) => vec![prompt],
+        Some(CompletionPrompt::Multiple(prompts)) if !prompts.is_empty() => prompts,
+        Some(CompletionPrompt::Multiple(_)) => {
+            render_error(res, StatusCode::BAD_REQUEST, "prompt 不可為空陣列".to_string(), "invalid_value", Some("prompt"));
+            return;
+        }
+    };
+
+    // 每個 prompt 各產生 n 個回應，總數受 MAX_CHOICES 限制
+    let n = request.n.unwrap_or(1) as usize;
+    let choice_count = prompts.len() * n;
+    let choice_limit = max_choices();
+    if n == 0 || choice_count > choice_limit {
+        error!("❌ 回應數量超出允許範圍: {} (上限: {})", choice_count, choice_limit);
+        render_error(res, StatusCode::BAD_REQUEST,
+            format!("prompt 數量乘以 n 必須介於 1 到 {} 之間", choice_limit), "invalid_value", Some("n"));
+        return;
+    }
+
+    let access = key_access(&config, credential.key.as_ref());
+    let caller = Caller { client_id: &credential.client_id, user: request.user.as_deref(), access: access.as_ref() };
+    let (resolved, chain) = match route(&config, &request.model, &caller) {
+        Ok(routed) => routed,
+        Err(message) => {
+            error!("❌ 模型存取被拒: {}", message);
+            render_error(res, StatusCode::NOT_FOUND, message, "model_not_found", Some("model"));
+            return;
+        }
+    };
+    let ResolvedModel { display: display_model, bot: original_model, config_key } = resolved;
+    info!("🤖 使用模型: {} (原始: {})", display_model, original_model);
+
+    let tokenizer = Tokenizer::for_model(&original_model, config.models.get(&config_key));
+    let limits = OutputLimits::new(
+        request.stop.map(StopSequences::into_vec).unwrap_or_default(),
+        resolve_max_tokens(&config_key, request.max_tokens),
+        tokenizer,
+    );
+    let echo = request.echo.unwrap_or(false);
+
+    // 每個 prompt 包成單一則 user 訊息；有 suffix 時以 system 提示要求輸出能接上後文
+    let conversations: Vec<Vec<Message>> = prompts.iter()
+        .map(|prompt| {
+            let mut messages = Vec::with_capacity(2);
+            if let Some(suffix) = request.suffix.as_ref().filter(|suffix| !suffix.is_empty()) {
+                messages.push(Message::instruction(format!(
+                    "Continue the user's text. Reply with only the inserted text, which will be followed directly by:\n{}",
+                    suffix
+                )));
+            }
+            messages.push(Message::text("user", prompt.clone()));
+            apply_system_prompt(&config_key, &mut messages);
+            messages
+        })
+        .collect();
+    let usage = UsageContext {
+        prompt_tokens: conversations.iter().map(|messages| tokenizer.count_messages(messages)).sum(),
+        include_usage: request.stream_options
+            .and_then(|options| options.include_usage)
+            .unwrap_or(false),
+    };
+
+    let build_request = |messages: &Vec<Message>| {
+        create_query_request(&config_key, messages.clone(), request.temperature, limits.stop.clone())
+    };
+    // 第一個回應依 fallback 順序決定回答的 bot，其餘回應使用同一個 bot
+    let (client, first_stream) = match stream_with_fallbacks(&chain, &credential.poe_keys, || build_request(&conversations[0])).await {
+        Ok(result) => result,
+        Err(e) => {
+            error!("❌ 建立串流請求失敗: {}", e);
+            render_error(res, StatusCode::BAD_GATEWAY, e, "upstream_error", None);
+            return;
+        }
+    };
+    let display_model = answered_model(res, display_model, &original_model, &client);
+    let first_stream = shadow::mirror(&config, &config_key, &client, || build_request(&conversations[0]), first_stream);
+
+    let requests = conversations.iter()
+        .flat_map(|messages| std::iter::repeat(messages).take(n))
+        .skip(1)
+        .map(|messages| client.stream_request(build_request(messages)));
+    let mut event_streams = Vec::with_capacity(choice_count);
+    event_streams.push(first_stream);
+    for result in future::join_all(requests).await {
+        match result {
+            Ok(event_stream) => event_streams.push(event_stream),
+            Err(e) => {
+                error!("❌ 建立串流請求失敗: {}", e);
+                render_error(res, StatusCode::BAD_GATEWAY, e.to_string(), "upstream_error", None);
+                return;
+            }
+        }
+    }
+
+    let id = format!("cmpl-{}", nanoid!(24));
+    let created = Utc::now().timestamp();
+    // 選項的順序與 OpenAI 相同：先依 prompt，再依 n
+    let echoes: Vec<String> = (0..choice_count)
+        .map(|index| if echo { prompts[index / n].clone() } else { String::new() })
+        .collect();
+
+    if request.stream.unwrap_or(false) {
+        debug!("🌊 開始處理文字完成串流響應 | ID: {} | 回應數量: {}", id, choice_count);
+        let completion_tokens = Arc::new(AtomicUsize::new(0));
+        let mut choice_streams = Vec::with_capacity(choice_count);
+
+        for (index, (event_stream, echo_text)) in event_streams.into_iter().zip(echoes).enumerate() {
+            let text_stream = match start_text_stream(event_stream, limits.limiter()).await {
+                Ok(text_stream) => text_stream,
+                Err(error) => {
+                    let (status, error_response) = convert_poe_error_to_openai(&error);
+                    res.status_code(status);
+                    res.render(Json(error_response));
+                    return;
+                }
+            };
+
+            let id = id.clone();
+            let model = display_model.clone();
+            let completion_tokens = Arc::clone(&completion_tokens);
+            let chunk = move |text: String, finish_reason: Option<String>| {
+                let chunk = TextCompletionResponse {
+                    id: id.clone(),
+                    object: "text_completion".to_string(),
+                    created,
+                    model: model.clone(),
+                    choices: vec![TextCompletionChoice {
+                        text,
+                        index: index as u32,
+                        logprobs: None,
+                        finish_reason,
+                    }],
+                    usage: None,
+                };
+                format!("data: {}\n\n", serde_json::to_string(&chunk).unwrap())
+            };
+
+            let prefix = if echo_text.is_empty() { String::new() } else { chunk(echo_text, None) };
+            let choice_stream = stream::once(future::ready(prefix))
+                .chain(text_stream.map(move |event| match event {
+                    TextEvent::Delta(text) if text.is_empty() => String::new(),
+                    TextEvent::Delta(text) => chunk(text, None),
+                    TextEvent::Error(message) => {
+                        let error_chunk = json!({
+                            "error": {
+                                "message": message,
+                                "type": "stream_error",
+                                "code": "stream_error"
+                            }
+                        });
+                        format!("data: {}\n\n", serde_json::to_string(&error_chunk).unwrap())
+                    },
+                    TextEvent::Finished { finish_reason, completion_tokens: tokens, .. } => {
+                        completion_tokens.fetch_add(tokens, Ordering::Relaxed);
+                        chunk(String::new(), Some(finish_reason.to_string()))
+                    },
+                }))
+                .map(Ok::<_, std::convert::Infallible>);
+            choice_streams.push(choice_stream.boxed());
+        }
+
+        res.headers_mut().insert(header::CONTENT_TYPE, "text/event-stream".parse().unwrap());
+        res.headers_mut().insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+        res.headers_mut().insert(header::CONNECTION, "keep-alive".parse().unwrap());
+
+        let done = stream::once(async move {
+            let mut message = String::new();
+            if let Some(usage) = usage.stream_usage(completion_tokens.load(Ordering::Relaxed)) {
+                let usage_chunk = TextCompletionResponse {
+                    id,
+                    object: "text_completion".to_string(),
+                    created,
+                    model: display_model,
+                    choices: Vec::new(),
+                    usage: Some(usage),
+                };
+                message.push_str(&format!("data: {}\n\n", serde_json::to_string(&usage_chunk).unwrap()));
+            }
+            message.push_str("data: [DONE]\n\n");
+            Ok::<_, std::convert::Infallible>(message)
+        });
+        let chunks = rate_limit::hold_permit(res, stream::select_all(choice_streams).chain(done));
+        res.stream(chunks);
+    } else {
+        let results = future::join_all(event_streams.into_iter().map(|event_stream| {
+            let mut limiter = limits.limiter();
+            async move {
+                let content = collect_response(event_stream, &mut limiter).await?;
+                Ok::<_, poe_api_process::types::ErrorResponse>((content, limiter))
+            }
+        })).await;
+
+        let mut choices = Vec::with_capacity(choice_count);
+        let mut completion_tokens = 0;
+        for (index, (result, echo_text)) in results.into_iter().zip(echoes).enumerate() {
+            match result {
+                Ok((content, limiter)) => {
+                    completion_tokens += limiter.emitted_tokens();
+                    choices.push(TextCompletionChoice {
+                        text: echo_text + &content,
+                        index: index as u32,
+                        logprobs: None,
+                        finish_reason: Some(limiter.finish_reason().to_string()),
+                    });
+                },
+                Err(error) => {
+                    let (status, error_response) = convert_poe_error_to_openai(&error);
+                    res.status_code(status);
+                    res.render(Json(error_response));
+                    return;
+                }
+            }
+        }
+
+        res.render(Json(TextCompletionResponse {
+            id,
+            object: "text_completion".to_string(),
+            created,
+            model: display_model,
+            choices,
+            usage: usage.usage(completion_tokens),
+        }));
+    }
+
+    info!("✅ 請求處理完成 | 耗時: {}", format_duration(start_time.elapsed()));
+}
+
+fn render_error(res: &mut Response, status: StatusCode, message: String, code: &str, param: Option<&str>) {
+    res.status_code(status);
+    res.render(Json(OpenAIErrorResponse {
+        error: OpenAIError {
+            message,
+            r#type: if status.is_server_error() { "server_error" } else { "invalid_request_error" }.to_string(),
+            code: code.to_string(),
+            param: param.map(str::to_string),
+        }
+    }));
+}
@@ -1,7 +1,16 @@
 mod chat;
 mod models;
 mod admin;
+mod admin_models;
+mod anthropic;
+mod responses;
+mod ollama;
+mod completions;
 
 pub use chat::chat_completions;
 pub use models::get_models;
-pub use admin::admin_routes;
\ No newline at end of file
+pub use admin::admin_routes;
+pub use anthropic::anthropic_messages;
+pub use responses::{create_response, get_response};
+pub use completions::completions;
+pub use ollama::{ollama_chat, ollama_generate, ollama_tags, ollama_version};
\ No newline at end of file
@@ -1,10 +1,12 @@
 use salvo::prelude::*;
 use poe_api_process::get_model_list;
+use poe_api_process::types::ModelInfo;
+use poe_api_process::PoeError;
 use serde_json::json;
 use tracing::{error, info, debug};
 use std::time::Instant;
-use std::path::Path;
 
+use crate::routing::{key_access, resolve_model};
 use crate::types::*;
 
 #[handler]
@@ -13,131 +15,24 @@ pub async fn get_models(req: &mut Request, res: &mut Response) {
     info!("📋 收到獲取模型列表請求 | 路徑: {}", path);
     let start_time = Instant::now();
 
-    match get_model_list(Some("zh-Hant")).await {
-        Ok(model_list) => {
-            debug!("📊 原始模型數量: {}", model_list.data.len());
-
-            // 首先進行全部小寫轉換
-            let lowercase_models = model_list.data.into_iter()
-                .map(|mut model| {
-                    debug!("🏷️ 轉換小寫: {} -> {}", model.id, model.id.to_lowercase());
-                    model.id = model.id.to_lowercase();
-                    model
-                })
-                .collect::<Vec<_>>();
-
-            // 如果是 api/models 路徑，直接返回小寫轉換後的結果
-            if path == "/api/models" {
-                let response = json!({
-                    "object": "list",
-                    "data": lowercase_models
-                });
-
-                let duration = start_time.elapsed();
-                info!("✅ 成功獲取未過濾模型列表 | 模型數量: {} | 處理時間: {}",
-                    lowercase_models.len(),
-                    crate::utils::format_duration(duration)
-                );
-                
-                res.render(Json(response));
-                return;
-            }
-
-            // 讀取並解析 models.yaml 配置
-            let config = match Path::new("models.yaml").exists() {
-                true => {
-                    match std::fs::read_to_string("models.yaml") {
-                        Ok(contents) => {
-                            match serde_yaml::from_str::<Config>(&contents) {
-                                Ok(config) => config,
-                                Err(e) => {
-                                    error!("❌ 解析 models.yaml 失敗: {}", e);
-                                    Config {
-                                        enable: Some(false),
-                                        models: std::collections::HashMap::new(),
-                                    }
-                                }
-                            }
-                        },
-                        Err(e) => {
-                            error!("❌ 讀取 models.yaml 失敗: {}", e);
-                            Config {
-                                enable: Some(false),
-                                models: std::collections::HashMap::new(),
-                            }
-                        }
-                    }
-                },
-                false => {
-                    debug!("⚠️ models.yaml 不存在，預設為不啟用");
-                    Config {
-                        enable: Some(false),
-                        models: std::collections::HashMap::new(),
-                    }
-                }
-            };
-
-            let is_enabled = config.enable.unwrap_or(false);
-            debug!("🔍 設定檔啟用狀態: {}", is_enabled);
-
-            // 將 config.models 的鍵轉換為小寫以匹配轉換後的模型 ID
-            let lowercase_config_models: std::collections::HashMap<String, ModelConfig> = config.models
-                .into_iter()
-                .map(|(k, v)| (k.to_lowercase(), v))
-                .collect();
-
-            let processed_models = lowercase_models.into_iter()
-                .filter_map(|mut model| {
-                    let model_id = model.id.clone();
-                    let config = lowercase_config_models.get(&model_id);
-                    
-                    if !is_enabled {
-                        // 全域停用時，只處理 mapping
-                        if let Some(model_config) = config {
-                            if let Some(mapping) = &model_config.mapping {
-                                debug!("🔄 模型改名: {} -> {}", model_id, mapping);
-                                model.id = mapping.to_lowercase();
-                            }
-                            Some(model)
-                        } else {
-                            Some(model)
-                        }
-                    } else {
-                        // 全域啟用時，檢查個別模型設定
-                        match config {
-                            Some(model_config) => {
-                                // enable 預設為 true
-                                if model_config.enable.unwrap_or(true) {
-                                    if let Some(mapping) = &model_config.mapping {
-                                        debug!("🔄 模型改名並保留: {} -> {}", model_id, mapping);
-                                        model.id = mapping.to_lowercase();
-                                    }
-                                    Some(model)
-                                } else {
-                                    debug!("❌ 排除停用模型: {}", model_id);
-                                    None
-                                }
-                            },
-                            None => {
-                                debug!("✅ 無配置，保留模型: {}", model_id);
-                                Some(model)
-                            }
-                        }
-                    }
-                })
-                .collect::<Vec<_>>();
+    // 如果是 api/models 路徑，直接返回小寫轉換後的結果
+    let filtered = path != "/api/models";
+    let access = request_access(req);
 
+    match list_models(filtered).await.map(|models| restrict_models(access.as_ref(), models)) {
+        Ok(models) => {
             let response = json!({
                 "object": "list",
-                "data": processed_models
+                "data": models
             });
 
             let duration = start_time.elapsed();
-            info!("✅ 成功獲取處理後模型列表 | 模型數量: {} | 處理時間: {}",
-                processed_models.len(),
+            info!("✅ 成功獲取{}模型列表 | 模型數量: {} | 處理時間: {}",
+                if filtered { "處理後" } else { "未過濾" },
+                models.len(),
                 crate::utils::format_duration(duration)
             );
-            
+
             res.render(Json(response));
         },
         Err(e) => {
@@ -150,4 +45,159 @@ pub async fn get_models(req: &mut Request, res: &mut Response) {
             res.render(Json(json!({ "error": e.to_string() })));
         }
     }
-}
\ No newline at end of file
+}
+
+/// 取得 Poe 模型列表並轉為小寫；filtered 為 true 時依 models.yaml 過濾及改名
+pub(crate) async fn list_models(filtered: bool) -> Result<Vec<ModelInfo>, PoeError> {
+    let lowercase_models = upstream_models().await?;
+    if !filtered {
+        return Ok(lowercase_models);
+    }
+    Ok(public_models(&crate::config::current(), lowercase_models))
+}
+
+/// 取得 Poe 模型列表並轉為小寫
+pub(crate) async fn upstream_models() -> Result<Vec<ModelInfo>, PoeError> {
+    let model_list = get_model_list(Some("zh-Hant")).await?;
+    debug!("📊 原始模型數量: {}", model_list.data.len());
+
+    // 首先進行全部小寫轉換
+    let lowercase_models = model_list.data.into_iter()
+        .map(|mut model| {
+            debug!("🏷️ 轉換小寫: {} -> {}", model.id, model.id.to_lowercase());
+            model.id = model.id.to_lowercase();
+            model
+        })
+        .collect::<Vec<_>>();
+    Ok(lowercase_models)
+}
+
+/// 依 models.yaml 過濾及改名，得到 /v1/models 回傳的列表
+pub(crate) fn public_models(config: &Config, lowercase_models: Vec<ModelInfo>) -> Vec<ModelInfo> {
+    let is_enabled = config.enable.unwrap_or(false);
+    debug!("🔍 設定檔啟用狀態: {}", is_enabled);
+
+    // 將 config.models 的鍵轉換為小寫以匹配轉換後的模型 ID
+    let lowercase_config_models: std::collections::HashMap<String, &ModelConfig> = config.models
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), v))
+        .collect();
+
+    // 虛擬模型與別名需要引用上游 bot 的資訊
+    let upstream_models = if is_enabled { lowercase_models.clone() } else { Vec::new() };
+
+    let mut processed_models = lowercase_models.into_iter()
+        .filter_map(|mut model| {
+            let model_id = model.id.clone();
+            let config = lowercase_config_models.get(&model_id);
+            
+            if !is_enabled {
+                // 全域停用時，只處理 mapping
+                if let Some(model_config) = config {
+                    if let Some(mapping) = &model_config.mapping {
+                        debug!("🔄 模型改名: {} -> {}", model_id, mapping);
+                        model.id = mapping.to_lowercase();
+                    }
+                    Some(model)
+                } else {
+                    Some(model)
+                }
+            } else {
+                // 全域啟用時，檢查個別模型設定
+                match config {
+                    Some(model_config) => {
+                        // enable 預設為 true
+                        if model_config.enable.unwrap_or(true) {
+                            if let Some(mapping) = &model_config.mapping {
+                                debug!("🔄 模型改名並保留: {} -> {}", model_id, mapping);
+                                model.id = mapping.to_lowercase();
+                            }
+                            Some(model)
+                        } else {
+                            debug!("❌ 排除停用模型: {}", model_id);
+                            None
+                        }
+                    },
+                    None => {
+                        debug!("✅ 無配置，保留模型: {}", model_id);
+                        Some(model)
+                    }
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if is_enabled {
+        processed_models.extend(virtual_models(config, &upstream_models));
+    }
+
+    processed_models
+}
+
+/// 請求金鑰的模型存取設定；未帶金鑰或金鑰沒有限制時回傳 None
+pub(crate) fn request_access(req: &Request) -> Option<ModelAccess> {
+    let credential = crate::rate_limit::request_credential(req).ok()?;
+    key_access(&crate::config::current(), credential.key.as_ref())
+}
+
+/// 只保留金鑰可使用的模型，並加入其私有對應
+pub(crate) fn restrict_models(access: Option<&ModelAccess>, models: Vec<ModelInfo>) -> Vec<ModelInfo> {
+    let Some(access) = access else {
+        return models;
+    };
+    let config = crate::config::current();
+
+    let mut private = Vec::new();
+    for (name, target) in &access.mappings {
+        let bot = resolve_model(&config, target).bot.to_lowercase();
+        let upstream = models.iter().find(|model| {
+            model.id == target.to_lowercase() || resolve_model(&config, &model.id).bot.to_lowercase() == bot
+        });
+        match upstream {
+            Some(upstream) => {
+                let mut model = upstream.clone();
+                model.id = name.to_lowercase();
+                private.push(model);
+            },
+            None => debug!("⚠️ 找不到私有對應的上游模型，略過: {} -> {}", name, target),
+        }
+    }
+    private.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let mut models: Vec<ModelInfo> = models.into_iter()
+        .filter(|model| {
+            let resolved = resolve_model(&config, &model.id);
+            access.permits_route(&model.id, &resolved.config_key, &resolved.bot)
+        })
+        .collect();
+    debug!("🔐 依金鑰存取設定過濾模型 | 可用: {} | 私有對應: {}", models.len(), private.len());
+    models.extend(private);
+    models
+}
+
+/// 依 models.yaml 產生虛擬模型與別名項目，沿用上游 bot 的模型資訊
+fn virtual_models(config: &Config, upstream_models: &[ModelInfo]) -> Vec<ModelInfo> {
+    let mut entries = Vec::new();
+    for (config_key, model_config) in &config.models {
+        if !model_config.enable.unwrap_or(true) {
+            continue;
+        }
+        let bot = model_config.target.as_deref().unwrap_or(config_key).to_lowercase();
+        let Some(upstream) = upstream_models.iter().find(|model| model.id == bot) else {
+            debug!("⚠️ 找不到上游模型，略過: {} -> {}", config_key, bot);
+            continue;
+        };
+
+        // 非虛擬項目的主要名稱已在改名時加入
+        let names = crate::routing::public_names(config_key, model_config);
+        let skip = if model_config.target.is_some() { 0 } else { 1 };
+        for name in names.into_iter().skip(skip) {
+            debug!("➕ 加入虛擬模型: {} -> {}", name, bot);
+            let mut model = upstream.clone();
+            model.id = name.to_lowercase();
+            entries.push(model);
+        }
+    }
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    entries
+}
@@ -0,0 +1,339 @@
+use futures_util::stream::StreamExt;
+use salvo::http::header;
+use salvo::prelude::*;
+use serde_json::{json, Value};
+use std::time::Instant;
+use tracing::{debug, error, info};
+use chrono::Utc;
+
+use crate::handlers::chat::{
+    answered_model, collect_response, convert_poe_error_to_openai, start_text_stream, TextEvent,
+};
+use crate::handlers::models::{list_models, request_access, restrict_models};
+use crate::keys::Credential;
+use crate::limits::OutputLimits;
+use crate::poe_client::{PoeClientWrapper, apply_system_prompt, create_query_request, resolve_max_tokens, stream_with_fallbacks};
+use crate::routing::{key_access, route, Caller, ResolvedModel};
+use crate::rate_limit::{self, request_credential};
+use crate::shadow;
+use crate::tokenizer::Tokenizer;
+use crate::types::*;
+use crate::utils::format_duration;
+
+/// 回報給 Ollama 客戶端的版本號
+const OLLAMA_VERSION: &str = "0.5.0";
+
+/// /api/chat 與 /api/generate 的回應格式差異
+#[derive(Clone, Copy)]
+enum OllamaKind {
+    Chat,
+    Generate,
+}
+
+impl OllamaKind {
+    fn body(self, text: &str) -> Value {
+        match self {
+            OllamaKind::Chat => json!({ "message": { "role": "assistant", "content": text } }),
+            OllamaKind::Generate => json!({ "response": text }),
+        }
+    }
+}
+
+#[handler]
+pub async fn ollama_version(res: &mut Response) {
+    res.render(Json(json!({ "version": OLLAMA_VERSION })));
+}
+
+#[handler]
+pub async fn ollama_tags(req: &mut Request, res: &mut Response) {
+    info!("📋 收到 Ollama 模型列表請求");
+    let start_time = Instant::now();
+    let access = request_access(req);
+
+    match list_models(true).await.map(|models| restrict_models(access.as_ref(), models)) {
+        Ok(models) => {
+            let modified_at = Utc::now().to_rfc3339();
+            let models: Vec<Value> = models.into_iter()
+                .map(|model| json!({
+                    "name": model.id,
+                    "model": model.id,
+                    "modified_at": modified_at,
+                    "size": 0,
+                    "digest": "",
+                    "details": {
+                        "format": "",
+                        "family": model.owned_by,
+                        "families": null,
+                        "parameter_size": "",
+                        "quantization_level": ""
+                    }
+                }))
+                .collect();
+
+            info!("✅ 成功獲取 Ollama 模型列表 | 模型數量: {} | 處理時間: {}",
+                models.len(),
+                format_duration(start_time.elapsed())
+            );
+            res.render(Json(json!({ "models": models })));
+        },
+        Err(e) => {
+            error!("❌ 獲取模型列表失敗 | 錯誤: {}", e);
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+        }
+    }
+}
+
+#[handler]
+pub async fn ollama_chat(req: &mut Request, res: &mut Response) {
+    info!("📝 收到新的 Ollama chat 請求");
+    let Some(credential) = authenticate(req, res) else {
+        return;
+    };
+    let request = match parse_body::<OllamaChatRequest>(req, res).await {
+        Some(request) => request,
+        None => return,
+    };
+
+    let messages = request.messages.into_iter()
+        .map(|message| {
+            let images = message.images.unwrap_or_default();
+            if images.is_empty() {
+                return Message::text(&message.role, message.content);
+            }
+            let mut converted = Message::text(&message.role, "");
+            converted.content = content_with_images(message.content, &images);
+            converted
+        })
+        .collect();
+
+    let options = request.options.unwrap_or_default();
+    run_query(res, OllamaKind::Chat, &credential, &request.model, messages, options, request.stream.unwrap_or(true)).await;
+}
+
+#[handler]
+pub async fn ollama_generate(req: &mut Request, res: &mut Response) {
+    info!("📝 收到新的 Ollama generate 請求");
+    let Some(credential) = authenticate(req, res) else {
+        return;
+    };
+    let request = match parse_body::<OllamaGenerateRequest>(req, res).await {
+        Some(request) => request,
+        None => return,
+    };
+
+    let mut messages = Vec::with_capacity(2);
+    if let Some(system) = request.system.filter(|system| !system.is_empty()) {
+        messages.push(Message::text("system", system));
+    }
+    let images = request.images.unwrap_or_default();
+    let mut prompt = Message::text("user", "");
+    prompt.content = if images.is_empty() {
+        MessageContent::Text(request.prompt)
+    } else {
+        content_with_images(request.prompt, &images)
+    };
+    messages.push(prompt);
+
+    let options = request.options.unwrap_or_default();
+    run_query(res, OllamaKind::Generate, &credential, &request.model, messages, options, request.stream.unwrap_or(true)).await;
+}
+
+/// 向 Poe 發出請求，並依 Ollama 格式回傳 NDJSON 串流或單一 JSON
+async fn run_query(
+    res: &mut Response,
+    kind: OllamaKind,
+    credential: &Credential,
+    model: &str,
+    mut messages: Vec<Message>,
+    options: OllamaOptions,
+    stream: bool,
+) {
+    let start_time = Instant::now();
+    let config = crate::config::current();
+    let access = key_access(&config, credential.key.as_ref());
+    let caller = Caller { client_id: &credential.client_id, user: None, access: access.as_ref() };
+    let (resolved, chain) = match route(&config, model, &caller) {
+        Ok(routed) => routed,
+        Err(message) => {
+            error!("❌ 模型存取被拒: {}", message);
+            render_error(res, StatusCode::NOT_FOUND, &message);
+            return;
+        }
+    };
+    let ResolvedModel { display: display_model, bot: original_model, config_key } = resolved;
+    info!("🤖 使用模型: {} (原始: {})", display_model, original_model);
+
+    let client = PoeClientWrapper::new(&original_model, &credential.upload_key());
+    if let Err(e) = client.upload_data_images(&mut messages).await {
+        error!("❌ 處理圖片附件失敗: {}", e);
+        render_error(res, StatusCode::BAD_REQUEST, &e);
+        return;
+    }
+
+    let tokenizer = Tokenizer::for_model(&original_model, config.models.get(&config_key));
+    // num_predict 小於等於 0 時不限制輸出長度
+    let max_tokens = options.num_predict
+        .filter(|num_predict| *num_predict > 0)
+        .map(|num_predict| num_predict.min(u32::MAX as i64) as u32);
+    let limits = OutputLimits::new(
+        options.stop.unwrap_or_default(),
+        resolve_max_tokens(&config_key, max_tokens),
+        tokenizer,
+    );
+    apply_system_prompt(&config_key, &mut messages);
+    let prompt_tokens = tokenizer.count_messages(&messages);
+
+    let build_request = || create_query_request(&config_key, messages.clone(), options.temperature, limits.stop.clone());
+    let (client, event_stream) = match stream_with_fallbacks(&chain, &credential.poe_keys, &build_request).await {
+        Ok(result) => result,
+        Err(e) => {
+            error!("❌ 建立串流請求失敗: {}", e);
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+            return;
+        }
+    };
+    let display_model = answered_model(res, display_model, &original_model, &client);
+    let event_stream = shadow::mirror(&config, &config_key, &client, &build_request, event_stream);
+
+    let mut limiter = limits.limiter();
+    if stream {
+        debug!("🌊 開始處理 Ollama NDJSON 串流響應");
+        let text_stream = match start_text_stream(event_stream, limiter).await {
+            Ok(text_stream) => text_stream,
+            Err(error) => {
+                let (status, _) = convert_poe_error_to_openai(&error);
+                render_error(res, status, &error.text);
+                return;
+            }
+        };
+
+        res.headers_mut().insert(header::CONTENT_TYPE, "application/x-ndjson".parse().unwrap());
+        res.headers_mut().insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+
+        let lines = text_stream.filter_map(move |event| {
+            let line = match event {
+                TextEvent::Delta(text) if text.is_empty() => None,
+                TextEvent::Delta(text) => {
+                    let mut chunk = kind.body(&text);
+                    chunk["model"] = json!(display_model);
+                    chunk["created_at"] = json!(Utc::now().to_rfc3339());
+                    chunk["done"] = json!(false);
+                    Some(chunk)
+                },
+                TextEvent::Error(message) => Some(json!({ "error": message })),
+                TextEvent::Finished { finish_reason, completion_tokens, .. } => {
+                    Some(final_chunk(kind, &display_model, "", finish_reason, prompt_tokens, completion_tokens, start_time))
+                },
+            };
+            futures_util::future::ready(line.map(|line| {
+                Ok::<_, std::convert::Infallible>(format!("{}\n", serde_json::to_string(&line).unwrap()))
+            }))
+        });
+        let lines = rate_limit::hold_permit(res, lines);
+        res.stream(lines);
+    } else {
+        let content = match collect_response(event_stream, &mut limiter).await {
+            Ok(content) => content,
+            Err(error) => {
+                let (status, _) = convert_poe_error_to_openai(&error);
+                render_error(res, status, &error.text);
+                return;
+            }
+        };
+        res.render(Json(final_chunk(
+            kind,
+            &display_model,
+            &content,
+            limiter.finish_reason(),
+            prompt_tokens,
+            limiter.emitted_tokens(),
+            start_time,
+        )));
+    }
+
+    info!("✅ 請求處理完成 | 耗時: {}", format_duration(start_time.elapsed()));
+}
+
+/// 最後一個片段（或非串流的完整回應），附帶結束原因與統計資訊
+fn final_chunk(
+    kind: OllamaKind,
+    model: &str,
+    text: &str,
+    finish_reason: &str,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    start_time: Instant,
+) -> Value {
+    let mut chunk = kind.body(text);
+    chunk["model"] = json!(model);
+    chunk["created_at"] = json!(Utc::now().to_rfc3339());
+    chunk["done"] = json!(true);
+    chunk["done_reason"] = json!(if finish_reason == "length" { "length" } else { "stop" });
+    chunk["total_duration"] = json!(start_time.elapsed().as_nanos() as u64);
+    chunk["prompt_eval_count"] = json!(prompt_tokens);
+    chunk["eval_count"] = json!(completion_tokens);
+    chunk
+}
+
+/// Ollama 客戶端通常不帶授權標頭，未提供時改用設定中的 ollama.access_key
+fn authenticate(req: &Request, res: &mut Response) -> Option<Credential> {
+    match request_credential(req) {
+        Ok(credential) => Some(credential),
+        Err(message) => {
+            error!("❌ 驗證失敗: {}", message);
+            render_error(res, StatusCode::UNAUTHORIZED, &message);
+            None
+        }
+    }
+}
+
+async fn parse_body<T: serde::de::DeserializeOwned>(req: &mut Request, res: &mut Response) -> Option<T> {
+    let max_size = crate::settings::get().server.max_request_size;
+
+    match req.payload_with_max_size(max_size).await {
+        Ok(bytes) => match serde_json::from_slice::<T>(&bytes) {
+            Ok(request) => Some(request),
+            Err(e) => {
+                error!("❌ JSON 解析失敗: {}", e);
+                render_error(res, StatusCode::BAD_REQUEST, &format!("JSON 解析失敗: {}", e));
+                None
+            }
+        },
+        Err(e) => {
+            error!("❌ 請求大小超過限制或讀取失敗: {}", e);
+            render_error(res, StatusCode::PAYLOAD_TOO_LARGE, &format!("請求大小超過限制 ({} bytes) 或讀取失敗: {}", max_size, e));
+            None
+        }
+    }
+}
+
+/// 以 Ollama 的錯誤格式回應
+fn render_error(res: &mut Response, status: StatusCode, message: &str) {
+    res.status_code(status);
+    res.render(Json(json!({ "error": message })));
+}
+
+/// 將文字與 base64 圖片組合為多段內容，圖片轉為 data URI 以沿用附件上傳流程
+fn content_with_images(text: String, images: &[String]) -> MessageContent {
+    let mut parts = vec![ContentPart::Text { text }];
+    parts.extend(images.iter().map(|image| ContentPart::ImageUrl {
+        image_url: ImageUrl {
+            url: format!("data:{};base64,{}", sniff_image_type(image), image),
+            detail: None,
+        },
+    }));
+    MessageContent::Parts(parts)
+}
+
+/// 依 base64 開頭判斷圖片格式
+fn sniff_image_type(data: &str) -> &'static str {
+    if data.starts_with("/9j/") {
+        "image/jpeg"
+    } else if data.starts_with("R0lGOD") {
+        "image/gif"
+    } else if data.starts_with("UklGR") {
+        "image/webp"
+    } else {
+        "image/png"
+    }
+}
@@ -0,0 +1,635 @@
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use nanoid::nanoid;
+use salvo::http::header;
+use salvo::prelude::*;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+use tracing::{debug, error, info, warn};
+use chrono::Utc;
+
+use crate::handlers::chat::{
+    answered_model, authenticate, collect_response, convert_poe_error_to_openai, start_text_stream, TextEvent,
+};
+use crate::limits::OutputLimits;
+use crate::poe_client::{PoeClientWrapper, apply_system_prompt, create_query_request, resolve_max_tokens, stream_with_fallbacks};
+use crate::routing::{key_access, route, Caller, ResolvedModel};
+use crate::rate_limit;
+use crate::shadow;
+use crate::tokenizer::Tokenizer;
+use crate::tools::{self, ToolCallDetector, ToolChoice, ToolConfig};
+use crate::types::*;
+use crate::utils::format_duration;
+
+/// 已儲存的回應，供 previous_response_id 串接對話及 GET /v1/responses/{id} 查詢
+struct StoredResponse {
+    client_id: String,
+    /// 不含 instructions 的完整對話（包含本次輸出）
+    messages: Vec<Message>,
+    response: Value,
+}
+
+#[derive(Default)]
+struct ResponseStore {
+    entries: HashMap<String, StoredResponse>,
+    order: VecDeque<String>,
+}
+
+fn response_store() -> &'static Mutex<ResponseStore> {
+    static STORE: OnceLock<Mutex<ResponseStore>> = OnceLock::new();
+    STORE.get_or_init(|| Mutex::new(ResponseStore::default()))
+}
+
+/// 記憶體中最多保留的回應數量，超過時移除最舊的
+fn max_stored_responses() -> usize {
+    crate::settings::get().limits.response_store_max_entries
+}
+
+fn store_response(id: String, stored: StoredResponse) {
+    let mut store = response_store().lock().unwrap();
+    let limit = max_stored_responses();
+    while store.order.len() >= limit {
+        match store.order.pop_front() {
+            Some(oldest) => {
+                store.entries.remove(&oldest);
+            },
+            None => break,
+        }
+    }
+    debug!("💾 儲存回應 | ID: {} | 訊息數量: {}", id, stored.messages.len());
+    store.order.push_back(id.clone());
+    store.entries.insert(id, stored);
+}
+
+#[handler]
+pub async fn create_response(req: &mut Request, res: &mut Response) {
+    let start_time = Instant::now();
+    info!("📝 收到新的 Responses 請求");
+
+    let max_size = crate::settings::get().server.max_request_size;
+
+    let config = crate::config::current();
+
+    let Some(credential) = authenticate(req, res) else {
+        return;
+    };
+
+    let request = match req.payload_with_max_size(max_size).await {
+        Ok(bytes) => match serde_json::from_slice::<ResponsesRequest>(&bytes) {
+            Ok(request) => {
+                debug!("📊 請求解析成功 | 模型: {} | previous_response_id: {:?} | 是否串流: {:?}",
+                    request.model,
+                    request.previous_response_id,
+                    request.stream
+                );
+                request
+            },
+            Err(e) => {
+                error!("❌ JSON 解析失敗: {}", e);
+                render_error(res, StatusCode::BAD_REQUEST, format!("JSON 解析失敗: {}", e), "parse_error", None);
+                return;
+            }
+        },
+        Err(e) => {
+            error!("❌ 請求大小超過限制或讀取失敗: {}", e);
+            render_error(res, StatusCode::PAYLOAD_TOO_LARGE,
+                format!("請求大小超過限制 ({} bytes) 或讀取失敗: {}", max_size, e), "payload_too_large", None);
+            return;
+        }
+    };
+
+    // 串接先前回應的對話歷史
+    let mut conversation = match &request.previous_response_id {
+        Some(previous_id) => {
+            let store = response_store().lock().unwrap();
+            match store.entries.get(previous_id).filter(|stored| stored.client_id == credential.client_id) {
+                Some(stored) => stored.messages.clone(),
+                None => {
+                    warn!("⚠️ 找不到先前的回應: {}", previous_id);
+                    render_error(res, StatusCode::NOT_FOUND,
+                        format!("找不到回應 '{}'", previous_id), "previous_response_not_found", Some("previous_response_id"));
+                    return;
+                }
+            }
+        },
+        None => Vec::new(),
+    };
+    conversation.extend(convert_input(request.input));
+
+    let access = key_access(&config, credential.key.as_ref());
+    let caller = Caller { client_id: &credential.client_id, user: request.user.as_deref(), access: access.as_ref() };
+    let (resolved, chain) = match route(&config, &request.model, &caller) {
+        Ok(routed) => routed,
+        Err(message) => {
+            error!("❌ 模型存取被拒: {}", message);
+            render_error(res, StatusCode::NOT_FOUND, message, "model_not_found", Some("model"));
+            return;
+        }
+    };
+    let ResolvedModel { display: display_model, bot: original_model, config_key } = resolved;
+    info!("🤖 使用模型: {} (原始: {})", display_model, original_model);
+
+    let client = PoeClientWrapper::new(&original_model, &credential.upload_key());
+    let tool_config = tool_config_from_request(&request.tools, request.tool_choice.as_ref(), request.parallel_tool_calls);
+    let tokenizer = Tokenizer::for_model(&original_model, config.models.get(&config_key));
+    let limits = OutputLimits::new(
+        Vec::new(),
+        resolve_max_tokens(&config_key, request.max_output_tokens),
+        tokenizer,
+    );
+
+    if let Err(e) = client.upload_data_images(&mut conversation).await {
+        error!("❌ 處理圖片附件失敗: {}", e);
+        render_error(res, StatusCode::BAD_REQUEST, e, "invalid_image_url", Some("input"));
+        return;
+    }
+
+    // instructions 只套用於本次請求，不會沿用到後續串接的回應
+    let mut messages = Vec::with_capacity(conversation.len() + 1);
+    if let Some(instructions) = &request.instructions {
+        messages.push(Message::text("system", instructions.clone()));
+    }
+    messages.extend(conversation.iter().cloned());
+    let mut messages = tools::prepare_messages(messages, tool_config.as_ref());
+    apply_system_prompt(&config_key, &mut messages);
+    let input_tokens = tokenizer.count_messages(&messages);
+    debug!("🧮 估算 input token 數量: {} | tokenizer: {:?}", input_tokens, tokenizer);
+
+    let build_request = || create_query_request(&config_key, messages.clone(), request.temperature, Vec::new());
+    let (client, event_stream) = match stream_with_fallbacks(&chain, &credential.poe_keys, &build_request).await {
+        Ok(result) => result,
+        Err(e) => {
+            error!("❌ 建立串流請求失敗: {}", e);
+            render_error(res, StatusCode::BAD_GATEWAY, e, "upstream_error", None);
+            return;
+        }
+    };
+    let display_model = answered_model(res, display_model, &original_model, &client);
+    let event_stream = shadow::mirror(&config, &config_key, &client, &build_request, event_stream);
+
+    let meta = ResponseMeta {
+        id: format!("resp_{}", nanoid!(24)),
+        created_at: Utc::now().timestamp(),
+        model: display_model,
+        instructions: request.instructions,
+        previous_response_id: request.previous_response_id,
+        input_tokens,
+    };
+    // store 預設為 true
+    let store = request.store.unwrap_or(true).then(|| (credential.client_id, conversation));
+    let mut limiter = limits.limiter();
+
+    if request.stream.unwrap_or(false) {
+        debug!("🌊 開始處理 Responses 串流響應 | ID: {}", meta.id);
+        let text_stream = match start_text_stream(event_stream, limiter).await {
+            Ok(text_stream) => text_stream,
+            Err(error) => {
+                let (status, error_response) = convert_poe_error_to_openai(&error);
+                res.status_code(status);
+                res.render(Json(error_response));
+                return;
+            }
+        };
+
+        res.headers_mut().insert(header::CONTENT_TYPE, "text/event-stream".parse().unwrap());
+        res.headers_mut().insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+        res.headers_mut().insert(header::CONNECTION, "keep-alive".parse().unwrap());
+
+        let mut state = ResponseStreamState {
+            sequence_number: 0,
+            message_id: format!("msg_{}", nanoid!(24)),
+            text: String::new(),
+            text_open: false,
+            output: Vec::new(),
+            tool_detector: tool_config.as_ref().map(|_| ToolCallDetector::default()),
+            meta,
+            store,
+        };
+        let in_progress = state.meta.response_object("in_progress", Vec::new(), None, None);
+        let mut prefix = state.event("response.created", json!({ "response": in_progress }));
+        prefix.push_str(&state.event("response.in_progress", json!({ "response": in_progress })));
+
+        let events = text_stream.map(move |event| {
+            let message = match event {
+                TextEvent::Delta(text) => {
+                    let text = match state.tool_detector.as_mut() {
+                        Some(detector) => detector.push(&text),
+                        None => text,
+                    };
+                    state.text_delta(&text)
+                },
+                TextEvent::Error(message) => {
+                    state.event("error", json!({
+                        "code": "stream_error",
+                        "message": message,
+                        "param": null
+                    }))
+                },
+                TextEvent::Finished { finish_reason, completion_tokens, .. } => {
+                    state.finish(finish_reason, completion_tokens)
+                },
+            };
+            Ok::<_, std::convert::Infallible>(message)
+        });
+
+        let events = rate_limit::hold_permit(res, stream::once(future::ready(Ok::<_, std::convert::Infallible>(prefix))).chain(events));
+        res.stream(events);
+    } else {
+        let content = match collect_response(event_stream, &mut limiter).await {
+            Ok(content) => content,
+            Err(error) => {
+                let (status, error_response) = convert_poe_error_to_openai(&error);
+                res.status_code(status);
+                res.render(Json(error_response));
+                return;
+            }
+        };
+
+        let (text, tool_calls) = match tool_config.as_ref().and_then(|_| tools::parse_tool_calls(&content)) {
+            Some((text, tool_calls)) => (text, tool_calls),
+            None => (content, Vec::new()),
+        };
+
+        let mut output = Vec::new();
+        if !text.is_empty() {
+            output.push(message_item(&format!("msg_{}", nanoid!(24)), &text, "completed"));
+        }
+        output.extend(tool_calls.iter().map(|call| function_call_item(call, "completed")));
+
+        let (status, incomplete_reason) = response_status(limiter.finish_reason());
+        let response = meta.response_object(status, output, Some(limiter.emitted_tokens()), incomplete_reason);
+        if let Some((client_id, mut conversation)) = store {
+            conversation.push(assistant_message(text, tool_calls));
+            store_response(meta.id.clone(), StoredResponse {
+                client_id,
+                messages: conversation,
+                response: response.clone(),
+            });
+        }
+        res.render(Json(response));
+    }
+
+    let duration = start_time.elapsed();
+    info!("✅ 請求處理完成 | 耗時: {}", format_duration(duration));
+}
+
+#[handler]
+pub async fn get_response(req: &mut Request, res: &mut Response) {
+    let Some(credential) = authenticate(req, res) else {
+        return;
+    };
+    let id = req.param::<String>("id").unwrap_or_default();
+    debug!("🔍 查詢回應: {}", id);
+
+    let store = response_store().lock().unwrap();
+    match store.entries.get(&id).filter(|stored| stored.client_id == credential.client_id) {
+        Some(stored) => res.render(Json(stored.response.clone())),
+        None => {
+            render_error(res, StatusCode::NOT_FOUND, format!("找不到回應 '{}'", id), "not_found", None);
+        },
+    }
+}
+
+fn render_error(res: &mut Response, status: StatusCode, message: String, code: &str, param: Option<&str>) {
+    res.status_code(status);
+    res.render(Json(OpenAIErrorResponse {
+        error: OpenAIError {
+            message,
+            r#type: if status.is_server_error() { "server_error" } else { "invalid_request_error" }.to_string(),
+            code: code.to_string(),
+            param: param.map(str::to_string),
+        }
+    }));
+}
+
+/// 將 Responses API 的工具設定轉為共用的工具設定，僅支援 function 類型的工具
+fn tool_config_from_request(
+    tools: &Option<Vec<ResponsesTool>>,
+    tool_choice: Option<&Value>,
+    parallel_tool_calls: Option<bool>,
+) -> Option<ToolConfig> {
+    let tools: Vec<ChatTool> = tools.iter()
+        .flatten()
+        .filter_map(|tool| {
+            if tool.r#type != "function" {
+                debug!("⏭️ 忽略不支援的工具類型: {}", tool.r#type);
+                return None;
+            }
+            Some(ChatTool {
+                r#type: "function".to_string(),
+                function: FunctionDefinition {
+                    name: tool.name.clone()?,
+                    description: tool.description.clone(),
+                    parameters: tool.parameters.clone(),
+                },
+            })
+        })
+        .collect();
+    if tools.is_empty() {
+        return None;
+    }
+
+    let choice = match tool_choice {
+        None => ToolChoice::Auto,
+        Some(Value::String(choice)) => match choice.as_str() {
+            "none" => {
+                debug!("🔧 tool_choice 為 none，不注入工具定義");
+                return None;
+            },
+            "required" => ToolChoice::Required,
+            _ => ToolChoice::Auto,
+        },
+        Some(value) => match value.get("name").and_then(Value::as_str) {
+            Some(name) => ToolChoice::Function(name.to_string()),
+            None => ToolChoice::Auto,
+        },
+    };
+
+    debug!("🔧 啟用工具呼叫 | 工具數量: {}", tools.len());
+
+    Some(ToolConfig {
+        tools,
+        choice,
+        parallel: parallel_tool_calls.unwrap_or(true),
+        legacy: false,
+    })
+}
+
+/// 將 input 轉為 OpenAI 格式的訊息，以沿用既有的處理流程
+fn convert_input(input: ResponsesInput) -> Vec<Message> {
+    let items = match input {
+        ResponsesInput::Text(text) => return vec![Message::text("user", text)],
+        ResponsesInput::Items(items) => items,
+    };
+
+    let mut messages: Vec<Message> = Vec::with_capacity(items.len());
+    for item in items {
+        let item = match item {
+            ResponsesInputItem::Typed(item) => item,
+            ResponsesInputItem::Message { role, content } => ResponsesTypedItem::Message { role, content },
+        };
+        match item {
+            ResponsesTypedItem::Message { role, content } => {
+                // developer 角色等同於 system
+                let role = if role == "developer" { "system".to_string() } else { role };
+                let mut message = Message::text(&role, "");
+                message.content = convert_content(content);
+                messages.push(message);
+            },
+            ResponsesTypedItem::FunctionCall { call_id, name, arguments } => {
+                let call = ToolCall {
+                    id: call_id,
+                    r#type: "function".to_string(),
+                    function: FunctionCall { name, arguments },
+                };
+                // 連續的 function_call 合併到同一則 assistant 訊息
+                match messages.last_mut() {
+                    Some(last) if last.role == "assistant" => {
+                        last.tool_calls.get_or_insert_with(Vec::new).push(call);
+                    },
+                    _ => {
+                        let mut message = Message::text("assistant", "");
+                        message.tool_calls = Some(vec![call]);
+                        messages.push(message);
+                    },
+                }
+            },
+            ResponsesTypedItem::FunctionCallOutput { call_id, output } => {
+                let mut message = Message::text("tool", output);
+                message.tool_call_id = Some(call_id);
+                messages.push(message);
+            },
+            ResponsesTypedItem::Unsupported => {
+                debug!("⏭️ 忽略不支援的 input 項目");
+            },
+        }
+    }
+    messages
+}
+
+fn convert_content(content: ResponsesContent) -> MessageContent {
+    match content {
+        ResponsesContent::Text(text) => MessageContent::Text(text),
+        ResponsesContent::Parts(parts) => MessageContent::Parts(parts.into_iter()
+            .map(|part| match part {
+                ResponsesContentPart::InputText { text } | ResponsesContentPart::OutputText { text } => ContentPart::Text { text },
+                ResponsesContentPart::InputImage { image_url: Some(url) } => {
+                    ContentPart::ImageUrl { image_url: ImageUrl { url, detail: None } }
+                },
+                _ => ContentPart::Unsupported,
+            })
+            .collect()),
+    }
+}
+
+/// 本次輸出轉為歷史中的 assistant 訊息
+fn assistant_message(text: String, tool_calls: Vec<ToolCall>) -> Message {
+    let mut message = Message::text("assistant", text);
+    if !tool_calls.is_empty() {
+        message.tool_calls = Some(tool_calls);
+    }
+    message
+}
+
+fn message_item(id: &str, text: &str, status: &str) -> Value {
+    json!({
+        "type": "message",
+        "id": id,
+        "status": status,
+        "role": "assistant",
+        "content": [output_text_part(text)]
+    })
+}
+
+fn output_text_part(text: &str) -> Value {
+    json!({ "type": "output_text", "text": text, "annotations": [] })
+}
+
+fn function_call_item(call: &ToolCall, status: &str) -> Value {
+    json!({
+        "type": "function_call",
+        "id": format!("fc_{}", nanoid!(24)),
+        "call_id": call.id,
+        "name": call.function.name,
+        "arguments": call.function.arguments,
+        "status": status
+    })
+}
+
+/// 將 finish_reason 轉為回應狀態及未完成原因
+fn response_status(finish_reason: &str) -> (&'static str, Option<&'static str>) {
+    match finish_reason {
+        "length" => ("incomplete", Some("max_output_tokens")),
+        _ => ("completed", None),
+    }
+}
+
+/// 回應物件中與輸出無關的欄位
+struct ResponseMeta {
+    id: String,
+    created_at: i64,
+    model: String,
+    instructions: Option<String>,
+    previous_response_id: Option<String>,
+    input_tokens: usize,
+}
+
+impl ResponseMeta {
+    fn response_object(&self, status: &str, output: Vec<Value>, output_tokens: Option<usize>, incomplete_reason: Option<&str>) -> Value {
+        let output_text: String = output.iter()
+            .filter(|item| item["type"] == "message")
+            .filter_map(|item| item["content"][0]["text"].as_str())
+            .collect();
+        json!({
+            "id": self.id,
+            "object": "response",
+            "created_at": self.created_at,
+            "status": status,
+            "model": self.model,
+            "instructions": self.instructions,
+            "previous_response_id": self.previous_response_id,
+            "incomplete_details": incomplete_reason.map(|reason| json!({ "reason": reason })),
+            "output": output,
+            "output_text": output_text,
+            "usage": output_tokens.map(|output_tokens| json!({
+                "input_tokens": self.input_tokens,
+                "output_tokens": output_tokens,
+                "total_tokens": self.input_tokens + output_tokens
+            }))
+        })
+    }
+}
+
+/// 串流模式下的輸出狀態，訊息項目在收到第一段文字時才開啟
+struct ResponseStreamState {
+    sequence_number: u64,
+    message_id: String,
+    text: String,
+    text_open: bool,
+    output: Vec<Value>,
+    tool_detector: Option<ToolCallDetector>,
+    meta: ResponseMeta,
+    store: Option<(String, Vec<Message>)>,
+}
+
+impl ResponseStreamState {
+    fn event(&mut self, event_type: &str, mut data: Value) -> String {
+        data["type"] = json!(event_type);
+        data["sequence_number"] = json!(self.sequence_number);
+        self.sequence_number += 1;
+        format!("event: {}\ndata: {}\n\n", event_type, serde_json::to_string(&data).unwrap())
+    }
+
+    fn text_delta(&mut self, text: &str) -> String {
+        if text.is_empty() {
+            return String::new();
+        }
+        let mut message = String::new();
+        let output_index = self.output.len();
+        if !self.text_open {
+            self.text_open = true;
+            let item = json!({
+                "type": "message",
+                "id": self.message_id,
+                "status": "in_progress",
+                "role": "assistant",
+                "content": []
+            });
+            message.push_str(&self.event("response.output_item.added", json!({
+                "output_index": output_index,
+                "item": item
+            })));
+            let item_id = self.message_id.clone();
+            message.push_str(&self.event("response.content_part.added", json!({
+                "item_id": item_id,
+                "output_index": output_index,
+                "content_index": 0,
+                "part": output_text_part("")
+            })));
+        }
+        self.text.push_str(text);
+        let item_id = self.message_id.clone();
+        message.push_str(&self.event("response.output_text.delta", json!({
+            "item_id": item_id,
+            "output_index": output_index,
+            "content_index": 0,
+            "delta": text
+        })));
+        message
+    }
+
+    fn finish(&mut self, finish_reason: &str, completion_tokens: usize) -> String {
+        let (leftover, tool_calls) = match self.tool_detector.take() {
+            Some(detector) => detector.finish(),
+            None => (String::new(), Vec::new()),
+        };
+        let mut message = self.text_delta(&leftover);
+
+        if self.text_open {
+            let output_index = self.output.len();
+            let item_id = self.message_id.clone();
+            let text = self.text.clone();
+            message.push_str(&self.event("response.output_text.done", json!({
+                "item_id": item_id,
+                "output_index": output_index,
+                "content_index": 0,
+                "text": text
+            })));
+            message.push_str(&self.event("response.content_part.done", json!({
+                "item_id": item_id,
+                "output_index": output_index,
+                "content_index": 0,
+                "part": output_text_part(&text)
+            })));
+            let item = message_item(&item_id, &text, "completed");
+            message.push_str(&self.event("response.output_item.done", json!({
+                "output_index": output_index,
+                "item": item
+            })));
+            self.output.push(item);
+        }
+
+        for call in &tool_calls {
+            let output_index = self.output.len();
+            let item = function_call_item(call, "completed");
+            let mut added = item.clone();
+            added["arguments"] = json!("");
+            added["status"] = json!("in_progress");
+            message.push_str(&self.event("response.output_item.added", json!({
+                "output_index": output_index,
+                "item": added
+            })));
+            message.push_str(&self.event("response.function_call_arguments.delta", json!({
+                "item_id": item["id"],
+                "output_index": output_index,
+                "delta": call.function.arguments
+            })));
+            message.push_str(&self.event("response.function_call_arguments.done", json!({
+                "item_id": item["id"],
+                "output_index": output_index,
+                "arguments": call.function.arguments
+            })));
+            message.push_str(&self.event("response.output_item.done", json!({
+                "output_index": output_index,
+                "item": item
+            })));
+            self.output.push(item);
+        }
+
+        let (status, incomplete_reason) = response_status(finish_reason);
+        let response = self.meta.response_object(status, std::mem::take(&mut self.output), Some(completion_tokens), incomplete_reason);
+        if let Some((client_id, mut conversation)) = self.store.take() {
+            conversation.push(assistant_message(std::mem::take(&mut self.text), tool_calls));
+            store_response(self.meta.id.clone(), StoredResponse {
+                client_id,
+                messages: conversation,
+                response: response.clone(),
+            });
+        }
+        let event_type = if status == "completed" { "response.completed" } else { "response.incomplete" };
+        message.push_str(&self.event(event_type, json!({ "response": response })));
+        message
+    }
+}
@@ -0,0 +1,307 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+use tracing::{debug, error, info, warn};
+
+use crate::types::Config;
+
+/// 修改配置的管理員
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Actor {
+    pub(crate) user: String,
+    pub(crate) ip: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Action {
+    /// 第一次修改前的原始配置
+    Initial,
+    Save,
+    Rollback,
+    /// 直接修改 models.yaml 後自動重新載入
+    Reload,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ChangeOp {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// 單一欄位的變更，path 為 JSON Pointer
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Change {
+    op: ChangeOp,
+    path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    old: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    new: Option<Value>,
+}
+
+/// 一次配置變更的快照
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Version {
+    pub(crate) version: u64,
+    created_at: i64,
+    action: Action,
+    /// 直接修改檔案或原始配置時為空
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    actor: Option<Actor>,
+    /// 回復時的來源版本
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<u64>,
+    etag: String,
+    /// 與前一版本的差異
+    changes: Vec<Change>,
+    pub(crate) config: Value,
+}
+
+/// 歷史紀錄列表中的版本資訊，不含完整配置
+#[derive(Serialize)]
+pub(crate) struct VersionSummary {
+    version: u64,
+    created_at: i64,
+    action: Action,
+    user: Option<String>,
+    ip: Option<String>,
+    source: Option<u64>,
+    etag: String,
+    changes: usize,
+}
+
+impl From<&Version> for VersionSummary {
+    fn from(version: &Version) -> Self {
+        Self {
+            version: version.version,
+            created_at: version.created_at,
+            action: version.action,
+            user: version.actor.as_ref().map(|actor| actor.user.clone()),
+            ip: version.actor.as_ref().map(|actor| actor.ip.clone()),
+            source: version.source,
+            etag: version.etag.clone(),
+            changes: version.changes.len(),
+        }
+    }
+}
+
+fn store() -> &'static RwLock<Vec<Version>> {
+    static STORE: OnceLock<RwLock<Vec<Version>>> = OnceLock::new();
+    STORE.get_or_init(|| RwLock::new(Vec::new()))
+}
+
+fn history_dir() -> &'static Path {
+    &crate::settings::get().models.history_dir
+}
+
+fn version_path(version: u64) -> PathBuf {
+    history_dir().join(format!("{:06}.json", version))
+}
+
+/// 啟動時載入歷史版本
+pub(crate) fn init() {
+    let dir = history_dir();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => {
+            debug!("📜 配置歷史目錄不存在，尚無任何版本");
+            return;
+        }
+    };
+    let mut versions: Vec<Version> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .filter_map(|path| {
+            let loaded = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| serde_json::from_str::<Version>(&contents).map_err(|e| e.to_string()));
+            loaded.map_err(|e| error!("❌ 載入配置版本 {} 失敗: {}", path.display(), e)).ok()
+        })
+        .collect();
+    versions.sort_by_key(|version| version.version);
+    info!("📜 已載入配置歷史 | 版本數量: {}", versions.len());
+    *store().write().unwrap() = versions;
+}
+
+/// 配置的 JSON 表示，物件的鍵已排序，可直接比較
+pub(crate) fn snapshot(config: &Config) -> Value {
+    serde_json::to_value(config).unwrap_or_default()
+}
+
+/// 以配置內容計算的 ETag
+pub(crate) fn etag(snapshot: &Value) -> String {
+    let bytes = serde_json::to_vec(snapshot).unwrap_or_default();
+    format!("\"{}\"", &hex::encode(Sha256::digest(&bytes))[..16])
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// 比較兩份配置，物件逐欄比較，其他值（包含陣列）整個比較
+pub(crate) fn diff(old: &Value, new: &Value) -> Vec<Change> {
+    let mut changes = Vec::new();
+    diff_into("", Some(old), Some(new), &mut changes);
+    changes
+}
+
+fn diff_into(path: &str, old: Option<&Value>, new: Option<&Value>, changes: &mut Vec<Change>) {
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                let path = format!("{}/{}", path, escape_pointer(key));
+                diff_into(&path, old.get(key), new.get(key), changes);
+            }
+        },
+        (old, new) if old == new => {},
+        (old, new) => changes.push(Change {
+            op: match (old, new) {
+                (None, _) => ChangeOp::Added,
+                (_, None) => ChangeOp::Removed,
+                _ => ChangeOp::Changed,
+            },
+            path: path.to_string(),
+            old: old.cloned(),
+            new: new.cloned(),
+        }),
+    }
+}
+
+fn persist(version: &Version) -> Result<(), String> {
+    std::fs::create_dir_all(history_dir()).map_err(|e| e.to_string())?;
+    let json = serde_json::to_string_pretty(version).map_err(|e| e.to_string())?;
+    crate::utils::write_atomic(&version_path(version.version), json).map_err(|e| e.to_string())
+}
+
+fn push(versions: &mut Vec<Version>, version: Version) {
+    if let Err(e) = persist(&version) {
+        error!("❌ 寫入配置版本 {} 失敗: {}", version.version, e);
+    }
+    versions.push(version);
+
+    let limit = crate::settings::get().models.history_limit;
+    if versions.len() > limit {
+        for removed in versions.drain(..versions.len() - limit) {
+            if let Err(e) = std::fs::remove_file(version_path(removed.version)) {
+                warn!("⚠️ 刪除舊配置版本 {} 失敗: {}", removed.version, e);
+            }
+        }
+    }
+}
+
+/// 記錄一次配置變更；第一次變更時一併保存原始配置，方便回復
+///
+/// 直接修改檔案造成的重新載入與最新版本比較，尚無任何版本時不記錄
+pub(crate) fn record(previous: &Value, current: &Value, action: Action, actor: Option<Actor>, source: Option<u64>) {
+    let mut versions = store().write().unwrap();
+    let base = match versions.last() {
+        Some(latest) => latest.config.clone(),
+        None if action == Action::Reload => return,
+        None => {
+            let initial = Version {
+                version: 1,
+                created_at: Utc::now().timestamp(),
+                action: Action::Initial,
+                actor: None,
+                source: None,
+                etag: etag(previous),
+                changes: Vec::new(),
+                config: previous.clone(),
+            };
+            push(&mut versions, initial);
+            previous.clone()
+        },
+    };
+    let changes = diff(&base, current);
+    if changes.is_empty() && action == Action::Reload {
+        return;
+    }
+
+    let version = Version {
+        version: versions.last().map_or(1, |latest| latest.version + 1),
+        created_at: Utc::now().timestamp(),
+        action,
+        actor,
+        source,
+        etag: etag(current),
+        changes,
+        config: current.clone(),
+    };
+    match &version.actor {
+        Some(actor) => info!("📜 配置版本 {} | {:?} | 管理員: {} | IP: {} | 變更: {} 項",
+            version.version, action, actor.user, actor.ip, version.changes.len()),
+        None => info!("📜 配置版本 {} | {:?} | 變更: {} 項", version.version, action, version.changes.len()),
+    }
+    push(&mut versions, version);
+}
+
+/// 所有版本，最新的在前
+pub(crate) fn list() -> Vec<VersionSummary> {
+    store().read().unwrap().iter().rev().map(VersionSummary::from).collect()
+}
+
+pub(crate) fn get(version: u64) -> Option<Version> {
+    store().read().unwrap().iter().find(|entry| entry.version == version).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn summary(changes: &[Change]) -> Vec<(ChangeOp, &str)> {
+        changes.iter().map(|change| (change.op, change.path.as_str())).collect()
+    }
+
+    #[test]
+    fn diff_reports_nested_fields_as_json_pointers() {
+        let old = json!({ "models": { "a": { "mapping": "x", "points": 1 }, "b": {} }, "enable": true });
+        let new = json!({ "models": { "a": { "mapping": "y", "points": 1 }, "c": {} }, "enable": true });
+        let changes = diff(&old, &new);
+        assert_eq!(summary(&changes), vec![
+            (ChangeOp::Changed, "/models/a/mapping"),
+            (ChangeOp::Removed, "/models/b"),
+            (ChangeOp::Added, "/models/c"),
+        ]);
+        assert_eq!(changes[0].old, Some(json!("x")));
+        assert_eq!(changes[0].new, Some(json!("y")));
+        assert!(changes[1].new.is_none());
+        assert!(changes[2].old.is_none());
+    }
+
+    #[test]
+    fn diff_escapes_pointer_segments() {
+        let old = json!({ "models": {} });
+        let new = json!({ "models": { "org/model~v2": {} } });
+        assert_eq!(summary(&diff(&old, &new)), vec![(ChangeOp::Added, "/models/org~1model~0v2")]);
+    }
+
+    #[test]
+    fn diff_compares_arrays_as_a_whole() {
+        let old = json!({ "fallbacks": ["a", "b"] });
+        let new = json!({ "fallbacks": ["b", "a"] });
+        assert_eq!(summary(&diff(&old, &new)), vec![(ChangeOp::Changed, "/fallbacks")]);
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn etag_depends_only_on_content() {
+        let config = json!({ "enable": true, "models": { "a": { "points": 1 } } });
+        let reordered = json!({ "models": { "a": { "points": 1 } }, "enable": true });
+        assert_eq!(etag(&config), etag(&reordered));
+        assert_ne!(etag(&config), etag(&json!({ "enable": false, "models": { "a": { "points": 1 } } })));
+
+        let value = etag(&config);
+        assert!(value.starts_with('"') && value.ends_with('"'));
+        assert_eq!(value.len(), 18);
+    }
+}
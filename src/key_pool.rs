@@ -0,0 +1,298 @@
+use chrono::Utc;
+use regex::Regex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tracing::{debug, info, warn};
+
+use crate::settings::PoolStrategy;
+
+/// 被標記為失效的金鑰重新嘗試前等待的冷卻倍數
+const DEAD_COOLDOWN_FACTOR: i64 = 10;
+
+/// 單一 Poe API Key 的使用狀況
+#[derive(Default, Clone)]
+struct KeyHealth {
+    requests: u64,
+    errors: u64,
+    rate_limits: u64,
+    last_used: Option<i64>,
+    last_rate_limited: Option<i64>,
+    last_error: Option<String>,
+    cooldown_until: Option<i64>,
+    /// 驗證失敗的時間，之後自動略過此金鑰
+    dead_since: Option<i64>,
+}
+
+/// 上游回應錯誤的種類，決定如何處置金鑰
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum KeyFailure {
+    RateLimited,
+    Unauthorized,
+    /// 與金鑰無關的錯誤，只記錄次數
+    Other,
+}
+
+/// 錯誤訊息中的 HTTP 狀態碼，只認 `status 429`、`HTTP 401`、`status code: 403`
+/// 或 reqwest 的 `(429 Too Many Requests)` 等形式，不會把 token 數或請求 ID 中的數字當成狀態碼
+fn status_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(
+        r"(?i)\b(?:status(?:\s+code)?|http(?:/[\d.]+)?)\s*[:=]?\s*(\d{3})\b|\((\d{3})\s+[a-z]"
+    ).expect("狀態碼的正規表示式有效"))
+}
+
+/// 不帶狀態碼時判斷錯誤種類的片語，皆以完整單字比對
+fn phrase_patterns() -> &'static [(Regex, KeyFailure)] {
+    static PATTERNS: OnceLock<Vec<(Regex, KeyFailure)>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        [
+            (r"(?i)\brate[\s_-]?limit(?:ed|s)?\b|\btoo many requests\b", KeyFailure::RateLimited),
+            (
+                r"(?i)\bunauthori[sz]ed\b|\bforbidden\b|\binvalid (?:api key|token|access key)\b|\bauthentication (?:failed|error|required)\b|\binsufficient (?:compute points|points|credits|balance|funds)\b",
+                KeyFailure::Unauthorized,
+            ),
+        ]
+        .into_iter()
+        .map(|(pattern, failure)| (Regex::new(pattern).expect("錯誤片語的正規表示式有效"), failure))
+        .collect()
+    })
+}
+
+impl KeyFailure {
+    /// 依 Poe 回傳的錯誤訊息分類：先看訊息中的 HTTP 狀態碼，沒有時再比對錯誤片語
+    pub(crate) fn classify(message: &str) -> Self {
+        let status = status_pattern().captures_iter(message)
+            .filter_map(|captures| captures.get(1).or_else(|| captures.get(2)))
+            .find_map(|code| code.as_str().parse::<u16>().ok());
+        match status {
+            Some(429) => return KeyFailure::RateLimited,
+            Some(401..=403) => return KeyFailure::Unauthorized,
+            _ => {},
+        }
+        phrase_patterns().iter()
+            .find(|(pattern, _)| pattern.is_match(message))
+            .map_or(KeyFailure::Other, |(_, failure)| *failure)
+    }
+
+    /// 是否應換一把金鑰重試
+    pub(crate) fn is_key_related(self) -> bool {
+        self != KeyFailure::Other
+    }
+}
+
+/// 管理介面顯示的金鑰狀態
+#[derive(Serialize)]
+pub(crate) struct KeyStatus {
+    id: String,
+    key: String,
+    status: &'static str,
+    requests: u64,
+    errors: u64,
+    rate_limits: u64,
+    last_used: Option<i64>,
+    last_error: Option<String>,
+    cooldown_until: Option<i64>,
+}
+
+fn health() -> &'static Mutex<HashMap<String, KeyHealth>> {
+    static HEALTH: OnceLock<Mutex<HashMap<String, KeyHealth>>> = OnceLock::new();
+    HEALTH.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// 以雜湊開頭識別金鑰，避免在日誌及管理介面中出現完整金鑰
+pub(crate) fn fingerprint(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))[..12].to_string()
+}
+
+fn cooldown_secs() -> i64 {
+    crate::settings::get().pool.cooldown as i64
+}
+
+impl KeyHealth {
+    /// 目前是否可以使用；失效的金鑰在較長的冷卻後會再嘗試一次
+    fn is_available(&self, now: i64) -> bool {
+        if let Some(dead_since) = self.dead_since {
+            return now >= dead_since + cooldown_secs() * DEAD_COOLDOWN_FACTOR;
+        }
+        self.cooldown_until.is_none_or(|until| now >= until)
+    }
+
+    fn status(&self, now: i64) -> &'static str {
+        if self.dead_since.is_some() {
+            "dead"
+        } else if !self.is_available(now) {
+            "cooldown"
+        } else {
+            "active"
+        }
+    }
+}
+
+/// 依設定的策略從候選金鑰中選出一把；全部不可用時選最早恢復的那把
+pub(crate) fn select(candidates: &[String], exclude: &[String]) -> Option<String> {
+    static ROUND_ROBIN: AtomicUsize = AtomicUsize::new(0);
+
+    let candidates: Vec<&String> = candidates.iter().filter(|key| !exclude.contains(key)).collect();
+    if candidates.len() <= 1 {
+        return candidates.first().map(|key| key.to_string());
+    }
+
+    let now = Utc::now().timestamp();
+    let health = health().lock().unwrap();
+    let state = |key: &String| health.get(&fingerprint(key)).cloned().unwrap_or_default();
+    let available: Vec<&String> = candidates.iter()
+        .copied()
+        .filter(|key| state(key).is_available(now))
+        .collect();
+
+    let selected = if available.is_empty() {
+        warn!("⚠️ 所有 Poe API Key 都在冷卻中，改用最早恢復的金鑰");
+        candidates.iter()
+            .copied()
+            .min_by_key(|key| {
+                let state = state(key);
+                state.cooldown_until.unwrap_or(0).max(state.dead_since.map_or(0, |since| since + cooldown_secs() * DEAD_COOLDOWN_FACTOR))
+            })
+    } else {
+        match crate::settings::get().pool.strategy {
+            PoolStrategy::RoundRobin => {
+                let index = ROUND_ROBIN.fetch_add(1, Ordering::Relaxed);
+                Some(available[index % available.len()])
+            },
+            PoolStrategy::LeastRateLimited => available.iter()
+                .copied()
+                .min_by_key(|key| (state(key).last_rate_limited.unwrap_or(i64::MIN), state(key).last_used.unwrap_or(i64::MIN))),
+            PoolStrategy::LeastUsed => available.iter()
+                .copied()
+                .min_by_key(|key| state(key).requests),
+        }
+    };
+    selected.map(|key| {
+        debug!("🔑 選用 Poe API Key: {}", fingerprint(key));
+        key.to_string()
+    })
+}
+
+/// 記錄一次成功的請求
+pub(crate) fn report_success(key: &str) {
+    let mut health = health().lock().unwrap();
+    let state = health.entry(fingerprint(key)).or_default();
+    state.requests += 1;
+    state.last_used = Some(Utc::now().timestamp());
+    state.cooldown_until = None;
+    if state.dead_since.take().is_some() {
+        info!("✅ Poe API Key 已恢復: {}", fingerprint(key));
+    }
+}
+
+/// 記錄失敗的請求；被限流或驗證失敗時暫停使用此金鑰
+pub(crate) fn report_failure(key: &str, failure: KeyFailure, message: &str) {
+    let now = Utc::now().timestamp();
+    let id = fingerprint(key);
+    let mut health = health().lock().unwrap();
+    let state = health.entry(id.clone()).or_default();
+    state.requests += 1;
+    state.errors += 1;
+    state.last_used = Some(now);
+    state.last_error = Some(message.to_string());
+    match failure {
+        KeyFailure::RateLimited => {
+            state.rate_limits += 1;
+            state.last_rate_limited = Some(now);
+            state.cooldown_until = Some(now + cooldown_secs());
+            warn!("⏳ Poe API Key 被限流，暫停使用 {} 秒: {}", cooldown_secs(), id);
+        },
+        KeyFailure::Unauthorized => {
+            state.dead_since.get_or_insert(now);
+            warn!("💀 Poe API Key 驗證失敗，暫時略過: {}", id);
+        },
+        KeyFailure::Other => {},
+    }
+}
+
+/// 清除金鑰的冷卻及失效狀態
+pub(crate) fn reset(id: &str) -> bool {
+    let mut health = health().lock().unwrap();
+    match health.get_mut(id) {
+        Some(state) => {
+            state.cooldown_until = None;
+            state.dead_since = None;
+            info!("🔄 已重設 Poe API Key 狀態: {}", id);
+            true
+        },
+        None => false,
+    }
+}
+
+/// 所有已設定的 Poe API Key 的狀態
+pub(crate) fn status() -> Vec<KeyStatus> {
+    let mut keys = crate::settings::get().pool.keys.clone();
+    keys.extend(crate::keys::upstream_keys());
+    keys.sort();
+    keys.dedup();
+
+    let now = Utc::now().timestamp();
+    let health = health().lock().unwrap();
+    keys.iter()
+        .map(|key| {
+            let id = fingerprint(key);
+            let state = health.get(&id).cloned().unwrap_or_default();
+            KeyStatus {
+                status: state.status(now),
+                key: crate::keys::mask(key),
+                id,
+                requests: state.requests,
+                errors: state.errors,
+                rate_limits: state.rate_limits,
+                last_used: state.last_used,
+                last_error: state.last_error,
+                cooldown_until: state.cooldown_until.filter(|until| *until > now),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_codes_in_http_errors() {
+        assert_eq!(KeyFailure::classify("HTTP status client error (429 Too Many Requests) for url (https://api.poe.com/bot/x)"), KeyFailure::RateLimited);
+        assert_eq!(KeyFailure::classify("HTTP status client error (401 Unauthorized) for url (https://api.poe.com/bot/x)"), KeyFailure::Unauthorized);
+        assert_eq!(KeyFailure::classify("request failed with status 403"), KeyFailure::Unauthorized);
+        assert_eq!(KeyFailure::classify("status code: 402"), KeyFailure::Unauthorized);
+        assert_eq!(KeyFailure::classify("HTTP/1.1 429"), KeyFailure::RateLimited);
+        assert_eq!(KeyFailure::classify("HTTP status server error (500 Internal Server Error)"), KeyFailure::Other);
+    }
+
+    #[test]
+    fn numbers_that_are_not_status_codes() {
+        assert_eq!(KeyFailure::classify("context length exceeded: 4290 tokens"), KeyFailure::Other);
+        assert_eq!(KeyFailure::classify("prompt has 429 tokens, maximum is 400"), KeyFailure::Other);
+        assert_eq!(KeyFailure::classify("request id 1401-403a failed"), KeyFailure::Other);
+        assert_eq!(KeyFailure::classify("file too large: 40133 bytes"), KeyFailure::Other);
+    }
+
+    #[test]
+    fn phrases_match_whole_words() {
+        assert_eq!(KeyFailure::classify("Rate limit exceeded, slow down"), KeyFailure::RateLimited);
+        assert_eq!(KeyFailure::classify("You have been rate-limited"), KeyFailure::RateLimited);
+        assert_eq!(KeyFailure::classify("Too Many Requests"), KeyFailure::RateLimited);
+        assert_eq!(KeyFailure::classify("Invalid API key provided"), KeyFailure::Unauthorized);
+        assert_eq!(KeyFailure::classify("Unauthorized"), KeyFailure::Unauthorized);
+        assert_eq!(KeyFailure::classify("Insufficient points to run this bot"), KeyFailure::Unauthorized);
+    }
+
+    #[test]
+    fn unrelated_upstream_text() {
+        assert_eq!(KeyFailure::classify("insufficient context to answer the question"), KeyFailure::Other);
+        assert_eq!(KeyFailure::classify("the model discussed authentication in its reply"), KeyFailure::Other);
+        assert_eq!(KeyFailure::classify("Bot does not exist"), KeyFailure::Other);
+        assert_eq!(KeyFailure::classify("Internal server error"), KeyFailure::Other);
+        assert!(!KeyFailure::classify("Internal server error").is_key_related());
+    }
+}
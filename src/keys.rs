@@ -0,0 +1,433 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::quota::Quota;
+use crate::settings::Settings;
+use crate::types::{Config, ModelAccess, RateLimits};
+
+/// 代理金鑰的前綴，用來與 Poe API Key 區分
+pub(crate) const KEY_PREFIX: &str = "sk-p2o-";
+
+/// 由管理介面發放給客戶端的代理金鑰
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct ProxyKey {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    /// 金鑰的 SHA-256，原始金鑰只在建立時回傳一次
+    pub(crate) hash: String,
+    /// 顯示用的金鑰開頭
+    pub(crate) prefix: String,
+    /// 轉發請求時使用的 Poe API Key，為空時使用金鑰池
+    #[serde(default)]
+    pub(crate) upstream_keys: Vec<String>,
+    pub(crate) created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) revoked_at: Option<i64>,
+    /// 覆蓋 settings 中 rate_limit 的預設上限
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) rate_limit: Option<RateLimits>,
+    /// 每日或每月的用量上限
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) quotas: Vec<Quota>,
+    /// 套用 models.yaml 中 groups 的模型存取設定
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) group: Option<String>,
+    /// 此金鑰本身的模型存取設定，疊加在群組設定之上
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) access: Option<ModelAccess>,
+}
+
+/// 管理介面顯示的金鑰資訊，不含雜湊，Poe API Key 只顯示結尾
+#[derive(Serialize)]
+pub(crate) struct KeyView {
+    id: String,
+    name: String,
+    prefix: String,
+    upstream_keys: Vec<String>,
+    created_at: i64,
+    expires_at: Option<i64>,
+    revoked_at: Option<i64>,
+    rate_limit: Option<RateLimits>,
+    quotas: Vec<Quota>,
+    group: Option<String>,
+    access: Option<ModelAccess>,
+}
+
+impl From<&ProxyKey> for KeyView {
+    fn from(key: &ProxyKey) -> Self {
+        Self {
+            id: key.id.clone(),
+            name: key.name.clone(),
+            prefix: key.prefix.clone(),
+            upstream_keys: key.upstream_keys.iter().map(|upstream| mask(upstream)).collect(),
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+            revoked_at: key.revoked_at,
+            rate_limit: key.rate_limit,
+            quotas: key.quotas.clone(),
+            group: key.group.clone(),
+            access: key.access.clone(),
+        }
+    }
+}
+
+/// 驗證通過的呼叫者
+pub(crate) struct Credential {
+    /// 識別客戶端的 ID：代理金鑰為其 id，直通模式為金鑰雜湊的開頭
+    pub(crate) client_id: String,
+    /// 轉發請求時可使用的 Poe API Key，由金鑰池從中選用
+    pub(crate) poe_keys: Vec<String>,
+    /// 使用代理金鑰時的金鑰資料
+    pub(crate) key: Option<ProxyKey>,
+}
+
+impl Credential {
+    /// 不經 fallback 的請求（例如上傳檔案）使用的 Poe API Key
+    pub(crate) fn upload_key(&self) -> String {
+        crate::key_pool::select(&self.poe_keys, &[]).unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum AuthError {
+    Unknown,
+    Revoked,
+    Expired,
+    PassThroughDisabled,
+    NoUpstream,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            AuthError::Unknown => "無效的 API 金鑰",
+            AuthError::Revoked => "API 金鑰已被撤銷",
+            AuthError::Expired => "API 金鑰已過期",
+            AuthError::PassThroughDisabled => "未啟用 Poe API Key 直通模式，請使用代理金鑰",
+            AuthError::NoUpstream => "此金鑰沒有可用的 Poe API Key",
+        };
+        f.write_str(message)
+    }
+}
+
+fn store() -> &'static RwLock<Vec<ProxyKey>> {
+    static STORE: OnceLock<RwLock<Vec<ProxyKey>>> = OnceLock::new();
+    STORE.get_or_init(|| RwLock::new(Vec::new()))
+}
+
+fn keys_path() -> &'static Path {
+    &crate::settings::get().keys.file
+}
+
+/// 啟動時載入代理金鑰；檔案無法讀取或解析時先備份再回傳錯誤，
+/// 避免之後建立金鑰時以空的清單覆蓋檔案而遺失所有金鑰
+pub(crate) fn init() -> Result<(), String> {
+    let path = keys_path();
+    if !path.exists() {
+        debug!("🔑 代理金鑰檔案不存在，尚未建立任何金鑰");
+    } else {
+        let keys = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| serde_json::from_str::<Vec<ProxyKey>>(&contents).map_err(|e| e.to_string()));
+        match keys {
+            Ok(keys) => {
+                info!("🔑 已載入代理金鑰 | 數量: {}", keys.len());
+                *store().write().unwrap() = keys;
+            },
+            Err(e) => return Err(crate::utils::load_failure("代理金鑰", path, &e)),
+        }
+    }
+    if crate::settings::get().keys.pass_through {
+        warn!("⚠️ 已啟用 Poe API Key 直通模式，客戶端可直接使用 Poe API Key");
+    }
+    Ok(())
+}
+
+/// 檔案中有上游 Poe API Key 的明文，只允許擁有者讀寫
+fn persist(keys: &[ProxyKey]) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(keys).map_err(|e| e.to_string())?;
+    crate::utils::write_atomic_private(keys_path(), json).map_err(|e| format!("寫入代理金鑰檔案失敗: {}", e))
+}
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// 只保留結尾四個字元
+pub(crate) fn mask(key: &str) -> String {
+    let tail: String = key.chars().rev().take(4).collect::<Vec<_>>().into_iter().rev().collect();
+    format!("****{}", tail)
+}
+
+/// 驗證客戶端送來的金鑰，並決定轉發時使用的 Poe API Key
+pub(crate) fn authenticate(token: &str) -> Result<Credential, AuthError> {
+    authenticate_in(crate::settings::get(), &store().read().unwrap(), token)
+}
+
+fn authenticate_in(settings: &Settings, keys: &[ProxyKey], token: &str) -> Result<Credential, AuthError> {
+    if !token.starts_with(KEY_PREFIX) {
+        if !settings.keys.pass_through {
+            return Err(AuthError::PassThroughDisabled);
+        }
+        return Ok(Credential {
+            client_id: hash_key(token)[..16].to_string(),
+            poe_keys: vec![token.to_string()],
+            key: None,
+        });
+    }
+
+    let hash = hash_key(token);
+    let key = keys.iter().find(|key| key.hash == hash).ok_or(AuthError::Unknown)?;
+    if key.revoked_at.is_some() {
+        return Err(AuthError::Revoked);
+    }
+    if key.expires_at.is_some_and(|expires_at| expires_at <= Utc::now().timestamp()) {
+        return Err(AuthError::Expired);
+    }
+    let poe_keys = if key.upstream_keys.is_empty() {
+        settings.pool.keys.clone()
+    } else {
+        key.upstream_keys.clone()
+    };
+    if poe_keys.is_empty() {
+        return Err(AuthError::NoUpstream);
+    }
+    debug!("🔑 代理金鑰驗證成功 | 名稱: {} | ID: {}", key.name, key.id);
+    Ok(Credential {
+        client_id: key.id.clone(),
+        poe_keys,
+        key: Some(key.clone()),
+    })
+}
+
+/// 列出所有代理金鑰
+pub(crate) fn list() -> Vec<KeyView> {
+    store().read().unwrap().iter().map(KeyView::from).collect()
+}
+
+/// 所有代理金鑰的完整資料
+pub(crate) fn snapshot() -> Vec<ProxyKey> {
+    store().read().unwrap().clone()
+}
+
+/// 所有代理金鑰指定的 Poe API Key
+pub(crate) fn upstream_keys() -> Vec<String> {
+    store().read().unwrap().iter()
+        .flat_map(|key| key.upstream_keys.iter().cloned())
+        .collect()
+}
+
+/// 建立代理金鑰的參數
+#[derive(Deserialize)]
+pub(crate) struct NewKey {
+    pub(crate) name: String,
+    /// 未指定時使用金鑰池
+    #[serde(default)]
+    pub(crate) upstream_keys: Vec<String>,
+    /// Unix 時間戳記（秒），未指定時永不過期
+    pub(crate) expires_at: Option<i64>,
+    pub(crate) rate_limit: Option<RateLimits>,
+    #[serde(default)]
+    pub(crate) quotas: Vec<Quota>,
+    /// models.yaml 中 groups 的名稱
+    pub(crate) group: Option<String>,
+    pub(crate) access: Option<ModelAccess>,
+}
+
+/// 檢查建立金鑰的參數，回傳去除空白後的 upstream_keys
+fn check_new_key(settings: &Settings, config: &Config, new_key: &NewKey) -> Result<Vec<String>, String> {
+    let upstream_keys: Vec<String> = new_key.upstream_keys.iter()
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect();
+    if upstream_keys.is_empty() && settings.pool.keys.is_empty() {
+        return Err("金鑰池為空，至少需要指定一個 Poe API Key".to_string());
+    }
+    if upstream_keys.iter().any(|key| key.starts_with(KEY_PREFIX)) {
+        return Err("upstream_keys 必須是 Poe API Key，不可使用代理金鑰".to_string());
+    }
+    if let Some(field) = new_key.rate_limit.and_then(|limits| limits.zero_field()) {
+        return Err(format!("rate_limit.{} 必須大於 0", field));
+    }
+    if new_key.quotas.iter().any(|quota| quota.limit == 0) {
+        return Err("quotas 的 limit 必須大於 0".to_string());
+    }
+    if let Some(group) = &new_key.group {
+        if !config.groups.contains_key(group) {
+            return Err(format!("models.yaml 中沒有名為 `{}` 的群組", group));
+        }
+    }
+    Ok(upstream_keys)
+}
+
+/// 建立代理金鑰，回傳完整金鑰（只會出現這一次）與金鑰資訊
+pub(crate) fn create(new_key: NewKey) -> Result<(String, KeyView), String> {
+    let upstream_keys = check_new_key(crate::settings::get(), &crate::config::current(), &new_key)?;
+
+    let secret = format!("{}{}", KEY_PREFIX, nanoid::nanoid!(40));
+    let key = ProxyKey {
+        id: nanoid::nanoid!(12),
+        name: new_key.name,
+        hash: hash_key(&secret),
+        prefix: secret.chars().take(KEY_PREFIX.len() + 4).collect(),
+        upstream_keys,
+        created_at: Utc::now().timestamp(),
+        expires_at: new_key.expires_at,
+        revoked_at: None,
+        rate_limit: new_key.rate_limit.filter(|limits| !limits.is_unlimited()),
+        quotas: new_key.quotas,
+        group: new_key.group,
+        access: new_key.access,
+    };
+    let view = KeyView::from(&key);
+
+    // 先寫入檔案再更新記憶體，寫入失敗時金鑰不會生效
+    let mut keys = store().write().unwrap();
+    let mut updated = keys.clone();
+    updated.push(key);
+    persist(&updated)?;
+    *keys = updated;
+    info!("🔑 已建立代理金鑰 | 名稱: {} | ID: {}", view.name, view.id);
+    Ok((secret, view))
+}
+
+/// 撤銷代理金鑰，保留紀錄
+pub(crate) fn revoke(id: &str) -> Result<bool, String> {
+    let mut keys = store().write().unwrap();
+    let mut updated = keys.clone();
+    let Some(key) = updated.iter_mut().find(|key| key.id == id) else {
+        return Ok(false);
+    };
+    key.revoked_at.get_or_insert(Utc::now().timestamp());
+    persist(&updated)?;
+    *keys = updated;
+    info!("🚫 已撤銷代理金鑰 | ID: {}", id);
+    Ok(true)
+}
+
+/// 刪除代理金鑰
+pub(crate) fn delete(id: &str) -> Result<bool, String> {
+    let mut keys = store().write().unwrap();
+    let remaining: Vec<ProxyKey> = keys.iter().filter(|key| key.id != id).cloned().collect();
+    if remaining.len() == keys.len() {
+        return Ok(false);
+    }
+    persist(&remaining)?;
+    *keys = remaining;
+    drop(keys);
+    info!("🗑️ 已刪除代理金鑰 | ID: {}", id);
+    crate::quota::forget(id);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SECRET: &str = "sk-p2o-test-secret";
+
+    fn settings(pass_through: bool, pool: &[&str]) -> Settings {
+        let mut settings = Settings::default();
+        settings.keys.pass_through = pass_through;
+        settings.pool.keys = pool.iter().map(|key| key.to_string()).collect();
+        settings
+    }
+
+    fn key(extra: serde_json::Value) -> ProxyKey {
+        let mut value = json!({
+            "id": "key-1",
+            "name": "test",
+            "hash": hash_key(SECRET),
+            "prefix": "sk-p2o-test",
+            "created_at": 0,
+        });
+        value.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(value).expect("測試金鑰無效")
+    }
+
+    fn new_key(value: serde_json::Value) -> NewKey {
+        serde_json::from_value(value).expect("測試參數無效")
+    }
+
+    #[test]
+    fn authenticate_finds_the_key_by_hash() {
+        let keys = [key(json!({ "upstream_keys": ["poe-own"] }))];
+        let credential = authenticate_in(&settings(false, &["poe-pool"]), &keys, SECRET).unwrap();
+        assert_eq!(credential.client_id, "key-1");
+        assert_eq!(credential.poe_keys, vec!["poe-own".to_string()]);
+        assert!(credential.key.is_some());
+
+        let error = authenticate_in(&settings(false, &["poe-pool"]), &keys, "sk-p2o-other").err().unwrap();
+        assert!(matches!(error, AuthError::Unknown));
+    }
+
+    #[test]
+    fn authenticate_uses_the_pool_without_upstream_keys() {
+        let keys = [key(json!({}))];
+        let credential = authenticate_in(&settings(false, &["poe-pool"]), &keys, SECRET).unwrap();
+        assert_eq!(credential.poe_keys, vec!["poe-pool".to_string()]);
+
+        let error = authenticate_in(&settings(false, &[]), &keys, SECRET).err().unwrap();
+        assert!(matches!(error, AuthError::NoUpstream));
+    }
+
+    #[test]
+    fn authenticate_rejects_revoked_and_expired_keys() {
+        let settings = settings(false, &["poe-pool"]);
+        let revoked = [key(json!({ "revoked_at": 1 }))];
+        assert!(matches!(authenticate_in(&settings, &revoked, SECRET), Err(AuthError::Revoked)));
+
+        let expired = [key(json!({ "expires_at": Utc::now().timestamp() }))];
+        assert!(matches!(authenticate_in(&settings, &expired, SECRET), Err(AuthError::Expired)));
+
+        let valid = [key(json!({ "expires_at": Utc::now().timestamp() + 3600 }))];
+        assert!(authenticate_in(&settings, &valid, SECRET).is_ok());
+    }
+
+    #[test]
+    fn authenticate_passes_poe_keys_through_only_when_enabled() {
+        let error = authenticate_in(&settings(false, &[]), &[], "poe-key").err().unwrap();
+        assert!(matches!(error, AuthError::PassThroughDisabled));
+
+        let credential = authenticate_in(&settings(true, &[]), &[], "poe-key").unwrap();
+        assert_eq!(credential.poe_keys, vec!["poe-key".to_string()]);
+        assert_eq!(credential.client_id, hash_key("poe-key")[..16]);
+        assert!(credential.key.is_none());
+    }
+
+    #[test]
+    fn create_requires_an_upstream_key_or_a_pool() {
+        let config = Config::default();
+        let request = new_key(json!({ "name": "a", "upstream_keys": ["  ", ""] }));
+        assert!(check_new_key(&settings(false, &[]), &config, &request).is_err());
+        assert_eq!(check_new_key(&settings(false, &["poe-pool"]), &config, &request).unwrap(), Vec::<String>::new());
+
+        let request = new_key(json!({ "name": "a", "upstream_keys": [" poe-own "] }));
+        assert_eq!(check_new_key(&settings(false, &[]), &config, &request).unwrap(), vec!["poe-own".to_string()]);
+    }
+
+    #[test]
+    fn create_rejects_invalid_settings() {
+        let settings = settings(false, &["poe-pool"]);
+        let mut config = Config::default();
+        config.groups.insert("basic".to_string(), ModelAccess::default());
+        let invalid = [
+            json!({ "name": "a", "upstream_keys": ["sk-p2o-nested"] }),
+            json!({ "name": "a", "rate_limit": { "requests_per_minute": 0 } }),
+            json!({ "name": "a", "quotas": [{ "metric": "requests", "period": "day", "limit": 0 }] }),
+            json!({ "name": "a", "group": "missing" }),
+        ];
+        for value in invalid {
+            assert!(check_new_key(&settings, &config, &new_key(value.clone())).is_err(), "{}", value);
+        }
+        assert!(check_new_key(&settings, &config, &new_key(json!({ "name": "a", "group": "basic" }))).is_ok());
+    }
+}
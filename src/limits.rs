@@ -0,0 +1,247 @@
+use tracing::debug;
+
+use crate::tokenizer::Tokenizer;
+use crate::utils::partial_suffix_len;
+
+/// 請求中的 stop 與 max_tokens 設定
+#[derive(Clone)]
+pub struct OutputLimits {
+    pub stop: Vec<String>,
+    pub max_tokens: Option<usize>,
+    pub tokenizer: Tokenizer,
+}
+
+impl OutputLimits {
+    pub fn new(stop: Vec<String>, max_tokens: Option<u32>, tokenizer: Tokenizer) -> Self {
+        Self {
+            stop: stop.into_iter().filter(|sequence| !sequence.is_empty()).collect(),
+            max_tokens: max_tokens.map(|max_tokens| max_tokens as usize),
+            tokenizer,
+        }
+    }
+
+    pub fn limiter(&self) -> OutputLimiter {
+        OutputLimiter {
+            limits: self.clone(),
+            pending: String::new(),
+            emitted_tokens: 0,
+            finish_reason: None,
+            stop_sequence: None,
+        }
+    }
+}
+
+/// 在伺服器端套用 stop 序列與 token 上限，可跨串流片段偵測 stop 序列，並統計輸出的 token 數量
+pub struct OutputLimiter {
+    limits: OutputLimits,
+    pending: String,
+    emitted_tokens: usize,
+    finish_reason: Option<&'static str>,
+    stop_sequence: Option<String>,
+}
+
+impl OutputLimiter {
+    /// 推入新的文字片段，回傳可以立即輸出的部分
+    pub fn push(&mut self, text: &str) -> String {
+        if self.finish_reason.is_some() {
+            return String::new();
+        }
+        self.pending.push_str(text);
+
+        let stop_match = self.limits.stop.iter()
+            .filter_map(|sequence| self.pending.find(sequence.as_str()).map(|position| (position, sequence)))
+            .min_by_key(|(position, _)| *position);
+        if let Some((position, sequence)) = stop_match {
+            debug!("✋ 偵測到 stop 序列 | 位置: {}", position);
+            self.finish_reason = Some("stop");
+            self.stop_sequence = Some(sequence.clone());
+            let output = self.pending[..position].to_string();
+            self.pending.clear();
+            return self.apply_budget(output);
+        }
+
+        // 保留可能是 stop 序列開頭的結尾部分，等下一個片段再判斷
+        let keep = self.limits.stop.iter()
+            .map(|sequence| partial_suffix_len(&self.pending, sequence))
+            .max()
+            .unwrap_or(0);
+        let split = self.pending.len() - keep;
+        let output: String = self.pending.drain(..split).collect();
+        self.apply_budget(output)
+    }
+
+    /// 串流結束時輸出保留中的文字
+    pub fn flush(&mut self) -> String {
+        if self.finish_reason.is_some() {
+            return String::new();
+        }
+        let output = std::mem::take(&mut self.pending);
+        self.apply_budget(output)
+    }
+
+    /// 一次處理完整內容（非串流或 ReplaceResponse 模式）
+    pub fn apply(&mut self, text: &str) -> String {
+        let mut output = self.push(text);
+        output.push_str(&self.flush());
+        output
+    }
+
+    /// 是否已觸發 stop 序列或 token 上限，觸發後應停止讀取上游
+    pub fn is_finished(&self) -> bool {
+        self.finish_reason.is_some()
+    }
+
+    pub fn finish_reason(&self) -> &'static str {
+        self.finish_reason.unwrap_or("stop")
+    }
+
+    /// 觸發結束的 stop 序列
+    pub fn stop_sequence(&self) -> Option<&str> {
+        self.stop_sequence.as_deref()
+    }
+
+    /// 目前已輸出的 token 數量（估算值）
+    pub fn emitted_tokens(&self) -> usize {
+        self.emitted_tokens
+    }
+
+    fn apply_budget(&mut self, output: String) -> String {
+        let tokens = self.limits.tokenizer.count_tokens(&output);
+        let Some(max_tokens) = self.limits.max_tokens else {
+            self.emitted_tokens += tokens;
+            return output;
+        };
+
+        let remaining = max_tokens.saturating_sub(self.emitted_tokens);
+        if tokens <= remaining {
+            self.emitted_tokens += tokens;
+            return output;
+        }
+
+        debug!("✂️ 已達 max_tokens 上限 | 上限: {}", max_tokens);
+        self.finish_reason = Some("length");
+        self.pending.clear();
+        self.emitted_tokens = max_tokens;
+        self.limits.tokenizer.truncate_to_tokens(&output, remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(stop: &[&str], max_tokens: Option<u32>) -> OutputLimiter {
+        let stop = stop.iter().map(|sequence| sequence.to_string()).collect();
+        OutputLimits::new(stop, max_tokens, Tokenizer::Cl100k).limiter()
+    }
+
+    #[test]
+    fn stop_sequence_split_across_two_chunks() {
+        let mut limiter = limiter(&["STOP"], None);
+        assert_eq!(limiter.push("hello ST"), "hello ");
+        assert!(!limiter.is_finished());
+        assert_eq!(limiter.push("OP world"), "");
+        assert!(limiter.is_finished());
+        assert_eq!(limiter.finish_reason(), "stop");
+        assert_eq!(limiter.stop_sequence(), Some("STOP"));
+        assert_eq!(limiter.push("more"), "");
+        assert_eq!(limiter.flush(), "");
+    }
+
+    #[test]
+    fn stop_sequence_split_across_three_chunks() {
+        let mut limiter = limiter(&["<|end|>"], None);
+        assert_eq!(limiter.push("answer<"), "answer");
+        assert_eq!(limiter.push("|en"), "");
+        assert_eq!(limiter.push("d|>ignored"), "");
+        assert_eq!(limiter.stop_sequence(), Some("<|end|>"));
+        assert_eq!(limiter.finish_reason(), "stop");
+    }
+
+    #[test]
+    fn partial_match_is_released_when_it_does_not_complete() {
+        let mut limiter = limiter(&["STOP"], None);
+        assert_eq!(limiter.push("a ST"), "a ");
+        assert_eq!(limiter.push("AR"), "STAR");
+        assert_eq!(limiter.push("ST"), "");
+        assert_eq!(limiter.flush(), "ST");
+        assert!(!limiter.is_finished());
+        assert_eq!(limiter.finish_reason(), "stop");
+        assert_eq!(limiter.stop_sequence(), None);
+    }
+
+    #[test]
+    fn stop_sequence_at_start_and_end() {
+        let mut at_start = limiter(&["STOP"], None);
+        assert_eq!(at_start.apply("STOPtext"), "");
+        assert_eq!(at_start.stop_sequence(), Some("STOP"));
+
+        let mut at_end = limiter(&["STOP"], None);
+        assert_eq!(at_end.apply("textSTOP"), "text");
+        assert_eq!(at_end.stop_sequence(), Some("STOP"));
+    }
+
+    #[test]
+    fn earliest_stop_sequence_wins() {
+        let mut limiter = limiter(&["two", "one"], None);
+        assert_eq!(limiter.apply("zero one two"), "zero ");
+        assert_eq!(limiter.stop_sequence(), Some("one"));
+    }
+
+    #[test]
+    fn multi_byte_stop_sequence_split_across_chunks() {
+        let mut limiter = limiter(&["結束"], None);
+        assert_eq!(limiter.push("你好結"), "你好");
+        assert_eq!(limiter.push("束了"), "");
+        assert_eq!(limiter.stop_sequence(), Some("結束"));
+    }
+
+    #[test]
+    fn max_tokens_truncates_multi_byte_text_on_char_boundary() {
+        let text = "你好世界，這是一段用來測試截斷的中文文字。";
+        let mut limiter = limiter(&[], Some(5));
+        let output = limiter.apply(text);
+        assert!(text.starts_with(&output));
+        assert!(output.len() < text.len());
+        assert!(Tokenizer::Cl100k.count_tokens(&output) <= 5);
+        assert_eq!(limiter.finish_reason(), "length");
+        assert_eq!(limiter.emitted_tokens(), 5);
+    }
+
+    #[test]
+    fn max_tokens_across_chunks() {
+        let mut limiter = limiter(&[], Some(3));
+        assert_eq!(limiter.push("one"), "one");
+        assert_eq!(limiter.push(" two"), " two");
+        assert!(!limiter.is_finished());
+        assert_eq!(limiter.push(" three four five"), " three");
+        assert!(limiter.is_finished());
+        assert_eq!(limiter.finish_reason(), "length");
+        assert_eq!(limiter.push(" six"), "");
+    }
+
+    #[test]
+    fn finish_reason_is_stop_when_stop_sequence_comes_first() {
+        let mut limiter = limiter(&["STOP"], Some(100));
+        assert_eq!(limiter.apply("short STOP long tail"), "short ");
+        assert_eq!(limiter.finish_reason(), "stop");
+    }
+
+    #[test]
+    fn finish_reason_is_length_when_budget_runs_out_before_stop_sequence() {
+        let mut limiter = limiter(&["STOP"], Some(2));
+        let output = limiter.apply("one two three four STOP");
+        assert_eq!(output, "one two");
+        assert_eq!(limiter.finish_reason(), "length");
+    }
+
+    #[test]
+    fn no_limits_passes_everything_through() {
+        let mut limiter = limiter(&[], None);
+        assert_eq!(limiter.push("a"), "a");
+        assert_eq!(limiter.push("b"), "b");
+        assert_eq!(limiter.flush(), "");
+        assert_eq!(limiter.finish_reason(), "stop");
+        assert_eq!(limiter.emitted_tokens(), 2);
+    }
+}
@@ -1,21 +1,27 @@
 use salvo::prelude::*;
 use salvo::serve_static::StaticDir;
-use tracing::{info, debug};
-use std::env;
+use salvo::server::ServerHandle;
+use tracing::{info, debug, error};
 mod types;
 mod handlers;
 mod poe_client;
 mod utils;
+mod tools;
+mod structured;
+mod tokenizer;
+mod limits;
+mod config;
+mod settings;
+mod routing;
+mod shadow;
+mod keys;
+mod key_pool;
+mod rate_limit;
+mod quota;
+mod admin_auth;
+mod history;
 
-fn get_env_or_default(key: &str, default: &str) -> String {
-    let value = env::var(key).unwrap_or_else(|_| default.to_string());
-    if key == "ADMIN_PASSWORD" {
-        debug!("🔧 環境變數 {} = {}", key, "*".repeat(value.len()));
-    } else {
-        debug!("🔧 環境變數 {} = {}", key, value);
-    }
-    value
-}
+use settings::{CliArgs, Settings};
 
 fn setup_logging(log_level: &str) {
     tracing_subscriber::fmt()
@@ -30,38 +36,139 @@ fn setup_logging(log_level: &str) {
     info!("🚀 日誌系統初始化完成，日誌級別: {}", log_level);
 }
 
+/// 解析命令列並載入設定；有任何錯誤時列出全部並結束程式
+fn load_settings() -> (Settings, bool) {
+    let cli = match CliArgs::parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            eprintln!("用法: poe2openai [--config <path>] [validate-config | hash-password]");
+            std::process::exit(2);
+        }
+    };
+    if cli.hash_password {
+        match admin_auth::hash_from_stdin() {
+            Ok(hash) => {
+                println!("{}", hash);
+                std::process::exit(0);
+            },
+            Err(e) => {
+                eprintln!("❌ {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    match Settings::load(cli.config_path.as_deref()) {
+        Ok(settings) => (settings, cli.validate_only),
+        Err(errors) => {
+            eprintln!("❌ 配置驗證失敗，共 {} 個錯誤:", errors.len());
+            for error in &errors {
+                eprintln!("  - {}", error);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let log_level = get_env_or_default("LOG_LEVEL", "debug");
-    setup_logging(&log_level);
-    
-    let host = get_env_or_default("HOST", "0.0.0.0");
-    let port = get_env_or_default("PORT", "8080");
-    get_env_or_default("ADMIN_USERNAME", "admin");
-    get_env_or_default("ADMIN_PASSWORD", "123456");
-    let salvo_max_size = get_env_or_default("MAX_REQUEST_SIZE", "1073741824")
-        .parse()
-        .unwrap_or(1024 * 1024 * 1024); // 預設 1GB
+    let (settings, validate_only) = load_settings();
+    if validate_only {
+        println!("✅ 配置驗證通過");
+        return;
+    }
 
-    let bind_address = format!("{}:{}", host, port);
+    setup_logging(&settings.server.log_level);
+    debug!("🔧 伺服器設定: {:?}", settings.server);
+    debug!("🔧 模型設定: {:?}", settings.models);
+    debug!("🔧 限制設定: {:?}", settings.limits);
+    debug!("🔧 管理介面用戶名: {} | 工作階段: {} 秒 | 登入失敗上限: {} 次", settings.auth.admin_username, settings.auth.session_ttl, settings.auth.max_login_attempts);
+    debug!("🔧 預設速率限制: {:?}", settings.rate_limit);
+    debug!("🔧 金鑰池: {} 把金鑰 | 策略: {:?} | 冷卻: {} 秒", settings.pool.keys.len(), settings.pool.strategy, settings.pool.cooldown);
+
+    let bind_address = format!("{}:{}", settings.server.host, settings.server.port);
+    let salvo_max_size = settings.server.max_request_size;
+    settings::install(settings);
+
+    // 載入 models.yaml 並監看檔案變更
+    history::init();
+    config::init();
+    if let Err(e) = keys::init().and_then(|_| quota::init()) {
+        error!("❌ {}", e);
+        std::process::exit(1);
+    }
+    admin_auth::init();
 
     info!("🌟 正在啟動 Poe API To OpenAI API 服務...");
     debug!("📍 服務綁定地址: {}", bind_address);
 
     let router: Router = Router::new()
-        .hoop(max_size(salvo_max_size.try_into().unwrap()))
+        .hoop(max_size(salvo_max_size as u64))
         .push(Router::with_path("static/<**path>").get(StaticDir::new(["static"])))
         .push(handlers::admin_routes())
         .push(Router::with_path("models").get(handlers::get_models))
-        .push(Router::with_path("chat/completions").post(handlers::chat_completions))
         .push(Router::with_path("api/models").get(handlers::get_models))
+        // Ollama 相容端點（/api/models 已用於未過濾的模型列表，Ollama 使用 /api/tags）
+        .push(Router::with_path("api/tags").get(handlers::ollama_tags))
+        .push(Router::with_path("api/version").get(handlers::ollama_version))
         .push(Router::with_path("v1/models").get(handlers::get_models))
-        .push(Router::with_path("v1/chat/completions").post(handlers::chat_completions));
+        .push(Router::with_path("v1/responses/<id>").get(handlers::get_response))
+        // 聊天相關端點套用速率限制及配額
+        .push(
+            Router::new()
+                .hoop(rate_limit::rate_limit)
+                .hoop(quota::enforce)
+                .push(Router::with_path("chat/completions").post(handlers::chat_completions))
+                .push(Router::with_path("completions").post(handlers::completions))
+                .push(Router::with_path("api/chat").post(handlers::ollama_chat))
+                .push(Router::with_path("api/generate").post(handlers::ollama_generate))
+                .push(Router::with_path("v1/chat/completions").post(handlers::chat_completions))
+                .push(Router::with_path("v1/completions").post(handlers::completions))
+                .push(Router::with_path("v1/messages").post(handlers::anthropic_messages))
+                .push(Router::with_path("v1/responses").post(handlers::create_response))
+        );
 
     info!("🛣️  API 路由配置完成");
     
     let acceptor = TcpListener::new(&bind_address).bind().await;
     info!("🎯 服務已啟動並監聽於 {}", bind_address);
     
-    Server::new(acceptor).serve(router).await;
+    let server = Server::new(acceptor);
+    tokio::spawn(shutdown_signal(server.handle()));
+    server.serve(router).await;
+
+    // 所有連線結束後才寫入，串流中的請求結算的用量也會一併保存
+    quota::shutdown().await;
+    info!("👋 服務已停止");
+}
+
+/// 收到 Ctrl+C 或 SIGTERM 時停止接受新連線，等待進行中的請求結束
+async fn shutdown_signal(handle: ServerHandle) {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("❌ 無法監聽 Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            },
+            Err(e) => {
+                error!("❌ 無法監聽 SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("🛑 收到停止信號，等待進行中的請求結束");
+    handle.stop_graceful(None);
 }
\ No newline at end of file
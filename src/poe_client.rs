@@ -1,15 +1,20 @@
-use futures_util::Stream;
-use poe_api_process::{EventResponse, PoeClient, PoeError, ProtocolMessage, QueryRequest};
-use std::path::Path;
+use base64::Engine;
+use futures_util::stream::{self, Stream, StreamExt};
+use poe_api_process::{Attachment, EventResponse, EventType, FileUploadRequest, PoeClient, PoeError, ProtocolMessage, QueryRequest};
 use std::pin::Pin;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use std::time::Instant;
 
+use crate::key_pool::{self, KeyFailure};
+use crate::quota::Meter;
 use crate::types::*;
 
+type PoeEventStream = Pin<Box<dyn Stream<Item = Result<EventResponse, PoeError>> + Send>>;
+
 pub struct PoeClientWrapper {
     client: PoeClient,
-    _model: String,
+    model: String,
+    access_key: String,
 }
 
 impl PoeClientWrapper {
@@ -17,12 +22,25 @@ impl PoeClientWrapper {
         info!("🔑 初始化 POE 客戶端 | 模型: {}", model);
         Self {
             client: PoeClient::new(model, access_key),
-            _model: model.to_string(),
+            model: model.to_string(),
+            access_key: access_key.to_string(),
         }
     }
 
-    pub async fn stream_request(&self, query_request: QueryRequest) -> Result<Pin<Box<dyn Stream<Item = Result<EventResponse, PoeError>> + Send>>, PoeError> {
+    /// 此客戶端使用的 Poe API Key
+    pub fn access_key(&self) -> &str {
+        &self.access_key
+    }
+
+    /// 此客戶端請求的 Poe bot
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// 發出串流請求；每次請求都計入目前請求的配額用量
+    pub async fn stream_request(&self, query_request: QueryRequest) -> Result<PoeEventStream, PoeError> {
         let start_time = Instant::now();
+        Meter::current().query();
         debug!("📤 發送串流請求 | 訊息數量: {} | 溫度設置: {:?}", 
             query_request.query.len(),
             query_request.temperature
@@ -48,48 +66,234 @@ impl PoeClientWrapper {
         
         result
     }
-}
 
-pub fn create_query_request(model: &str, messages: Vec<Message>, temperature: Option<f32>) -> QueryRequest {
-    debug!("📝 創建查詢請求 | 模型: {} | 訊息數量: {} | 溫度設置: {:?}", 
-        model, messages.len(), temperature);
-    
-    // 讀取 models.yaml 配置
-    let config = match Path::new("models.yaml").exists() {
-        true => {
-            match std::fs::read_to_string("models.yaml") {
-                Ok(contents) => {
-                    match serde_yaml::from_str::<Config>(&contents) {
-                        Ok(config) => config,
-                        Err(e) => {
-                            error!("❌ 解析 models.yaml 失敗: {}", e);
-                            Config {
-                                enable: Some(false),
-                                models: std::collections::HashMap::new(),
-                            }
-                        }
+    /// 將訊息中的 data URI 圖片上傳至 Poe，並以回傳的附件網址取代
+    pub async fn upload_data_images(&self, messages: &mut [Message]) -> Result<(), String> {
+        for message in messages.iter_mut() {
+            let MessageContent::Parts(parts) = &mut message.content else {
+                continue;
+            };
+            for part in parts.iter_mut() {
+                let ContentPart::ImageUrl { image_url } = part else {
+                    continue;
+                };
+                if !image_url.url.starts_with("data:") {
+                    continue;
+                }
+
+                let (mime_type, data) = decode_data_uri(&image_url.url)?;
+                debug!("📤 上傳 data URI 圖片 | 類型: {} | 大小: {}",
+                    mime_type,
+                    crate::utils::format_bytes_length(data.len())
+                );
+
+                let temp_path = std::env::temp_dir().join(format!("poe2openai-{}.{}", nanoid::nanoid!(10), file_extension(&mime_type)));
+                std::fs::write(&temp_path, &data)
+                    .map_err(|e| format!("寫入暫存圖片失敗: {}", e))?;
+
+                let upload_result = self.client.upload_files(vec![FileUploadRequest::LocalFile {
+                    file: temp_path.to_string_lossy().to_string(),
+                    mime_type: Some(mime_type.clone()),
+                }]).await;
+                let _ = std::fs::remove_file(&temp_path);
+
+                match upload_result {
+                    Ok(mut responses) if !responses.is_empty() => {
+                        let uploaded = responses.remove(0);
+                        debug!("✅ 圖片上傳成功 | 網址: {}", uploaded.attachment_url);
+                        image_url.url = uploaded.attachment_url;
+                    },
+                    Ok(_) => return Err("圖片上傳失敗: 未收到附件網址".to_string()),
+                    Err(e) => {
+                        error!("❌ 圖片上傳失敗: {}", e);
+                        return Err(format!("圖片上傳失敗: {}", e));
                     }
-                },
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 依序向 bot 清單發出請求，在收到任何內容前就失敗時改用下一個 bot；
+/// 被限流或驗證失敗時先從金鑰池換一把 Poe API Key 重試同一個 bot。
+/// 最後一個 bot 的錯誤事件會保留在串流中，交由呼叫端依原本的方式回報
+pub async fn stream_with_fallbacks(
+    chain: &[String],
+    keys: &[String],
+    build_request: impl Fn() -> QueryRequest,
+) -> Result<(PoeClientWrapper, PoeEventStream), String> {
+    if chain.is_empty() {
+        return Err("沒有可以請求的 bot".to_string());
+    }
+    if keys.is_empty() {
+        return Err("沒有可用的 Poe API Key".to_string());
+    }
+    let mut keys = keys.to_vec();
+    keys.sort();
+    keys.dedup();
+
+    let mut last_error = None;
+    for (index, bot) in chain.iter().enumerate() {
+        let is_last_bot = index + 1 == chain.len();
+        let mut tried = Vec::new();
+        while let Some(access_key) = key_pool::select(&keys, &tried) {
+            let has_more_keys = tried.len() + 1 < keys.len();
+            let client = PoeClientWrapper::new(bot, &access_key);
+            let event_stream = match client.stream_request(build_request()).await {
+                Ok(event_stream) => event_stream,
                 Err(e) => {
-                    error!("❌ 讀取 models.yaml 失敗: {}", e);
-                    Config {
-                        enable: Some(false),
-                        models: std::collections::HashMap::new(),
+                    let message = e.to_string();
+                    let failure = KeyFailure::classify(&message);
+                    key_pool::report_failure(&access_key, failure, &message);
+                    last_error = Some(e);
+                    if failure.is_key_related() && has_more_keys {
+                        warn!("⚠️ Poe API Key 無法使用，換一把金鑰重試 | 錯誤: {}", message);
+                        tried.push(access_key);
+                        continue;
+                    }
+                    if !is_last_bot {
+                        warn!("⚠️ bot {} 請求失敗，改用下一個 fallback | 錯誤: {}", bot, message);
                     }
+                    break;
+                }
+            };
+
+            let (event_stream, failure) = check_initial_events(event_stream).await;
+            let Some(reason) = failure else {
+                key_pool::report_success(&access_key);
+                if index > 0 {
+                    info!("🛟 改由 fallback bot 回應: {}", bot);
                 }
+                return Ok((client, event_stream));
+            };
+            let failure = KeyFailure::classify(&reason);
+            key_pool::report_failure(&access_key, failure, &reason);
+            if failure.is_key_related() && has_more_keys {
+                warn!("⚠️ Poe API Key 無法使用，換一把金鑰重試 | 錯誤: {}", reason);
+                tried.push(access_key);
+                continue;
             }
-        },
-        false => {
-            debug!("⚠️ models.yaml 不存在，使用標準處理");
-            Config {
-                enable: Some(false),
-                models: std::collections::HashMap::new(),
+            if is_last_bot {
+                return Ok((client, event_stream));
             }
+            warn!("⚠️ bot {} 回應錯誤，改用下一個 fallback | 錯誤: {}", bot, reason);
+            break;
         }
+    }
+    // 走到這裡代表最後一次建立請求失敗
+    Err(last_error.map_or_else(|| "所有 bot 的請求都失敗".to_string(), |e| e.to_string()))
+}
+
+/// 讀取事件直到出現內容或串流結束，並將讀過的事件放回串流開頭；內容出現前就發生錯誤時回傳錯誤原因
+async fn check_initial_events(mut event_stream: PoeEventStream) -> (PoeEventStream, Option<String>) {
+    let mut buffered = Vec::new();
+    let mut failure = None;
+    while let Some(item) = event_stream.next().await {
+        match &item {
+            Ok(event) => match event.event {
+                EventType::Error => {
+                    failure = Some(event.error.as_ref().map(|error| error.text.clone()).unwrap_or_default());
+                },
+                EventType::Text | EventType::ReplaceResponse | EventType::Done => {},
+                _ => {
+                    buffered.push(item);
+                    continue;
+                },
+            },
+            Err(e) => failure = Some(e.to_string()),
+        }
+        buffered.push(item);
+        break;
+    }
+    (Box::pin(stream::iter(buffered).chain(event_stream)), failure)
+}
+
+/// 解析 `data:<mime>;base64,<data>` 格式的 URI
+fn decode_data_uri(uri: &str) -> Result<(String, Vec<u8>), String> {
+    let (header, data) = uri
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(','))
+        .ok_or_else(|| "無效的 data URI".to_string())?;
+    let mime_type = header
+        .strip_suffix(";base64")
+        .ok_or_else(|| "data URI 必須使用 base64 編碼".to_string())?;
+    let mime_type = if mime_type.is_empty() { "application/octet-stream" } else { mime_type };
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(data.trim())
+        .map_err(|e| format!("base64 解碼失敗: {}", e))?;
+    Ok((mime_type.to_string(), bytes))
+}
+
+/// 暫存檔使用的副檔名，未知的類型使用 bin
+fn file_extension(mime_type: &str) -> &'static str {
+    match mime_type.to_lowercase().as_str() {
+        "image/png" => "png",
+        "image/jpeg" | "image/jpg" | "image/pjpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "image/bmp" | "image/x-ms-bmp" => "bmp",
+        "image/tiff" => "tiff",
+        "image/heic" => "heic",
+        "image/heif" => "heif",
+        "image/avif" => "avif",
+        "image/x-icon" | "image/vnd.microsoft.icon" => "ico",
+        "application/pdf" => "pdf",
+        "text/plain" => "txt",
+        _ => "bin",
+    }
+}
+
+/// 依網址副檔名推測圖片類型
+fn guess_image_content_type(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next().unwrap_or(url).to_lowercase();
+    let content_type = match path.rsplit('.').next()? {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => return None,
     };
+    Some(content_type.to_string())
+}
+
+/// 依模型設定決定實際的輸出 token 上限
+pub fn resolve_max_tokens(model: &str, requested: Option<u32>) -> Option<u32> {
+    let config = crate::config::current();
+    let max_tokens = match config.models.get(model) {
+        Some(model_config) => model_config.resolve_max_tokens(requested),
+        None => requested,
+    };
+    if max_tokens != requested {
+        debug!("🎛️ 套用模型 max_tokens 設定 | 模型: {} | 請求: {:?} | 實際: {:?}", model, requested, max_tokens);
+    }
+    max_tokens
+}
+
+/// 套用模型設定的 system 提示；需在估算 prompt token 前呼叫，用量才會包含實際送出的提示
+pub fn apply_system_prompt(model: &str, messages: &mut Vec<Message>) {
+    if let Some(model_config) = crate::config::current().models.get(model) {
+        model_config.apply_system_prompt(messages);
+    }
+}
+
+/// 建立 Poe 查詢請求；messages 應已經過 apply_system_prompt
+pub fn create_query_request(model: &str, messages: Vec<Message>, temperature: Option<f32>, stop_sequences: Vec<String>) -> QueryRequest {
+    let config = crate::config::current();
+    let model_config = config.models.get(model);
+
+    // 套用模型的預設值及強制值
+    let temperature = match model_config {
+        Some(model_config) => model_config.resolve_temperature(temperature),
+        None => temperature,
+    };
+
+    debug!("📝 創建查詢請求 | 模型: {} | 訊息數量: {} | 溫度設置: {:?} | stop 序列數量: {}", 
+        model, messages.len(), temperature, stop_sequences.len());
 
     // 檢查模型是否需要 replace_response 處理
-    let should_replace_response = if let Some(model_config) = config.models.get(model) {
+    let should_replace_response = if let Some(model_config) = model_config {
         model_config.replace_response.unwrap_or(false)
     } else {
         false
@@ -108,16 +312,26 @@ pub fn create_query_request(model: &str, messages: Vec<Message>, temperature: Op
             other => other
         }.to_string();
 
-        debug!("🔄 處理訊息 | 原始角色: {} | 轉換後角色: {} | 內容長度: {}", 
+        let content = msg.content.text();
+        let attachments: Vec<Attachment> = msg.content.image_urls().into_iter()
+            .map(|url| Attachment {
+                url: url.to_string(),
+                content_type: guess_image_content_type(url),
+            })
+            .collect();
+
+        debug!("🔄 處理訊息 | 原始角色: {} | 轉換後角色: {} | 內容長度: {} | 附件數量: {}", 
             original_role,
             role,
-            crate::utils::format_bytes_length(msg.content.len())
+            crate::utils::format_bytes_length(content.len()),
+            attachments.len()
         );
 
         ProtocolMessage {
             role,
-            content: msg.content,
+            content,
             content_type: "text/markdown".to_string(),
+            attachments,
         }
     }).collect();
 
@@ -129,5 +343,10 @@ pub fn create_query_request(model: &str, messages: Vec<Message>, temperature: Op
         user_id: "".to_string(),
         conversation_id: "".to_string(),
         message_id: "".to_string(),
+        tools: None,
+        tool_calls: None,
+        tool_results: None,
+        logit_bias: None,
+        stop_sequences,
     }
 }
\ No newline at end of file
@@ -0,0 +1,630 @@
+use chrono::{Datelike, NaiveDate, Utc};
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+use crate::keys::ProxyKey;
+use crate::rate_limit::{estimate_request, request_credential, system_prompt_tokens};
+use crate::routing::{key_access, resolve_for};
+use crate::types::{OpenAIError, OpenAIErrorResponse};
+
+/// 代理金鑰的用量上限
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Quota {
+    pub(crate) metric: QuotaMetric,
+    pub(crate) period: QuotaPeriod,
+    pub(crate) limit: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QuotaMetric {
+    Requests,
+    /// 以請求大小估計的輸入加上實際輸出的 token 數
+    Tokens,
+    /// models.yaml 中模型設定的 points
+    Points,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+pub(crate) struct Counters {
+    pub(crate) requests: u64,
+    pub(crate) tokens: u64,
+    pub(crate) points: u64,
+}
+
+impl Counters {
+    fn get(&self, metric: QuotaMetric) -> u64 {
+        match metric {
+            QuotaMetric::Requests => self.requests,
+            QuotaMetric::Tokens => self.tokens,
+            QuotaMetric::Points => self.points,
+        }
+    }
+
+    fn add(&mut self, other: &Counters) {
+        self.requests += other.requests;
+        self.tokens += other.tokens;
+        self.points += other.points;
+    }
+
+    fn subtract(&mut self, other: &Counters) {
+        self.requests = self.requests.saturating_sub(other.requests);
+        self.tokens = self.tokens.saturating_sub(other.tokens);
+        self.points = self.points.saturating_sub(other.points);
+    }
+}
+
+/// 單一代理金鑰的用量，日期（UTC）改變時歸零
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct UsageRecord {
+    day: String,
+    month: String,
+    daily: Counters,
+    monthly: Counters,
+}
+
+impl UsageRecord {
+    fn roll_over(&mut self) {
+        let now = Utc::now();
+        let day = now.format("%Y-%m-%d").to_string();
+        let month = now.format("%Y-%m").to_string();
+        if self.day != day {
+            self.day = day;
+            self.daily = Counters::default();
+        }
+        if self.month != month {
+            self.month = month;
+            self.monthly = Counters::default();
+        }
+    }
+
+    fn counters(&self, period: QuotaPeriod) -> &Counters {
+        match period {
+            QuotaPeriod::Day => &self.daily,
+            QuotaPeriod::Month => &self.monthly,
+        }
+    }
+}
+
+/// 管理介面顯示的配額使用狀況
+#[derive(Serialize)]
+pub(crate) struct QuotaStatus {
+    metric: QuotaMetric,
+    period: QuotaPeriod,
+    limit: u64,
+    used: u64,
+    resets_at: i64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct KeyUsage {
+    id: String,
+    name: String,
+    daily: Counters,
+    monthly: Counters,
+    quotas: Vec<QuotaStatus>,
+}
+
+fn store() -> &'static Mutex<HashMap<String, UsageRecord>> {
+    static STORE: OnceLock<Mutex<HashMap<String, UsageRecord>>> = OnceLock::new();
+    STORE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn usage_path() -> &'static Path {
+    &crate::settings::get().keys.usage_file
+}
+
+/// 用量變更後不立即寫檔，由背景工作定期寫入
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+static DIRTY: AtomicBool = AtomicBool::new(false);
+
+fn mark_dirty() {
+    DIRTY.store(true, Ordering::Release);
+}
+
+/// 啟動時載入用量紀錄，並在背景定期寫入變更；檔案無法讀取或解析時先備份再回傳錯誤，
+/// 避免下一次寫入以空的紀錄覆蓋檔案而將所有用量歸零
+pub(crate) fn init() -> Result<(), String> {
+    load()?;
+    tokio::spawn(async {
+        loop {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+            flush().await;
+        }
+    });
+    Ok(())
+}
+
+fn load() -> Result<(), String> {
+    let path = usage_path();
+    if !path.exists() {
+        debug!("📊 用量紀錄檔案不存在，從零開始計算");
+        return Ok(());
+    }
+    let records = std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| serde_json::from_str::<HashMap<String, UsageRecord>>(&contents).map_err(|e| e.to_string()))
+        .map_err(|e| crate::utils::load_failure("用量紀錄", path, &e))?;
+    info!("📊 已載入用量紀錄 | 金鑰數量: {}", records.len());
+    *store().lock().unwrap() = records;
+    Ok(())
+}
+
+/// 服務停止前寫入尚未保存的用量
+pub(crate) async fn shutdown() {
+    flush().await;
+    debug!("📊 已保存用量紀錄");
+}
+
+/// 有變更時複製一份紀錄，在阻塞執行緒上寫入檔案，不佔用鎖及 tokio 工作執行緒
+async fn flush() {
+    if !DIRTY.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    let records = store().lock().unwrap().clone();
+    let result = tokio::task::spawn_blocking(move || persist(&records)).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    if let Err(e) = result {
+        error!("❌ 寫入用量紀錄失敗: {}", e);
+        // 下一輪再試
+        mark_dirty();
+    }
+}
+
+fn persist(records: &HashMap<String, UsageRecord>) -> Result<(), String> {
+    let json = serde_json::to_string(records).map_err(|e| e.to_string())?;
+    crate::utils::write_atomic(usage_path(), json).map_err(|e| e.to_string())
+}
+
+/// 下一次歸零的時間（UTC）
+fn resets_at(period: QuotaPeriod) -> i64 {
+    let today = Utc::now().date_naive();
+    let next = match period {
+        QuotaPeriod::Day => today.succ_opt(),
+        QuotaPeriod::Month if today.month() == 12 => NaiveDate::from_ymd_opt(today.year() + 1, 1, 1),
+        QuotaPeriod::Month => NaiveDate::from_ymd_opt(today.year(), today.month() + 1, 1),
+    };
+    next.and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map_or(i64::MAX, |time| time.and_utc().timestamp())
+}
+
+fn period_name(period: QuotaPeriod) -> &'static str {
+    match period {
+        QuotaPeriod::Day => "每日",
+        QuotaPeriod::Month => "每月",
+    }
+}
+
+fn metric_name(metric: QuotaMetric) -> &'static str {
+    match metric {
+        QuotaMetric::Requests => "請求",
+        QuotaMetric::Tokens => "token",
+        QuotaMetric::Points => "點數",
+    }
+}
+
+/// 已記錄的一次用量，請求失敗時用來退回
+struct Charge {
+    key_id: String,
+    day: String,
+    month: String,
+    amount: Counters,
+}
+
+/// 檢查配額並記錄本次用量；超過任一配額時不記錄並回傳原因
+fn charge(key: &ProxyKey, amount: Counters) -> Result<Charge, String> {
+    let mut records = store().lock().unwrap();
+    let record = records.entry(key.id.clone()).or_default();
+    record.roll_over();
+    for quota in &key.quotas {
+        let used = record.counters(quota.period).get(quota.metric);
+        // token 數只是估計值，只要還有剩餘就放行
+        let exceeded = match quota.metric {
+            QuotaMetric::Tokens => used >= quota.limit,
+            metric => used + amount.get(metric) > quota.limit,
+        };
+        if exceeded {
+            return Err(format!(
+                "已用完{}{}配額（{} / {}），將於 {} 重設",
+                period_name(quota.period),
+                metric_name(quota.metric),
+                used,
+                quota.limit,
+                chrono::DateTime::from_timestamp(resets_at(quota.period), 0)
+                    .map_or_else(String::new, |time| time.format("%Y-%m-%d %H:%M UTC").to_string()),
+            ));
+        }
+    }
+    record.daily.add(&amount);
+    record.monthly.add(&amount);
+    let charge = Charge {
+        key_id: key.id.clone(),
+        day: record.day.clone(),
+        month: record.month.clone(),
+        amount,
+    };
+    drop(records);
+    mark_dirty();
+    Ok(charge)
+}
+
+/// 退回請求失敗時先記錄的用量；期間已跨日或跨月時只退回仍在同一週期的部分
+fn refund(charge: &Charge) {
+    adjust(charge, &Counters::default(), &charge.amount);
+}
+
+/// 在已記錄的用量上追加或扣除，只調整與記錄時同一週期的計數
+fn adjust(charge: &Charge, extra: &Counters, unused: &Counters) {
+    let mut records = store().lock().unwrap();
+    let Some(record) = records.get_mut(&charge.key_id) else {
+        return;
+    };
+    if record.day == charge.day {
+        record.daily.add(extra);
+        record.daily.subtract(unused);
+    }
+    if record.month == charge.month {
+        record.monthly.add(extra);
+        record.monthly.subtract(unused);
+    }
+    drop(records);
+    mark_dirty();
+}
+
+tokio::task_local! {
+    /// 目前請求的用量計量，由 enforce 在處理請求期間設定
+    static METER: Meter;
+}
+
+/// 一次請求的用量計量：記錄實際送出的 Poe 請求及輸出的 token，
+/// 複本可移入串流，最後一個複本被丟棄時以實際輸出結算 token 用量
+#[derive(Clone, Default)]
+pub(crate) struct Meter(Option<Arc<Mutex<Metering>>>);
+
+struct Metering {
+    charge: Charge,
+    /// 每次請求 bot 的點數
+    points: u64,
+    /// 預扣用量已涵蓋的請求數
+    prepaid_queries: u64,
+    queries: u64,
+    /// 預扣的輸出 token 數，結算時改以實際輸出計算
+    reserved_output: u64,
+    completion_tokens: u64,
+    refunded: bool,
+}
+
+impl Meter {
+    /// 目前請求的計量；金鑰沒有設定配額時不做任何記錄
+    pub(crate) fn current() -> Self {
+        METER.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    /// 記錄一次 Poe 請求；超出預扣數量的請求（fallback、換金鑰重試、結構化輸出重新詢問）另外計算
+    pub(crate) fn query(&self) {
+        let Some(metering) = &self.0 else {
+            return;
+        };
+        let mut metering = metering.lock().unwrap();
+        metering.queries += 1;
+        if metering.queries > metering.prepaid_queries {
+            let extra = Counters {
+                requests: 1,
+                tokens: 0,
+                points: metering.points,
+            };
+            adjust(&metering.charge, &extra, &Counters::default());
+            metering.charge.amount.add(&extra);
+            debug!("💸 追加請求用量 | 金鑰: {} | 第 {} 次請求", metering.charge.key_id, metering.queries);
+        }
+    }
+
+    /// 記錄實際輸出的 token 數
+    pub(crate) fn complete(&self, completion_tokens: usize) {
+        if let Some(metering) = &self.0 {
+            metering.lock().unwrap().completion_tokens += completion_tokens as u64;
+        }
+    }
+
+    /// 請求失敗時退回目前為止記錄的所有用量
+    fn refund(&self) {
+        if let Some(metering) = &self.0 {
+            let mut metering = metering.lock().unwrap();
+            if !metering.refunded {
+                metering.refunded = true;
+                refund(&metering.charge);
+            }
+        }
+    }
+}
+
+impl Drop for Metering {
+    fn drop(&mut self) {
+        if self.refunded {
+            return;
+        }
+        let tokens = |tokens| Counters { requests: 0, tokens, points: 0 };
+        let (extra, unused) = if self.completion_tokens >= self.reserved_output {
+            (tokens(self.completion_tokens - self.reserved_output), Counters::default())
+        } else {
+            (Counters::default(), tokens(self.reserved_output - self.completion_tokens))
+        };
+        adjust(&self.charge, &extra, &unused);
+        debug!("💸 結算輸出用量 | 金鑰: {} | 預扣: {} | 實際: {}", self.charge.key_id, self.reserved_output, self.completion_tokens);
+    }
+}
+
+/// 聊天相關端點的配額檢查中間件，只對設定了配額的代理金鑰生效
+///
+/// 先預扣每個回應各一次請求的用量再處理請求；處理期間追加的 Poe 請求另外計算，
+/// 輸出 token 在回應結束後以實際數量結算；回應不是 2xx（模型不存在、請求無效或上游失敗等）時全數退回
+#[handler]
+pub(crate) async fn enforce(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    let Some(key) = request_credential(req).ok().and_then(|credential| credential.key) else {
+        return;
+    };
+    if key.quotas.is_empty() {
+        return;
+    }
+
+    let mut estimate = estimate_request(req).await;
+    let mut points = 0;
+    if let Some(model) = estimate.model.take() {
+        let config = crate::config::current();
+        let access = key_access(&config, Some(&key));
+        let config_key = resolve_for(&config, &model, access.as_ref()).config_key;
+        if let Some(model_config) = config.models.get(&config_key) {
+            estimate.input_tokens += system_prompt_tokens(model_config);
+            points = model_config.points.unwrap_or(0);
+        }
+    }
+    let amount = Counters {
+        requests: estimate.queries,
+        tokens: estimate.tokens() as u64,
+        points: points * estimate.queries,
+    };
+
+    let charge = match charge(&key, amount) {
+        Ok(charge) => charge,
+        Err(message) => {
+            warn!("💸 配額已用完 | 金鑰: {} ({}) | {}", key.name, key.id, message);
+            res.status_code(StatusCode::TOO_MANY_REQUESTS);
+            res.render(Json(OpenAIErrorResponse {
+                error: OpenAIError {
+                    message,
+                    r#type: "insufficient_quota".to_string(),
+                    code: "insufficient_quota".to_string(),
+                    param: None,
+                }
+            }));
+            ctrl.skip_rest();
+            return;
+        }
+    };
+
+    let meter = Meter(Some(Arc::new(Mutex::new(Metering {
+        charge,
+        points,
+        prepaid_queries: estimate.queries,
+        queries: 0,
+        reserved_output: (estimate.max_output * estimate.queries as f64) as u64,
+        completion_tokens: 0,
+        refunded: false,
+    }))));
+    METER.scope(meter.clone(), ctrl.call_next(req, depot, res)).await;
+    let status = res.status_code.unwrap_or(StatusCode::OK);
+    if !status.is_success() {
+        debug!("💸 請求未成功，退回用量 | 金鑰: {} | 狀態: {}", key.id, status);
+        meter.refund();
+    }
+}
+
+/// 所有代理金鑰的用量及配額
+pub(crate) fn usage() -> Vec<KeyUsage> {
+    let keys = crate::keys::snapshot();
+    let mut records = store().lock().unwrap();
+    keys.into_iter()
+        .map(|key| {
+            let record = records.entry(key.id.clone()).or_default();
+            record.roll_over();
+            let quotas = key.quotas.iter()
+                .map(|quota| QuotaStatus {
+                    metric: quota.metric,
+                    period: quota.period,
+                    limit: quota.limit,
+                    used: record.counters(quota.period).get(quota.metric),
+                    resets_at: resets_at(quota.period),
+                })
+                .collect();
+            KeyUsage {
+                id: key.id,
+                name: key.name,
+                daily: record.daily,
+                monthly: record.monthly,
+                quotas,
+            }
+        })
+        .collect()
+}
+
+/// 將代理金鑰的用量歸零
+pub(crate) fn reset(id: &str) -> bool {
+    if !crate::keys::snapshot().iter().any(|key| key.id == id) {
+        return false;
+    }
+    store().lock().unwrap().remove(id);
+    mark_dirty();
+    info!("🔄 已重設代理金鑰用量 | ID: {}", id);
+    true
+}
+
+/// 刪除代理金鑰時一併移除其用量紀錄
+pub(crate) fn forget(id: &str) {
+    if store().lock().unwrap().remove(id).is_some() {
+        mark_dirty();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(id: &str, quotas: serde_json::Value) -> ProxyKey {
+        serde_json::from_value(json!({
+            "id": id,
+            "name": "test",
+            "hash": "",
+            "prefix": "sk-p2o-test",
+            "created_at": 0,
+            "quotas": quotas,
+        })).expect("測試金鑰無效")
+    }
+
+    fn amount(requests: u64, tokens: u64, points: u64) -> Counters {
+        Counters { requests, tokens, points }
+    }
+
+    fn daily(id: &str) -> Counters {
+        store().lock().unwrap().get(id).map(|record| record.daily).unwrap_or_default()
+    }
+
+    fn monthly(id: &str) -> Counters {
+        store().lock().unwrap().get(id).map(|record| record.monthly).unwrap_or_default()
+    }
+
+    fn meter(charge: Charge, points: u64, prepaid_queries: u64, reserved_output: u64) -> Meter {
+        Meter(Some(Arc::new(Mutex::new(Metering {
+            charge,
+            points,
+            prepaid_queries,
+            queries: 0,
+            reserved_output,
+            completion_tokens: 0,
+            refunded: false,
+        }))))
+    }
+
+    #[test]
+    fn charge_records_daily_and_monthly_usage() {
+        let key = key("quota-charge", json!([]));
+        charge(&key, amount(1, 100, 5)).unwrap();
+        charge(&key, amount(2, 50, 10)).unwrap();
+        let daily = daily("quota-charge");
+        assert_eq!((daily.requests, daily.tokens, daily.points), (3, 150, 15));
+        assert_eq!(monthly("quota-charge").tokens, 150);
+    }
+
+    #[test]
+    fn charge_rejects_requests_that_would_exceed_the_limit() {
+        let key = key("quota-requests", json!([{ "metric": "requests", "period": "day", "limit": 2 }]));
+        charge(&key, amount(2, 0, 0)).unwrap();
+        let error = charge(&key, amount(1, 0, 0)).err().unwrap();
+        assert!(error.contains("2 / 2"));
+        // 被拒絕的請求不記錄
+        assert_eq!(daily("quota-requests").requests, 2);
+    }
+
+    #[test]
+    fn points_limit_counts_the_new_request_but_tokens_only_need_any_remaining() {
+        let points = key("quota-points", json!([{ "metric": "points", "period": "month", "limit": 100 }]));
+        charge(&points, amount(1, 0, 60)).unwrap();
+        assert!(charge(&points, amount(1, 0, 60)).is_err());
+        charge(&points, amount(1, 0, 40)).unwrap();
+        assert_eq!(monthly("quota-points").points, 100);
+
+        let tokens = key("quota-tokens", json!([{ "metric": "tokens", "period": "day", "limit": 100 }]));
+        charge(&tokens, amount(1, 60, 0)).unwrap();
+        // 估計值超過剩餘額度仍放行
+        charge(&tokens, amount(1, 60, 0)).unwrap();
+        assert_eq!(daily("quota-tokens").tokens, 120);
+        assert!(charge(&tokens, amount(1, 1, 0)).is_err());
+    }
+
+    #[test]
+    fn refund_returns_the_charged_amount() {
+        let key = key("quota-refund", json!([{ "metric": "requests", "period": "day", "limit": 1 }]));
+        let charged = charge(&key, amount(1, 30, 2)).unwrap();
+        refund(&charged);
+        let daily = daily("quota-refund");
+        assert_eq!((daily.requests, daily.tokens, daily.points), (0, 0, 0));
+        assert!(charge(&key, amount(1, 0, 0)).is_ok());
+    }
+
+    #[test]
+    fn refund_after_a_day_boundary_only_returns_the_same_period() {
+        let key = key("quota-boundary", json!([]));
+        charge(&key, amount(1, 10, 0)).unwrap();
+        let mut charged = charge(&key, amount(1, 10, 0)).unwrap();
+        // 模擬前一天記錄的用量：已歸零的每日計數不再扣除，同月份的計數照常退回
+        charged.day = "1970-01-01".to_string();
+        refund(&charged);
+        assert_eq!(daily("quota-boundary").requests, 2);
+        assert_eq!(monthly("quota-boundary").requests, 1);
+
+        charged.month = "1970-01".to_string();
+        refund(&charged);
+        assert_eq!(monthly("quota-boundary").requests, 1);
+    }
+
+    #[test]
+    fn roll_over_resets_only_the_changed_period() {
+        let mut record = UsageRecord {
+            day: "1970-01-01".to_string(),
+            month: Utc::now().format("%Y-%m").to_string(),
+            daily: amount(1, 1, 1),
+            monthly: amount(5, 5, 5),
+        };
+        record.roll_over();
+        assert_eq!(record.day, Utc::now().format("%Y-%m-%d").to_string());
+        assert_eq!(record.daily.requests, 0);
+        assert_eq!(record.monthly.requests, 5);
+
+        record.month = "1970-01".to_string();
+        record.daily = amount(3, 3, 3);
+        record.roll_over();
+        assert_eq!(record.daily.requests, 3);
+        assert_eq!(record.monthly.requests, 0);
+    }
+
+    #[test]
+    fn meter_charges_extra_queries_and_settles_actual_output() {
+        let key = key("quota-meter", json!([]));
+        let meter = meter(charge(&key, amount(1, 110, 4)).unwrap(), 4, 1, 100);
+        meter.query();
+        meter.query();
+        meter.complete(30);
+        meter.complete(20);
+        drop(meter);
+        // 第二次請求另外計算，預扣的 100 個輸出 token 改以實際的 50 個結算
+        let daily = daily("quota-meter");
+        assert_eq!((daily.requests, daily.tokens, daily.points), (2, 60, 8));
+    }
+
+    #[test]
+    fn meter_refund_returns_extra_queries_and_skips_settlement() {
+        let key = key("quota-meter-refund", json!([]));
+        let meter = meter(charge(&key, amount(1, 110, 4)).unwrap(), 4, 1, 100);
+        meter.query();
+        meter.query();
+        meter.refund();
+        drop(meter);
+        let daily = daily("quota-meter-refund");
+        assert_eq!((daily.requests, daily.tokens, daily.points), (0, 0, 0));
+    }
+}
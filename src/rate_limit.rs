@@ -0,0 +1,573 @@
+use futures_util::stream::{Stream, StreamExt};
+use salvo::http::header::{self, HeaderValue};
+use salvo::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::keys::{Credential, ProxyKey, KEY_PREFIX};
+use crate::routing::{key_access, resolve_for};
+use crate::types::{ModelConfig, OpenAIError, OpenAIErrorResponse, RateLimits};
+
+/// 令牌桶：容量為每分鐘上限，以每秒 1/60 容量的速率補充
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: u32, now: Instant) -> Self {
+        Self {
+            capacity: f64::from(limit),
+            tokens: f64::from(limit),
+            updated: now,
+        }
+    }
+
+    /// 補充令牌；上限調整後以新的容量計算
+    fn refill(&mut self, limit: u32, now: Instant) {
+        self.capacity = f64::from(limit);
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// 取得指定數量的令牌需要等待的時間；超過容量的請求只需等到補滿
+    fn wait_for(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.tokens;
+        self.duration_of(missing)
+    }
+
+    /// 補滿所需的時間
+    fn reset_after(&self) -> Duration {
+        self.duration_of(self.capacity - self.tokens)
+    }
+
+    fn duration_of(&self, tokens: f64) -> Duration {
+        if tokens <= 0.0 || self.capacity <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(tokens * 60.0 / self.capacity)
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens = (self.tokens - amount.min(self.capacity)).max(0.0);
+    }
+
+    /// 到 now 為止是否已補滿，補滿的令牌桶與新建立的相同
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * self.capacity / 60.0 >= self.capacity
+    }
+}
+
+/// 一組限制（客戶端金鑰，或客戶端金鑰加上模型）的狀態
+#[derive(Default)]
+struct Scope {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+    active: u32,
+}
+
+impl Scope {
+    fn refill(&mut self, limits: &RateLimits, now: Instant) {
+        fn update(bucket: &mut Option<TokenBucket>, limit: Option<u32>, now: Instant) {
+            match limit {
+                Some(limit) => bucket.get_or_insert_with(|| TokenBucket::new(limit, now)).refill(limit, now),
+                None => *bucket = None,
+            }
+        }
+        update(&mut self.requests, limits.requests_per_minute, now);
+        update(&mut self.tokens, limits.tokens_per_minute, now);
+    }
+
+    /// 沒有進行中的請求且令牌桶都已補滿，移除後再建立的狀態相同
+    fn is_idle(&self, now: Instant) -> bool {
+        self.active == 0 && [&self.requests, &self.tokens].into_iter().flatten().all(|bucket| bucket.is_full(now))
+    }
+}
+
+/// 清除閒置狀態的間隔；直通模式下每把 Poe API Key 都有自己的狀態，不清除會無限增長
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+fn scopes() -> &'static Mutex<HashMap<String, Scope>> {
+    static SCOPES: OnceLock<Mutex<HashMap<String, Scope>>> = OnceLock::new();
+    SCOPES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn last_sweep() -> &'static Mutex<Instant> {
+    static LAST_SWEEP: OnceLock<Mutex<Instant>> = OnceLock::new();
+    LAST_SWEEP.get_or_init(|| Mutex::new(Instant::now()))
+}
+
+/// 每隔 SWEEP_INTERVAL 移除閒置的狀態
+fn sweep(scopes: &mut HashMap<String, Scope>, now: Instant) {
+    let mut last_sweep = last_sweep().lock().unwrap();
+    if now.duration_since(*last_sweep) < SWEEP_INTERVAL {
+        return;
+    }
+    *last_sweep = now;
+    let before = scopes.len();
+    scopes.retain(|_, scope| !scope.is_idle(now));
+    if scopes.len() < before {
+        debug!("🚦 已清除閒置的速率限制狀態 | 數量: {} | 剩餘: {}", before - scopes.len(), scopes.len());
+    }
+}
+
+/// 佔用中的並行名額，最後一個複本被丟棄時釋放
+#[derive(Clone)]
+pub(crate) struct StreamPermit {
+    _inner: Arc<PermitInner>,
+}
+
+struct PermitInner {
+    scopes: Vec<String>,
+}
+
+impl Drop for PermitInner {
+    fn drop(&mut self) {
+        let mut scopes = scopes().lock().unwrap();
+        for name in &self.scopes {
+            if let Some(scope) = scopes.get_mut(name) {
+                scope.active = scope.active.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// 回應中的 x-ratelimit-* 標頭，多組限制時取剩餘最少的那組；沒有對應的每分鐘上限時標示為 unlimited
+#[derive(Default)]
+struct RateLimitHeaders {
+    requests: Option<(u32, u64, Duration)>,
+    tokens: Option<(u32, u64, Duration)>,
+}
+
+impl RateLimitHeaders {
+    fn record(slot: &mut Option<(u32, u64, Duration)>, limit: Option<u32>, bucket: Option<&TokenBucket>) {
+        let (Some(limit), Some(bucket)) = (limit, bucket) else {
+            return;
+        };
+        let remaining = bucket.tokens.floor() as u64;
+        if slot.is_none_or(|(_, current, _)| remaining < current) {
+            *slot = Some((limit, remaining, bucket.reset_after()));
+        }
+    }
+
+    fn apply(&self, res: &mut Response) {
+        let groups = [
+            (["x-ratelimit-limit-requests", "x-ratelimit-remaining-requests", "x-ratelimit-reset-requests"], self.requests),
+            (["x-ratelimit-limit-tokens", "x-ratelimit-remaining-tokens", "x-ratelimit-reset-tokens"], self.tokens),
+        ];
+        for ([limit_name, remaining_name, reset_name], value) in groups {
+            let headers = res.headers_mut();
+            let Some((limit, remaining, reset)) = value else {
+                headers.insert(limit_name, HeaderValue::from_static(UNLIMITED));
+                headers.insert(remaining_name, HeaderValue::from_static(UNLIMITED));
+                headers.insert(reset_name, HeaderValue::from_static("0s"));
+                continue;
+            };
+            headers.insert(limit_name, HeaderValue::from(limit));
+            headers.insert(remaining_name, HeaderValue::from(remaining));
+            if let Ok(reset) = HeaderValue::from_str(&format_reset(reset)) {
+                headers.insert(reset_name, reset);
+            }
+        }
+    }
+}
+
+/// 沒有上限時 x-ratelimit-limit-* 及 x-ratelimit-remaining-* 的值
+const UNLIMITED: &str = "unlimited";
+
+/// 以 OpenAI 的格式表示重設時間，例如 `20ms`、`1s`、`6m0s`
+fn format_reset(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis < 1000 {
+        return format!("{}ms", millis);
+    }
+    let secs = duration.as_secs_f64().ceil() as u64;
+    if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m{}s", secs / 60, secs % 60)
+    }
+}
+
+/// 被拒絕的原因
+struct Rejection {
+    kind: &'static str,
+    message: String,
+    retry_after: Duration,
+}
+
+impl Rejection {
+    /// Retry-After 標頭的秒數，無條件進位且至少 1 秒
+    fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+/// 檢查所有限制，全部通過時才扣除令牌並佔用並行名額
+fn acquire(checks: &[(String, RateLimits)], estimated_tokens: f64) -> (Result<StreamPermit, Rejection>, RateLimitHeaders) {
+    let now = Instant::now();
+    let mut scopes = scopes().lock().unwrap();
+    sweep(&mut scopes, now);
+    let mut rejection: Option<Rejection> = None;
+
+    for (name, limits) in checks {
+        let scope = scopes.entry(name.clone()).or_default();
+        scope.refill(limits, now);
+        let candidates = [
+            (
+                "requests",
+                scope.requests.as_ref().map(|bucket| bucket.wait_for(1.0)),
+                format!("已達每分鐘請求上限 ({})", limits.requests_per_minute.unwrap_or_default()),
+            ),
+            (
+                "tokens",
+                scope.tokens.as_ref().map(|bucket| bucket.wait_for(estimated_tokens)),
+                format!("已達每分鐘 token 上限 ({})", limits.tokens_per_minute.unwrap_or_default()),
+            ),
+        ];
+        for (kind, wait, message) in candidates {
+            if let Some(wait) = wait.filter(|wait| !wait.is_zero()) {
+                if rejection.as_ref().is_none_or(|current| wait > current.retry_after) {
+                    rejection = Some(Rejection { kind, message, retry_after: wait });
+                }
+            }
+        }
+        if let Some(max) = limits.concurrent_streams {
+            if scope.active >= max && rejection.is_none() {
+                rejection = Some(Rejection {
+                    kind: "requests",
+                    message: format!("同時進行中的請求已達上限 ({})", max),
+                    retry_after: Duration::from_secs(1),
+                });
+            }
+        }
+    }
+
+    if rejection.is_none() {
+        for (name, _) in checks {
+            let scope = scopes.get_mut(name).expect("已在檢查時建立");
+            if let Some(bucket) = scope.requests.as_mut() {
+                bucket.take(1.0);
+            }
+            if let Some(bucket) = scope.tokens.as_mut() {
+                bucket.take(estimated_tokens);
+            }
+            scope.active += 1;
+        }
+    }
+
+    let mut headers = RateLimitHeaders::default();
+    for (name, limits) in checks {
+        let scope = &scopes[name];
+        RateLimitHeaders::record(&mut headers.requests, limits.requests_per_minute, scope.requests.as_ref());
+        RateLimitHeaders::record(&mut headers.tokens, limits.tokens_per_minute, scope.tokens.as_ref());
+    }
+
+    let result = match rejection {
+        Some(rejection) => Err(rejection),
+        None => Ok(StreamPermit {
+            _inner: Arc::new(PermitInner {
+                scopes: checks.iter().map(|(name, _)| name.clone()).collect(),
+            }),
+        }),
+    };
+    (result, headers)
+}
+
+/// 請求帶的金鑰：x-api-key 或 Authorization: Bearer
+fn request_token(req: &Request) -> Option<&str> {
+    req.headers().get("x-api-key")
+        .or_else(|| req.headers().get("Authorization"))
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.strip_prefix("Bearer ").unwrap_or(value))
+}
+
+/// 未帶金鑰時改用 ollama.access_key 的 Ollama 端點
+const OLLAMA_PATHS: [&str; 3] = ["/api/chat", "/api/generate", "/api/tags"];
+
+/// 驗證請求的金鑰；Ollama 客戶端通常不帶授權標頭，未提供時改用設定中的 ollama.access_key。
+/// 速率限制、配額及 Ollama 端點都以此決定呼叫者，設定的代理金鑰同樣受其配額及上限約束
+pub(crate) fn request_credential(req: &Request) -> Result<Credential, String> {
+    if let Some(token) = request_token(req) {
+        return crate::keys::authenticate(token).map_err(|e| e.to_string());
+    }
+    if !OLLAMA_PATHS.contains(&req.uri().path().trim_end_matches('/')) {
+        return Err("缺少或無效的 Authorization".to_string());
+    }
+    match crate::settings::get().ollama.access_key.clone() {
+        // 伺服器端設定的 Poe API Key 不受直通模式限制
+        Some(key) if !key.starts_with(KEY_PREFIX) => Ok(Credential {
+            client_id: "ollama".to_string(),
+            poe_keys: vec![key],
+            key: None,
+        }),
+        Some(key) => crate::keys::authenticate(&key).map_err(|e| e.to_string()),
+        None => Err("缺少授權標頭且未設定 ollama.access_key".to_string()),
+    }
+}
+
+/// 識別客戶端並決定其速率上限；金鑰無效時回傳 None，交由端點回應驗證錯誤
+fn identify(req: &Request) -> Option<(String, RateLimits, Option<ProxyKey>)> {
+    let defaults = crate::settings::get().rate_limit;
+    let credential = request_credential(req).ok()?;
+    let limits = credential.key.as_ref()
+        .and_then(|key| key.rate_limit)
+        .map_or(defaults, |limits| limits.or(defaults));
+    Some((credential.client_id, limits, credential.key))
+}
+
+/// 從請求內容估計的用量
+#[derive(Default)]
+pub(crate) struct Estimate {
+    pub(crate) model: Option<String>,
+    /// 以內容大小估計的輸入 token 數
+    pub(crate) input_tokens: f64,
+    /// 每個回應的輸出上限，未指定時為 0
+    pub(crate) max_output: f64,
+    /// 每個 prompt 的回應數量 n
+    pub(crate) n: u64,
+    /// 會向 Poe 發出的請求數：n 乘以 prompt 數量
+    pub(crate) queries: u64,
+}
+
+impl Estimate {
+    /// 每次請求都會送出完整輸入，每個回應都可能輸出到上限
+    pub(crate) fn tokens(&self) -> f64 {
+        self.input_tokens * self.n as f64 + self.max_output * self.queries as f64
+    }
+}
+
+/// 從請求內容取得模型名稱，並以內容大小加上輸出上限估計 token 數
+pub(crate) async fn estimate_request(req: &mut Request) -> Estimate {
+    let settings = crate::settings::get();
+    match req.payload_with_max_size(settings.server.max_request_size).await {
+        Ok(payload) => estimate(payload, settings.limits.max_choices as u64),
+        Err(_) => Estimate::default(),
+    }
+}
+
+/// 回應數量超過上限的請求會被端點拒絕，估計時以上限計算
+fn estimate(payload: &[u8], max_choices: u64) -> Estimate {
+    let Ok(body) = serde_json::from_slice::<Value>(payload) else {
+        return Estimate::default();
+    };
+    let model = body.get("model").and_then(Value::as_str).map(str::to_string);
+    let max_output = ["max_tokens", "max_completion_tokens", "max_output_tokens"]
+        .iter()
+        .find_map(|field| body.get(*field).and_then(Value::as_u64))
+        .or_else(|| body.pointer("/options/num_predict").and_then(Value::as_u64))
+        .unwrap_or(0);
+    let n = body.get("n").and_then(Value::as_u64).unwrap_or(1).clamp(1, max_choices.max(1));
+    let prompts = body.get("prompt").and_then(Value::as_array).map_or(1, |prompts| prompts.len().max(1) as u64);
+    Estimate {
+        model,
+        input_tokens: (payload.len() / 4) as f64,
+        max_output: max_output as f64,
+        n,
+        queries: (n * prompts).min(max_choices.max(1)),
+    }
+}
+
+/// 模型設定注入的 system 提示也會送到上游，以相同方式估計其 token 數
+pub(crate) fn system_prompt_tokens(model_config: &ModelConfig) -> f64 {
+    model_config.system_prompt.as_ref().map_or(0.0, |system_prompt| (system_prompt.content.len() / 4) as f64)
+}
+
+/// 聊天相關端點的速率限制中間件
+#[handler]
+pub(crate) async fn rate_limit(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    let Some((client_id, key_limits, key)) = identify(req) else {
+        // 金鑰無效的請求不受限制，由端點回應驗證錯誤
+        RateLimitHeaders::default().apply(res);
+        return;
+    };
+    let mut estimate = estimate_request(req).await;
+
+    let mut checks = vec![(format!("key:{}", client_id), key_limits)];
+    if let Some(model) = estimate.model.take() {
+        let config = crate::config::current();
+        let access = key_access(&config, key.as_ref());
+        let config_key = resolve_for(&config, &model, access.as_ref()).config_key;
+        if let Some(model_config) = config.models.get(&config_key) {
+            estimate.input_tokens += system_prompt_tokens(model_config);
+            if let Some(limits) = model_config.rate_limit {
+                checks.push((format!("model:{}:{}", client_id, config_key), limits));
+            }
+        }
+    }
+    checks.retain(|(_, limits)| !limits.is_unlimited());
+    if checks.is_empty() {
+        RateLimitHeaders::default().apply(res);
+        return;
+    }
+
+    let estimated_tokens = estimate.tokens();
+    let (result, headers) = acquire(&checks, estimated_tokens);
+    headers.apply(res);
+    match result {
+        Ok(permit) => {
+            debug!("🚦 速率限制通過 | 客戶端: {} | 估計 token: {}", client_id, estimated_tokens);
+            res.extensions_mut().insert(permit);
+            ctrl.call_next(req, depot, res).await;
+            // 非串流回應在此釋放名額，串流回應的名額已由 hold_permit 移入串流
+            res.extensions_mut().remove::<StreamPermit>();
+        },
+        Err(rejection) => {
+            let retry_after = rejection.retry_after_secs();
+            warn!("🚦 超過速率限制 | 客戶端: {} | {} | {} 秒後重試", client_id, rejection.message, retry_after);
+            res.status_code(StatusCode::TOO_MANY_REQUESTS);
+            res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            res.render(Json(OpenAIErrorResponse {
+                error: OpenAIError {
+                    message: rejection.message,
+                    r#type: rejection.kind.to_string(),
+                    code: "rate_limit_exceeded".to_string(),
+                    param: None,
+                }
+            }));
+            ctrl.skip_rest();
+        },
+    }
+}
+
+/// 將並行名額移入串流，串流結束或客戶端中斷連線時才釋放
+pub(crate) fn hold_permit<S: Stream>(res: &mut Response, stream: S) -> impl Stream<Item = S::Item> {
+    let permit = res.extensions_mut().remove::<StreamPermit>();
+    stream.map(move |item| {
+        let _ = &permit;
+        item
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(requests: Option<u32>, tokens: Option<u32>, concurrent: Option<u32>) -> RateLimits {
+        RateLimits {
+            requests_per_minute: requests,
+            tokens_per_minute: tokens,
+            concurrent_streams: concurrent,
+        }
+    }
+
+    fn rejection(retry_after: Duration) -> Rejection {
+        Rejection { kind: "requests", message: String::new(), retry_after }
+    }
+
+    #[test]
+    fn bucket_refills_at_capacity_per_minute() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(60, start);
+        bucket.take(60.0);
+        assert_eq!(bucket.wait_for(1.0), Duration::from_secs(1));
+
+        bucket.refill(60, start + Duration::from_secs(10));
+        assert!((bucket.tokens - 10.0).abs() < 1e-9);
+        assert!(bucket.wait_for(10.0).is_zero());
+        assert_eq!(bucket.wait_for(20.0), Duration::from_secs(10));
+        assert_eq!(bucket.reset_after(), Duration::from_secs(50));
+
+        // 補充不會超過容量
+        bucket.refill(60, start + Duration::from_secs(600));
+        assert_eq!(bucket.tokens, 60.0);
+        assert!(bucket.is_full(start + Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn bucket_requests_larger_than_capacity_wait_until_full() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(120, start);
+        bucket.take(1000.0);
+        assert_eq!(bucket.tokens, 0.0);
+        assert_eq!(bucket.wait_for(1000.0), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn bucket_uses_the_new_capacity_after_the_limit_changes() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(60, start);
+        bucket.refill(30, start);
+        assert_eq!(bucket.capacity, 30.0);
+        assert_eq!(bucket.tokens, 30.0);
+    }
+
+    #[test]
+    fn acquire_rejects_without_charging_any_scope() {
+        let checks = vec![
+            ("test:acquire-key".to_string(), limits(Some(10), None, None)),
+            ("test:acquire-model".to_string(), limits(None, Some(100), None)),
+        ];
+        let (result, headers) = acquire(&checks, 80.0);
+        assert!(result.is_ok());
+        assert_eq!(headers.requests.map(|(_, remaining, _)| remaining), Some(9));
+        assert_eq!(headers.tokens.map(|(_, remaining, _)| remaining), Some(20));
+
+        // 模型的 token 不足時整個請求被拒絕，金鑰的請求數也不扣除
+        let (result, headers) = acquire(&checks, 80.0);
+        let rejection = result.err().unwrap();
+        assert_eq!(rejection.kind, "tokens");
+        assert!(rejection.retry_after > Duration::from_secs(30));
+        assert_eq!(headers.requests.map(|(_, remaining, _)| remaining), Some(9));
+    }
+
+    #[test]
+    fn acquire_reports_the_longest_wait() {
+        let key = ("test:longest-key".to_string(), limits(Some(1), None, None));
+        let model = ("test:longest-model".to_string(), limits(Some(2), None, None));
+        assert!(acquire(std::slice::from_ref(&model), 0.0).0.is_ok());
+        let checks = vec![key, model];
+        assert!(acquire(&checks, 0.0).0.is_ok());
+        // 兩組都沒有剩餘時，以每分鐘 1 次的金鑰限制需要等待最久
+        let rejection = acquire(&checks, 0.0).0.err().unwrap();
+        assert!(rejection.retry_after > Duration::from_secs(45));
+        assert_eq!(rejection.retry_after_secs(), 60);
+    }
+
+    #[test]
+    fn concurrent_permit_is_released_when_dropped() {
+        let checks = vec![("test:concurrent".to_string(), limits(None, None, Some(1)))];
+        let permit = acquire(&checks, 0.0).0.ok().unwrap();
+        let copy = permit.clone();
+        assert!(acquire(&checks, 0.0).0.is_err());
+        drop(permit);
+        // 串流持有的複本仍佔用名額
+        assert!(acquire(&checks, 0.0).0.is_err());
+        drop(copy);
+        assert!(acquire(&checks, 0.0).0.is_ok());
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        assert_eq!(rejection(Duration::from_millis(10)).retry_after_secs(), 1);
+        assert_eq!(rejection(Duration::ZERO).retry_after_secs(), 1);
+        assert_eq!(rejection(Duration::from_millis(1500)).retry_after_secs(), 2);
+        assert_eq!(rejection(Duration::from_secs(60)).retry_after_secs(), 60);
+    }
+
+    #[test]
+    fn reset_uses_the_openai_format() {
+        assert_eq!(format_reset(Duration::from_millis(20)), "20ms");
+        assert_eq!(format_reset(Duration::from_millis(1200)), "2s");
+        assert_eq!(format_reset(Duration::from_secs(360)), "6m0s");
+    }
+
+    #[test]
+    fn estimate_scales_with_choices_and_prompts() {
+        let payload = br#"{"model":"gpt-4o","max_tokens":100,"n":2,"prompt":["a","b","c"]}"#;
+        let result = estimate(payload, 4);
+        assert_eq!(result.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(result.n, 2);
+        // 2 x 3 個回應超過上限時以上限計算
+        assert_eq!(result.queries, 4);
+        assert_eq!(result.tokens(), result.input_tokens * 2.0 + 400.0);
+
+        assert_eq!(estimate(b"not json", 4).queries, 0);
+    }
+}
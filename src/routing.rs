@@ -0,0 +1,678 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tracing::{debug, warn};
+
+use crate::keys::ProxyKey;
+use crate::types::{Config, ModelAccess, ModelConfig, ModelRule, StickyBy, TrafficSplit};
+
+/// 解析後的模型：對外顯示的名稱、實際請求的 Poe bot，以及套用參數時使用的 models.yaml 項目
+pub(crate) struct ResolvedModel {
+    pub(crate) display: String,
+    pub(crate) bot: String,
+    pub(crate) config_key: String,
+}
+
+/// 發出請求的客戶端，用於固定分流結果及檢查模型存取權限
+pub(crate) struct Caller<'a> {
+    pub(crate) client_id: &'a str,
+    pub(crate) user: Option<&'a str>,
+    pub(crate) access: Option<&'a ModelAccess>,
+}
+
+/// 公開名稱對應的 models.yaml 項目
+#[derive(Clone)]
+struct IndexEntry {
+    config_key: String,
+    bot: String,
+}
+
+#[derive(Clone)]
+struct CompiledRule {
+    regex: Regex,
+    target: String,
+}
+
+/// 建立索引時的錯誤，key 為出錯的模型項目名稱或 `rules`
+pub(crate) struct IndexError {
+    pub(crate) key: String,
+    pub(crate) message: String,
+}
+
+impl std::fmt::Display for IndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// 由 models.yaml 建立的查詢索引，公開名稱一律以小寫儲存
+#[derive(Clone, Default)]
+pub(crate) struct ModelIndex {
+    names: HashMap<String, IndexEntry>,
+    rules: Vec<CompiledRule>,
+}
+
+impl ModelIndex {
+    /// 建立索引；公開名稱重複或規則無效時回傳所有錯誤
+    pub(crate) fn build(config: &Config) -> Result<Self, Vec<IndexError>> {
+        let mut errors = Vec::new();
+        let mut names: HashMap<String, IndexEntry> = HashMap::new();
+
+        // 依名稱排序，讓錯誤訊息的順序固定
+        let mut entries: Vec<(&String, &ModelConfig)> = config.models.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        for (config_key, model_config) in entries {
+            let bot = model_config.target.clone().unwrap_or_else(|| config_key.clone());
+            for name in public_names(config_key, model_config) {
+                let entry = IndexEntry {
+                    config_key: config_key.clone(),
+                    bot: bot.clone(),
+                };
+                match names.get(&name.to_lowercase()) {
+                    Some(existing) if existing.config_key != *config_key => {
+                        errors.push(IndexError {
+                            key: config_key.clone(),
+                            message: format!(
+                                "公開名稱 `{}` 同時對應到 `{}` 與 `{}`",
+                                name, existing.config_key, config_key
+                            ),
+                        });
+                    },
+                    Some(_) => {},
+                    None => {
+                        names.insert(name.to_lowercase(), entry);
+                    },
+                }
+            }
+        }
+
+        let rules = config.rules.iter()
+            .enumerate()
+            .filter_map(|(index, rule)| match compile_rule(rule) {
+                Ok(regex) => Some(CompiledRule {
+                    regex,
+                    target: rule.target.clone(),
+                }),
+                Err(e) => {
+                    errors.push(IndexError {
+                        key: "rules".to_string(),
+                        message: format!("規則 #{} 無效: {}", index + 1, e),
+                    });
+                    None
+                },
+            })
+            .collect();
+
+        if errors.is_empty() {
+            debug!("🗂️ 建立模型索引 | 公開名稱數量: {} | 規則數量: {}", names.len(), config.rules.len());
+            Ok(Self { names, rules })
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// models.yaml 項目對外公開的名稱：mapping（或項目名稱本身）加上 aliases
+pub(crate) fn public_names(config_key: &str, model_config: &ModelConfig) -> Vec<String> {
+    let mut names = vec![model_config.mapping.clone().unwrap_or_else(|| config_key.to_string())];
+    names.extend(model_config.aliases.iter().flatten().cloned());
+    names
+}
+
+fn compile_rule(rule: &ModelRule) -> Result<Regex, String> {
+    let pattern = match (&rule.pattern, &rule.regex) {
+        (Some(glob), None) => format!("^{}$", glob_to_regex(glob)),
+        (None, Some(regex)) => regex.clone(),
+        _ => return Err("pattern 與 regex 必須擇一設定".to_string()),
+    };
+    Regex::new(&format!("(?i){}", pattern)).map_err(|e| e.to_string())
+}
+
+/// 不分大小寫比對整個名稱的 glob
+pub(crate) fn glob_regex(glob: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("(?i)^{}$", glob_to_regex(glob)))
+}
+
+/// 將 glob（`*`、`?`）轉為正規表示式
+fn glob_to_regex(glob: &str) -> String {
+    glob.chars()
+        .map(|c| match c {
+            '*' => ".*".to_string(),
+            '?' => ".".to_string(),
+            c => regex::escape(&c.to_string()),
+        })
+        .collect()
+}
+
+/// 尋找請求模型對應的 Poe bot：先查索引，再依序比對規則，都沒有時直接使用原始名稱
+pub(crate) fn resolve_model(config: &Config, requested_model: &str) -> ResolvedModel {
+    let identity = || ResolvedModel {
+        display: requested_model.to_string(),
+        bot: requested_model.to_string(),
+        config_key: requested_model.to_string(),
+    };
+
+    if !config.enable.unwrap_or(false) {
+        // 配置未啟用，直接使用原始名稱
+        return identity();
+    }
+
+    if let Some(entry) = config.index.names.get(&requested_model.to_lowercase()) {
+        debug!("🔄 模型映射: {} -> {} (設定: {})", requested_model, entry.bot, entry.config_key);
+        return ResolvedModel {
+            display: requested_model.to_string(),
+            bot: entry.bot.clone(),
+            config_key: entry.config_key.clone(),
+        };
+    }
+
+    for rule in &config.index.rules {
+        if let Some(captures) = rule.regex.captures(requested_model) {
+            let mut bot = String::new();
+            captures.expand(&rule.target, &mut bot);
+            debug!("🔄 規則映射: {} -> {} (規則: {})", requested_model, bot, rule.regex.as_str());
+            return ResolvedModel {
+                display: requested_model.to_string(),
+                config_key: bot.clone(),
+                bot,
+            };
+        }
+    }
+
+    // 完全沒有相關配置，使用原始名稱
+    identity()
+}
+
+/// 解析請求的模型並依權重分流，回傳解析結果及 fallback 順序；金鑰無權使用該模型時回傳錯誤訊息
+pub(crate) fn route(config: &Config, requested_model: &str, caller: &Caller) -> Result<(ResolvedModel, Vec<String>), String> {
+    let private = caller.access.is_some_and(|access| access.private_target(requested_model).is_some());
+    let mut resolved = resolve_for(config, requested_model, caller.access);
+    if let Some(access) = caller.access.filter(|_| !private) {
+        if !access.permits_route(requested_model, &resolved.config_key, &resolved.bot) {
+            return Err(format!("模型 `{}` 不存在或此金鑰無權使用", requested_model));
+        }
+    }
+
+    let split = config.models.get(&resolved.config_key)
+        .and_then(|model_config| model_config.routing.as_ref())
+        .filter(|_| config.enable.unwrap_or(false));
+    if let Some(split) = split {
+        // 只在金鑰可使用的目標之間分流，避免分到被禁止的 bot
+        let permitted = |bot: &str| private
+            || caller.access.is_none_or(|access| access.permits_route(requested_model, &resolved.config_key, bot));
+        match pick_weighted(config, split, caller, permitted) {
+            Some(bot) => {
+                debug!("⚖️ 權重分流: {} -> {}", requested_model, bot);
+                resolved.bot = bot;
+            },
+            None if split.targets.iter().any(|target| target.weight > 0) => {
+                return Err(format!("模型 `{}` 不存在或此金鑰無權使用", requested_model));
+            },
+            None => {},
+        }
+    }
+    let mut chain = fallback_chain(config, &resolved);
+    if let Some(access) = caller.access.filter(|_| !private) {
+        // 回答的 bot 已在上面檢查過；fallback 套用相同規則，避免藉此使用被禁止的 bot
+        let fallbacks = chain.split_off(1);
+        chain.extend(fallbacks.into_iter().filter(|bot| access.permits_route(requested_model, &resolved.config_key, bot)));
+    }
+    Ok((resolved, chain))
+}
+
+/// 先套用金鑰的私有對應再解析模型，對外仍顯示請求的名稱
+pub(crate) fn resolve_for(config: &Config, requested_model: &str, access: Option<&ModelAccess>) -> ResolvedModel {
+    match access.and_then(|access| access.private_target(requested_model)) {
+        Some(target) => {
+            debug!("🔐 私有模型對應: {} -> {}", requested_model, target);
+            let mut resolved = resolve_model(config, target);
+            resolved.display = requested_model.to_string();
+            resolved
+        },
+        None => resolve_model(config, requested_model),
+    }
+}
+
+/// 代理金鑰的模型存取設定：群組設定在下，金鑰本身的設定疊加其上；沒有任何限制時回傳 None
+pub(crate) fn key_access(config: &Config, key: Option<&ProxyKey>) -> Option<ModelAccess> {
+    let key = key?;
+    let group = match &key.group {
+        Some(name) => match config.groups.get(name) {
+            Some(group) => Some(group.clone()),
+            None => {
+                // 找不到群組時拒絕所有模型，避免設定錯誤時開放全部
+                warn!("⚠️ 代理金鑰 {} 的群組 `{}` 不存在，拒絕所有模型", key.id, name);
+                Some(ModelAccess {
+                    deny: vec!["*".to_string()],
+                    ..ModelAccess::default()
+                })
+            },
+        },
+        None => None,
+    };
+    match (group, key.access.clone()) {
+        (None, None) => None,
+        (Some(access), None) | (None, Some(access)) => Some(access),
+        (Some(mut group), Some(own)) => {
+            if !own.allow.is_empty() {
+                group.allow = own.allow;
+            }
+            group.deny.extend(own.deny);
+            group.mappings.extend(own.mappings);
+            Some(group)
+        },
+    }
+}
+
+/// ModelAccess 編譯後的 glob；複製時不沿用，讓合併後的設定重新編譯
+#[derive(Default)]
+pub(crate) struct AccessPatterns(OnceLock<(Vec<Regex>, Vec<Regex>)>);
+
+impl Clone for AccessPatterns {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl std::fmt::Debug for AccessPatterns {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AccessPatterns")
+    }
+}
+
+impl ModelAccess {
+    /// 任一名稱符合 allow（未設定時視為符合）且沒有名稱符合 deny
+    pub(crate) fn permits(&self, names: &[&str]) -> bool {
+        let (allow, deny) = self.patterns.0.get_or_init(|| {
+            // 無效的 glob 不符合任何名稱
+            let compile = |patterns: &[String]| patterns.iter().filter_map(|pattern| glob_regex(pattern).ok()).collect::<Vec<_>>();
+            (compile(&self.allow), compile(&self.deny))
+        });
+        let matches = |regexes: &[Regex]| regexes.iter().any(|regex| names.iter().any(|name| regex.is_match(name)));
+        !matches(deny) && (self.allow.is_empty() || matches(allow))
+    }
+
+    /// 請求某個名稱時能否由指定的 bot 回答：請求的名稱或 bot 符合 allow 即可，
+    /// 但三者任一符合 deny 就拒絕；主要模型、分流目標及 fallback 都用這個規則
+    pub(crate) fn permits_route(&self, requested_model: &str, config_key: &str, bot: &str) -> bool {
+        self.permits(&[requested_model, config_key, bot])
+    }
+
+    /// 私有對應的目標，名稱不分大小寫
+    pub(crate) fn private_target(&self, requested_model: &str) -> Option<&str> {
+        self.mappings.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(requested_model))
+            .map(|(_, target)| target.as_str())
+    }
+}
+
+/// 依權重從 permitted 允許的目標中選出上游 bot；有設定 sticky 時同一個金鑰或使用者固定分到相同的 bot
+fn pick_weighted(config: &Config, split: &TrafficSplit, caller: &Caller, permitted: impl Fn(&str) -> bool) -> Option<String> {
+    let targets: Vec<(String, u64)> = split.targets.iter()
+        .filter(|target| target.weight > 0)
+        .map(|target| (resolve_model(config, &target.model).bot, u64::from(target.weight)))
+        .filter(|(bot, _)| permitted(bot))
+        .collect();
+    let total: u64 = targets.iter().map(|(_, weight)| weight).sum();
+    if total == 0 {
+        return None;
+    }
+    let sticky_value = match split.sticky {
+        Some(StickyBy::Key) => Some(caller.client_id),
+        Some(StickyBy::User) => caller.user,
+        None => None,
+    };
+    let point = match sticky_value {
+        Some(value) => stable_hash(value) % total,
+        None => rand::random::<u64>() % total,
+    };
+
+    let mut upper = 0;
+    targets.into_iter()
+        .find(|(_, weight)| {
+            upper += weight;
+            point < upper
+        })
+        .map(|(bot, _)| bot)
+}
+
+/// FNV-1a，重新啟動後結果不變，讓 sticky 分配保持一致
+fn stable_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// 回答請求的 bot 清單：解析出的 bot 在前，接著是該模型設定的 fallbacks（可使用任何公開名稱）
+fn fallback_chain(config: &Config, resolved: &ResolvedModel) -> Vec<String> {
+    let mut chain = vec![resolved.bot.clone()];
+    let fallbacks = config.models.get(&resolved.config_key)
+        .and_then(|model_config| model_config.fallbacks.as_ref());
+    for fallback in fallbacks.into_iter().flatten() {
+        let bot = resolve_model(config, fallback).bot;
+        if !chain.contains(&bot) {
+            chain.push(bot);
+        }
+    }
+    if chain.len() > 1 {
+        debug!("🛟 fallback 順序: {}", chain.join(" -> "));
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const CONFIG: &str = r#"
+enable: true
+models:
+  smart:
+    routing:
+      targets:
+        - model: cheap-bot
+          weight: 80
+        - model: o1-pro
+          weight: 20
+  gpt-4o:
+    fallbacks: [o1-pro, claude-3-haiku]
+  assistant:
+    target: gpt-4o-mini
+    fallbacks: [o1-pro, claude-3-haiku]
+groups:
+  basic:
+    allow: ["gpt-*", "smart"]
+    deny: ["o1-pro"]
+    mappings:
+      team-model: claude-3-opus
+"#;
+
+    fn config() -> Config {
+        let mut config: Config = serde_yaml::from_str(CONFIG).expect("測試配置無效");
+        config.index = ModelIndex::build(&config).unwrap_or_else(|_| panic!("測試配置的索引無效"));
+        config
+    }
+
+    fn key(extra: serde_json::Value) -> ProxyKey {
+        let mut value = json!({
+            "id": "key-1",
+            "name": "test",
+            "hash": "",
+            "prefix": "sk-p2o-test",
+            "created_at": 0,
+        });
+        value.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(value).expect("測試金鑰無效")
+    }
+
+    fn access(value: serde_json::Value) -> ModelAccess {
+        serde_json::from_value(value).expect("測試存取設定無效")
+    }
+
+    fn route_as(config: &Config, model: &str, client_id: &str, access: Option<&ModelAccess>) -> Result<(ResolvedModel, Vec<String>), String> {
+        let caller = Caller { client_id, user: None, access };
+        route(config, model, &caller)
+    }
+
+    fn build(yaml: &str) -> Result<ModelIndex, Vec<IndexError>> {
+        let config: Config = serde_yaml::from_str(yaml).expect("測試配置無效");
+        ModelIndex::build(&config)
+    }
+
+    fn messages(errors: Vec<IndexError>) -> Vec<(String, String)> {
+        errors.into_iter().map(|error| (error.key, error.message)).collect()
+    }
+
+    #[test]
+    fn index_rejects_duplicate_public_names() {
+        let errors = build(r#"
+models:
+  a:
+    mapping: GPT-4o
+  b:
+    mapping: gpt-4o
+"#).err().unwrap();
+        assert_eq!(messages(errors), vec![(
+            "b".to_string(),
+            "公開名稱 `gpt-4o` 同時對應到 `a` 與 `b`".to_string(),
+        )]);
+    }
+
+    #[test]
+    fn index_rejects_aliases_that_collide_with_another_mapping() {
+        let errors = build(r#"
+models:
+  a:
+    aliases: [fast]
+  b:
+    mapping: FAST
+  c:
+    aliases: [a]
+"#).err().unwrap();
+        let keys: Vec<String> = errors.into_iter().map(|error| error.key).collect();
+        assert_eq!(keys, vec!["b".to_string(), "c".to_string()]);
+    }
+
+    #[test]
+    fn index_allows_repeating_a_name_within_the_same_model() {
+        let index = build(r#"
+models:
+  a:
+    mapping: fast
+    aliases: [FAST, quick]
+"#).ok().unwrap();
+        assert_eq!(index.names.len(), 2);
+        assert_eq!(index.names["quick"].config_key, "a");
+    }
+
+    #[test]
+    fn index_reports_every_invalid_rule() {
+        let errors = build(r#"
+models:
+  a:
+    mapping: x
+  b:
+    mapping: x
+rules:
+  - pattern: "gpt-*"
+    target: ok
+  - pattern: "claude-*"
+    regex: "^claude"
+    target: both
+  - target: neither
+  - regex: "(unclosed"
+    target: invalid
+"#).err().unwrap();
+        let keys: Vec<String> = errors.iter().map(|error| error.key.clone()).collect();
+        assert_eq!(keys, vec!["b", "rules", "rules", "rules"]);
+        assert!(errors[1].message.starts_with("規則 #2"));
+        assert!(errors[3].message.starts_with("規則 #4"));
+    }
+
+    #[test]
+    fn aliases_take_precedence_over_matching_rules() {
+        let mut config: Config = serde_yaml::from_str(r#"
+enable: true
+models:
+  claude-3-haiku:
+    aliases: [claude-fast]
+rules:
+  - pattern: "claude-*"
+    target: Claude-3.5-Sonnet
+  - regex: '^llama-(\d+)$'
+    target: Llama-$1-70B
+"#).expect("測試配置無效");
+        config.index = ModelIndex::build(&config).ok().unwrap();
+        assert_eq!(resolve_model(&config, "Claude-Fast").bot, "claude-3-haiku");
+        assert_eq!(resolve_model(&config, "claude-3-haiku").config_key, "claude-3-haiku");
+        assert_eq!(resolve_model(&config, "claude-other").bot, "Claude-3.5-Sonnet");
+        assert_eq!(resolve_model(&config, "llama-3").bot, "Llama-3-70B");
+        assert_eq!(resolve_model(&config, "unknown").bot, "unknown");
+    }
+
+    #[test]
+    fn permits_without_allow_accepts_everything_not_denied() {
+        let access = access(json!({ "deny": ["o1-*"] }));
+        assert!(access.permits(&["gpt-4o"]));
+        assert!(!access.permits(&["o1-pro"]));
+        assert!(!access.permits(&["O1-PRO"]));
+        // 任一名稱被拒絕就不允許
+        assert!(!access.permits(&["smart", "o1-pro"]));
+    }
+
+    #[test]
+    fn permits_requires_any_name_to_match_allow() {
+        let access = access(json!({ "allow": ["gpt-*", "claude-?-haiku"] }));
+        assert!(access.permits(&["GPT-4o"]));
+        assert!(access.permits(&["claude-3-haiku"]));
+        assert!(!access.permits(&["claude-3-opus"]));
+        assert!(access.permits(&["my-alias", "gpt-4o-mini"]));
+        assert!(!access.permits(&[]));
+    }
+
+    #[test]
+    fn permits_deny_overrides_allow() {
+        let access = access(json!({ "allow": ["*"], "deny": ["o1-pro"] }));
+        assert!(access.permits(&["gpt-4o"]));
+        assert!(!access.permits(&["o1-pro"]));
+    }
+
+    #[test]
+    fn key_access_without_restrictions_is_none() {
+        let config = config();
+        assert!(key_access(&config, None).is_none());
+        assert!(key_access(&config, Some(&key(json!({})))).is_none());
+    }
+
+    #[test]
+    fn key_access_uses_group_settings() {
+        let config = config();
+        let access = key_access(&config, Some(&key(json!({ "group": "basic" })))).unwrap();
+        assert!(access.permits(&["gpt-4o"]));
+        assert!(!access.permits(&["o1-pro"]));
+        assert!(!access.permits(&["claude-3-opus"]));
+        assert_eq!(access.private_target("TEAM-MODEL"), Some("claude-3-opus"));
+    }
+
+    #[test]
+    fn key_access_with_unknown_group_denies_everything() {
+        let config = config();
+        let access = key_access(&config, Some(&key(json!({ "group": "missing" })))).unwrap();
+        assert!(!access.permits(&["gpt-4o"]));
+    }
+
+    #[test]
+    fn key_access_merges_key_settings_over_group() {
+        let config = config();
+        let key = key(json!({
+            "group": "basic",
+            "access": {
+                "allow": ["claude-*"],
+                "deny": ["claude-3-opus"],
+                "mappings": { "mine": "gpt-4o" },
+            },
+        }));
+        let access = key_access(&config, Some(&key)).unwrap();
+        // 金鑰的 allow 取代群組的 allow
+        assert!(access.permits(&["claude-3-haiku"]));
+        assert!(!access.permits(&["gpt-4o"]));
+        // deny 與 mappings 會合併
+        assert!(!access.permits(&["claude-3-opus"]));
+        assert!(!access.permits(&["o1-pro"]));
+        assert_eq!(access.private_target("team-model"), Some("claude-3-opus"));
+        assert_eq!(access.private_target("mine"), Some("gpt-4o"));
+    }
+
+    #[test]
+    fn key_access_keeps_group_allow_when_key_has_none() {
+        let config = config();
+        let key = key(json!({ "group": "basic", "access": { "deny": ["gpt-4o"] } }));
+        let access = key_access(&config, Some(&key)).unwrap();
+        assert!(access.permits(&["gpt-4o-mini"]));
+        assert!(!access.permits(&["gpt-4o"]));
+    }
+
+    #[test]
+    fn split_reaches_every_target_without_restrictions() {
+        let config = config();
+        let bots: Vec<String> = (0..500)
+            .map(|index| route_as(&config, "smart", &format!("client-{}", index), None).unwrap().0.bot)
+            .collect();
+        assert!(bots.iter().any(|bot| bot == "o1-pro"));
+        assert!(bots.iter().any(|bot| bot == "cheap-bot"));
+    }
+
+    #[test]
+    fn split_never_routes_to_a_denied_target() {
+        let config = config();
+        let access = access(json!({ "deny": ["o1-pro"] }));
+        for index in 0..500 {
+            let (resolved, chain) = route_as(&config, "smart", &format!("client-{}", index), Some(&access)).unwrap();
+            assert_eq!(resolved.bot, "cheap-bot");
+            assert_eq!(chain, vec!["cheap-bot".to_string()]);
+        }
+    }
+
+    #[test]
+    fn split_is_rejected_when_every_target_is_denied() {
+        let config = config();
+        let access = access(json!({ "deny": ["o1-pro", "cheap-bot"] }));
+        assert!(route_as(&config, "smart", "client", Some(&access)).is_err());
+    }
+
+    #[test]
+    fn split_allows_targets_through_the_requested_name() {
+        let config = config();
+        let access = access(json!({ "allow": ["smart"] }));
+        let (resolved, _) = route_as(&config, "smart", "client", Some(&access)).unwrap();
+        assert!(resolved.bot == "cheap-bot" || resolved.bot == "o1-pro");
+    }
+
+    #[test]
+    fn denied_fallbacks_are_removed_from_the_chain() {
+        let config = config();
+        let access = access(json!({ "deny": ["o1-pro"] }));
+        let (_, chain) = route_as(&config, "gpt-4o", "client", Some(&access)).unwrap();
+        assert_eq!(chain, vec!["gpt-4o".to_string(), "claude-3-haiku".to_string()]);
+
+        let (_, chain) = route_as(&config, "gpt-4o", "client", None).unwrap();
+        assert_eq!(chain.len(), 3);
+    }
+
+    #[test]
+    fn virtual_name_allow_covers_fallbacks_except_denied_bots() {
+        let config = config();
+        let allowed = access(json!({ "allow": ["assistant"] }));
+        let (resolved, chain) = route_as(&config, "assistant", "client", Some(&allowed)).unwrap();
+        assert_eq!(resolved.bot, "gpt-4o-mini");
+        assert_eq!(chain, vec!["gpt-4o-mini".to_string(), "o1-pro".to_string(), "claude-3-haiku".to_string()]);
+
+        let denied = access(json!({ "allow": ["assistant"], "deny": ["o1-pro"] }));
+        let (_, chain) = route_as(&config, "assistant", "client", Some(&denied)).unwrap();
+        assert_eq!(chain, vec!["gpt-4o-mini".to_string(), "claude-3-haiku".to_string()]);
+    }
+
+    #[test]
+    fn denied_model_is_rejected() {
+        let config = config();
+        let access = access(json!({ "allow": ["gpt-*"] }));
+        assert!(route_as(&config, "claude-3-opus", "client", Some(&access)).is_err());
+        assert!(route_as(&config, "gpt-4o", "client", Some(&access)).is_ok());
+    }
+
+    #[test]
+    fn private_mapping_bypasses_allow_and_deny() {
+        let config = config();
+        let access = access(json!({
+            "allow": ["gpt-*"],
+            "deny": ["claude-*"],
+            "mappings": { "team-model": "claude-3-opus" },
+        }));
+        let (resolved, chain) = route_as(&config, "team-model", "client", Some(&access)).unwrap();
+        assert_eq!(resolved.display, "team-model");
+        assert_eq!(resolved.bot, "claude-3-opus");
+        assert_eq!(chain, vec!["claude-3-opus".to_string()]);
+    }
+}
@@ -0,0 +1,612 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::routing::ModelIndex;
+use crate::tokenizer::Tokenizer;
+use crate::types::{Config, ParamRange, RateLimits};
+
+/// 服務的主配置：配置檔的內容再疊加環境變數
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Settings {
+    pub(crate) server: ServerSettings,
+    pub(crate) auth: AuthSettings,
+    pub(crate) models: ModelsSettings,
+    pub(crate) limits: LimitSettings,
+    pub(crate) ollama: OllamaSettings,
+    pub(crate) keys: KeySettings,
+    pub(crate) pool: PoolSettings,
+    /// 每個客戶端金鑰的預設速率上限，代理金鑰可個別覆蓋
+    pub(crate) rate_limit: RateLimits,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerSettings {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) log_level: String,
+    /// 單一請求的最大位元組數
+    pub(crate) max_request_size: usize,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            log_level: "debug".to_string(),
+            max_request_size: 1024 * 1024 * 1024, // 預設 1GB
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuthSettings {
+    pub(crate) admin_username: String,
+    /// 明文密碼，僅供本機測試；正式環境請改用 admin_password_hash
+    pub(crate) admin_password: Option<String>,
+    /// Argon2 雜湊（PHC 格式），可用 `poe2openai hash-password` 產生
+    pub(crate) admin_password_hash: Option<String>,
+    /// 存放 Argon2 雜湊的檔案，例如 Docker secret
+    pub(crate) admin_password_hash_file: Option<PathBuf>,
+    /// TOTP 共用密鑰（Base32），設定後登入需輸入驗證碼
+    pub(crate) totp_secret: Option<String>,
+    pub(crate) totp_secret_file: Option<PathBuf>,
+    /// 登入工作階段的有效秒數
+    pub(crate) session_ttl: u64,
+    /// 連續登入失敗幾次後暫時鎖定
+    pub(crate) max_login_attempts: u32,
+    /// 鎖定的秒數
+    pub(crate) lockout_duration: u64,
+    /// 只透過 HTTPS 傳送工作階段 cookie
+    pub(crate) secure_cookie: bool,
+    /// 由管理介面新增的管理員帳號
+    pub(crate) users_file: PathBuf,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            admin_username: "admin".to_string(),
+            admin_password: None,
+            admin_password_hash: None,
+            admin_password_hash_file: None,
+            totp_secret: None,
+            totp_secret_file: None,
+            session_ttl: 8 * 60 * 60,
+            max_login_attempts: 5,
+            lockout_duration: 15 * 60,
+            secure_cookie: false,
+            users_file: PathBuf::from("admin_users.json"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ModelsSettings {
+    /// models.yaml 的路徑，相對路徑以工作目錄為準
+    pub(crate) file: PathBuf,
+    /// 檢查檔案變更的間隔秒數，0 表示不自動重新載入
+    pub(crate) reload_interval: u64,
+    /// 保存每次配置變更快照的目錄
+    pub(crate) history_dir: PathBuf,
+    /// 最多保留的版本數量
+    pub(crate) history_limit: usize,
+}
+
+impl Default for ModelsSettings {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from("models.yaml"),
+            reload_interval: 2,
+            history_dir: PathBuf::from("models_history"),
+            history_limit: 100,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LimitSettings {
+    /// 單一請求允許的最大 n 值
+    pub(crate) max_choices: usize,
+    pub(crate) structured_output_max_retries: usize,
+    pub(crate) response_store_max_entries: usize,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self {
+            max_choices: 4,
+            structured_output_max_retries: 2,
+            response_store_max_entries: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OllamaSettings {
+    /// Ollama 端點未帶 Authorization 時使用的 Poe API Key
+    pub(crate) access_key: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct KeySettings {
+    /// 代理金鑰的儲存檔案
+    pub(crate) file: PathBuf,
+    /// 允許客戶端直接以 Poe API Key 呼叫（舊版行為）
+    pub(crate) pass_through: bool,
+    /// 代理金鑰用量（配額計數）的儲存檔案
+    pub(crate) usage_file: PathBuf,
+}
+
+impl Default for KeySettings {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from("keys.json"),
+            pass_through: false,
+            usage_file: PathBuf::from("usage.json"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PoolSettings {
+    /// 未指定 upstream_keys 的代理金鑰共用的 Poe API Key
+    pub(crate) keys: Vec<String>,
+    pub(crate) strategy: PoolStrategy,
+    /// 被限流後暫停使用的秒數，驗證失敗的金鑰會暫停十倍時間
+    pub(crate) cooldown: u64,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            strategy: PoolStrategy::RoundRobin,
+            cooldown: 60,
+        }
+    }
+}
+
+/// 從金鑰池選用 Poe API Key 的方式
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PoolStrategy {
+    RoundRobin,
+    /// 優先使用最久沒被限流的金鑰
+    LeastRateLimited,
+    /// 優先使用請求次數最少的金鑰
+    LeastUsed,
+}
+
+impl std::str::FromStr for PoolStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round_robin" => Ok(PoolStrategy::RoundRobin),
+            "least_rate_limited" => Ok(PoolStrategy::LeastRateLimited),
+            "least_used" => Ok(PoolStrategy::LeastUsed),
+            _ => Err("可用的值: round_robin, least_rate_limited, least_used".to_string()),
+        }
+    }
+}
+
+/// 配置錯誤，盡可能附上檔案位置
+#[derive(Debug)]
+pub(crate) struct SettingsError {
+    pub(crate) source: String,
+    pub(crate) line: Option<usize>,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.source, line, self.message),
+            None => write!(f, "{}: {}", self.source, self.message),
+        }
+    }
+}
+
+/// 命令列參數
+pub(crate) struct CliArgs {
+    pub(crate) config_path: Option<PathBuf>,
+    pub(crate) validate_only: bool,
+    /// 從標準輸入讀取密碼並輸出 Argon2 雜湊
+    pub(crate) hash_password: bool,
+}
+
+impl CliArgs {
+    /// 解析 `--config <path>`、`--config=<path>`、`validate-config` 及 `hash-password`
+    pub(crate) fn parse() -> Result<Self, String> {
+        let mut args = std::env::args().skip(1);
+        let mut cli = CliArgs {
+            config_path: None,
+            validate_only: false,
+            hash_password: false,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "validate-config" => cli.validate_only = true,
+                "hash-password" => cli.hash_password = true,
+                "--config" | "-c" => {
+                    let path = args.next().ok_or("--config 需要指定檔案路徑")?;
+                    cli.config_path = Some(PathBuf::from(path));
+                },
+                _ => match arg.strip_prefix("--config=") {
+                    Some(path) => cli.config_path = Some(PathBuf::from(path)),
+                    None => return Err(format!("未知的參數: {}", arg)),
+                },
+            }
+        }
+        // 命令列優先於環境變數
+        if cli.config_path.is_none() {
+            cli.config_path = std::env::var("POE2OPENAI_CONFIG").ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from);
+        }
+        Ok(cli)
+    }
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// 取得目前的設定；尚未載入時使用預設值
+pub(crate) fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+/// 設定全域設定，只能在啟動時呼叫一次
+pub(crate) fn install(settings: Settings) {
+    if SETTINGS.set(settings).is_err() {
+        panic!("設定已初始化");
+    }
+}
+
+impl Settings {
+    /// 讀取配置檔（YAML 或 TOML）並疊加環境變數，回傳所有發現的錯誤
+    pub(crate) fn load(path: Option<&Path>) -> Result<Self, Vec<SettingsError>> {
+        let mut errors = Vec::new();
+        let mut settings = match path {
+            Some(path) => load_file(path, &mut errors),
+            None => Settings::default(),
+        };
+        settings.apply_env(&mut errors);
+        settings.validate(&mut errors);
+        if errors.is_empty() {
+            Ok(settings)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn apply_env(&mut self, errors: &mut Vec<SettingsError>) {
+        env_override("HOST", &mut self.server.host, errors);
+        env_override("PORT", &mut self.server.port, errors);
+        env_override("LOG_LEVEL", &mut self.server.log_level, errors);
+        env_override("MAX_REQUEST_SIZE", &mut self.server.max_request_size, errors);
+        env_override("ADMIN_USERNAME", &mut self.auth.admin_username, errors);
+        env_override_optional("ADMIN_PASSWORD", &mut self.auth.admin_password, errors);
+        env_override_optional("ADMIN_PASSWORD_HASH", &mut self.auth.admin_password_hash, errors);
+        env_override_optional("ADMIN_PASSWORD_HASH_FILE", &mut self.auth.admin_password_hash_file, errors);
+        env_override_optional("ADMIN_TOTP_SECRET", &mut self.auth.totp_secret, errors);
+        env_override_optional("ADMIN_TOTP_SECRET_FILE", &mut self.auth.totp_secret_file, errors);
+        env_override("ADMIN_SESSION_TTL", &mut self.auth.session_ttl, errors);
+        env_override("ADMIN_MAX_LOGIN_ATTEMPTS", &mut self.auth.max_login_attempts, errors);
+        env_override("ADMIN_LOCKOUT_DURATION", &mut self.auth.lockout_duration, errors);
+        env_override("ADMIN_SECURE_COOKIE", &mut self.auth.secure_cookie, errors);
+        env_override("ADMIN_USERS_FILE", &mut self.auth.users_file, errors);
+        env_override("MODELS_CONFIG_PATH", &mut self.models.file, errors);
+        env_override("CONFIG_RELOAD_INTERVAL", &mut self.models.reload_interval, errors);
+        env_override("MODELS_HISTORY_DIR", &mut self.models.history_dir, errors);
+        env_override("MODELS_HISTORY_LIMIT", &mut self.models.history_limit, errors);
+        env_override("MAX_CHOICES", &mut self.limits.max_choices, errors);
+        env_override("STRUCTURED_OUTPUT_MAX_RETRIES", &mut self.limits.structured_output_max_retries, errors);
+        env_override("RESPONSE_STORE_MAX_ENTRIES", &mut self.limits.response_store_max_entries, errors);
+        env_override("KEYS_FILE", &mut self.keys.file, errors);
+        env_override("ALLOW_PASS_THROUGH", &mut self.keys.pass_through, errors);
+        env_override("USAGE_FILE", &mut self.keys.usage_file, errors);
+        env_override("POOL_STRATEGY", &mut self.pool.strategy, errors);
+        env_override("POOL_COOLDOWN", &mut self.pool.cooldown, errors);
+        if let Ok(keys) = std::env::var("POE_API_KEYS") {
+            self.pool.keys = keys.split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        env_override_optional("RATE_LIMIT_RPM", &mut self.rate_limit.requests_per_minute, errors);
+        env_override_optional("RATE_LIMIT_TPM", &mut self.rate_limit.tokens_per_minute, errors);
+        env_override_optional("RATE_LIMIT_CONCURRENT_STREAMS", &mut self.rate_limit.concurrent_streams, errors);
+        if let Ok(access_key) = std::env::var("OLLAMA_ACCESS_KEY") {
+            self.ollama.access_key = Some(access_key).filter(|key| !key.is_empty());
+        }
+    }
+
+    /// 檢查數值範圍，並驗證 models.yaml
+    fn validate(&self, errors: &mut Vec<SettingsError>) {
+        let mut invalid = |message: String| errors.push(SettingsError {
+            source: "settings".to_string(),
+            line: None,
+            message,
+        });
+        if self.server.port == 0 {
+            invalid("server.port 不可為 0".to_string());
+        }
+        if self.server.max_request_size == 0 {
+            invalid("server.max_request_size 必須大於 0".to_string());
+        }
+        if self.models.history_limit == 0 {
+            invalid("models.history_limit 必須大於 0".to_string());
+        }
+        if self.limits.max_choices == 0 {
+            invalid("limits.max_choices 必須大於 0".to_string());
+        }
+        if self.limits.response_store_max_entries == 0 {
+            invalid("limits.response_store_max_entries 必須大於 0".to_string());
+        }
+        if let Some(field) = self.rate_limit.zero_field() {
+            invalid(format!("rate_limit.{} 必須大於 0，不限制時請移除此設定", field));
+        }
+        if self.auth.session_ttl == 0 {
+            invalid("auth.session_ttl 必須大於 0".to_string());
+        }
+        if self.auth.max_login_attempts == 0 {
+            invalid("auth.max_login_attempts 必須大於 0".to_string());
+        }
+        if let Err(e) = crate::admin_auth::load(&self.auth) {
+            invalid(format!("auth: {}", e));
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.server.log_level) {
+            invalid(format!("server.log_level 無效: {}", e));
+        }
+
+        validate_models_file(&self.models.file, errors);
+    }
+}
+
+fn env_override<T: std::str::FromStr>(key: &str, target: &mut T, errors: &mut Vec<SettingsError>)
+where
+    T::Err: fmt::Display,
+{
+    let Ok(value) = std::env::var(key) else {
+        return;
+    };
+    match value.parse() {
+        Ok(parsed) => *target = parsed,
+        Err(e) => errors.push(SettingsError {
+            source: format!("環境變數 {}", key),
+            line: None,
+            message: format!("無效的值 '{}': {}", value, e),
+        }),
+    }
+}
+
+/// 空字串表示未設定
+fn env_override_optional<T: std::str::FromStr>(key: &str, target: &mut Option<T>, errors: &mut Vec<SettingsError>)
+where
+    T::Err: fmt::Display,
+{
+    let Ok(value) = std::env::var(key) else {
+        return;
+    };
+    if value.is_empty() {
+        *target = None;
+        return;
+    }
+    match value.parse() {
+        Ok(parsed) => *target = Some(parsed),
+        Err(e) => errors.push(SettingsError {
+            source: format!("環境變數 {}", key),
+            line: None,
+            message: format!("無效的值 '{}': {}", value, e),
+        }),
+    }
+}
+
+fn load_file(path: &Path, errors: &mut Vec<SettingsError>) -> Settings {
+    let source = path.display().to_string();
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            errors.push(SettingsError { source, line: None, message: format!("無法讀取配置檔: {}", e) });
+            return Settings::default();
+        }
+    };
+    let is_toml = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("toml"));
+
+    // 先解析為通用結構，語法錯誤時直接回報位置
+    let root: Value = if is_toml {
+        match toml::from_str::<toml::Value>(&contents) {
+            Ok(value) => serde_json::to_value(value).unwrap_or_default(),
+            Err(e) => {
+                let line = e.span().map(|span| line_of_offset(&contents, span.start));
+                errors.push(SettingsError { source, line, message: e.message().to_string() });
+                return Settings::default();
+            }
+        }
+    } else {
+        match serde_yaml::from_str::<serde_yaml::Value>(&contents) {
+            Ok(serde_yaml::Value::Null) => Value::Object(Default::default()),
+            Ok(value) => serde_json::to_value(value).unwrap_or_default(),
+            Err(e) => {
+                let line = e.location().map(|location| location.line());
+                errors.push(SettingsError { source, line, message: e.to_string() });
+                return Settings::default();
+            }
+        }
+    };
+
+    // 逐一檢查每個欄位，一次回報所有錯誤
+    let Value::Object(sections) = &root else {
+        errors.push(SettingsError { source, line: Some(1), message: "配置檔的最外層必須是物件".to_string() });
+        return Settings::default();
+    };
+    let error_count = errors.len();
+    for (section, value) in sections {
+        match section.as_str() {
+            "server" => check_section::<ServerSettings>(&contents, &source, section, value, errors),
+            "auth" => check_section::<AuthSettings>(&contents, &source, section, value, errors),
+            "models" => check_section::<ModelsSettings>(&contents, &source, section, value, errors),
+            "limits" => check_section::<LimitSettings>(&contents, &source, section, value, errors),
+            "ollama" => check_section::<OllamaSettings>(&contents, &source, section, value, errors),
+            "keys" => check_section::<KeySettings>(&contents, &source, section, value, errors),
+            "pool" => check_section::<PoolSettings>(&contents, &source, section, value, errors),
+            "rate_limit" => check_section::<RateLimits>(&contents, &source, section, value, errors),
+            _ => errors.push(SettingsError {
+                line: find_key_line(&contents, None, section),
+                source: source.clone(),
+                message: format!("未知的區塊 `{}`，可用的區塊: server, auth, models, limits, ollama, keys, pool, rate_limit", section),
+            }),
+        }
+    }
+    if errors.len() > error_count {
+        return Settings::default();
+    }
+
+    serde_json::from_value(root).unwrap_or_else(|e| {
+        errors.push(SettingsError { source, line: None, message: e.to_string() });
+        Settings::default()
+    })
+}
+
+/// 將區塊中的每個欄位單獨反序列化，以便取得所有錯誤
+fn check_section<T: DeserializeOwned>(
+    contents: &str,
+    source: &str,
+    section: &str,
+    value: &Value,
+    errors: &mut Vec<SettingsError>,
+) {
+    let Value::Object(fields) = value else {
+        errors.push(SettingsError {
+            source: source.to_string(),
+            line: find_key_line(contents, None, section),
+            message: format!("`{}` 必須是物件", section),
+        });
+        return;
+    };
+    for (key, field) in fields {
+        let single = Value::Object([(key.clone(), field.clone())].into_iter().collect());
+        if let Err(e) = serde_json::from_value::<T>(single) {
+            errors.push(SettingsError {
+                source: source.to_string(),
+                line: find_key_line(contents, Some(section), key),
+                message: format!("{}.{}: {}", section, key, e),
+            });
+        }
+    }
+}
+
+/// 驗證 models.yaml 的格式及各模型設定
+fn validate_models_file(path: &Path, errors: &mut Vec<SettingsError>) {
+    let source = path.display().to_string();
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        // 不存在時視為不啟用
+        return;
+    };
+    let config = match serde_yaml::from_str::<Config>(&contents) {
+        Ok(config) => config,
+        Err(e) => {
+            let line = e.location().map(|location| location.line());
+            errors.push(SettingsError { source, line, message: e.to_string() });
+            return;
+        }
+    };
+    for issue in check_models(&config) {
+        errors.push(SettingsError {
+            source: source.clone(),
+            line: find_key_line(&contents, issue.model.as_deref(), &issue.key),
+            message: issue.message,
+        });
+    }
+}
+
+/// models.yaml 中的錯誤；model 與 key 用來在原始檔案中找出行號
+pub(crate) struct ConfigIssue {
+    pub(crate) model: Option<String>,
+    pub(crate) key: String,
+    pub(crate) message: String,
+}
+
+/// 檢查 models.yaml 的內容，回傳所有錯誤
+pub(crate) fn check_models(config: &Config) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+    let mut invalid = |model: &str, key: &str, message: String| issues.push(ConfigIssue {
+        model: Some(model.to_string()),
+        key: key.to_string(),
+        message,
+    });
+    for (name, model) in &config.models {
+        let clamp = model.clamp.as_ref();
+        let temperature_range = clamp.and_then(|clamp| clamp.temperature);
+        let max_tokens_range = clamp.and_then(|clamp| clamp.max_tokens);
+        let inverted = matches!(temperature_range, Some(ParamRange { min: Some(min), max: Some(max) }) if min > max)
+            || matches!(max_tokens_range, Some(ParamRange { min: Some(min), max: Some(max) }) if min > max);
+        if inverted {
+            invalid(name, "clamp", format!("模型 {} 的 clamp 範圍無效：min 不可大於 max", name));
+        }
+        if let Some(split) = &model.routing {
+            if split.targets.iter().all(|target| target.weight == 0) {
+                invalid(name, "routing", format!("模型 {} 的 routing 至少需要一個權重大於 0 的目標", name));
+            }
+        }
+        if let Some(shadow) = &model.shadow {
+            if !(0.0..=1.0).contains(&shadow.sample_rate) {
+                invalid(name, "sample_rate", format!("模型 {} 的 shadow.sample_rate 必須介於 0 到 1 之間", name));
+            }
+        }
+        if let Some(field) = model.rate_limit.and_then(|limits| limits.zero_field()) {
+            invalid(name, field, format!("模型 {} 的 rate_limit.{} 必須大於 0", name, field));
+        }
+        if let Some(tokenizer) = &model.tokenizer {
+            if Tokenizer::from_name(tokenizer).is_none() {
+                invalid(name, "tokenizer", format!("模型 {} 的 tokenizer 無效: {}（可用: cl100k_base, o200k_base）", name, tokenizer));
+            }
+        }
+    }
+    if let Err(index_errors) = ModelIndex::build(config) {
+        issues.extend(index_errors.into_iter().map(|error| ConfigIssue {
+            model: None,
+            key: error.key,
+            message: error.message,
+        }));
+    }
+    issues
+}
+
+fn line_of_offset(contents: &str, offset: usize) -> usize {
+    contents[..offset.min(contents.len())].matches('\n').count() + 1
+}
+
+/// 在原始文字中尋找鍵的行號；有指定區塊時從該區塊之後開始尋找
+fn find_key_line(contents: &str, section: Option<&str>, key: &str) -> Option<usize> {
+    let is_key = |line: &str, key: &str| {
+        let line = line.trim_start().trim_start_matches('[').trim_start_matches(['"', '\'']);
+        line.strip_prefix(key)
+            .map(|rest| rest.trim_start_matches(['"', '\'', ']']).trim_start())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(':') || rest.starts_with('='))
+    };
+    let lines: Vec<&str> = contents.lines().collect();
+    let start = match section {
+        Some(section) => lines.iter().position(|line| is_key(line, section))?,
+        None => 0,
+    };
+    lines.iter()
+        .enumerate()
+        .skip(start + usize::from(section.is_some()))
+        .find(|(_, line)| is_key(line, key))
+        .map(|(index, _)| index + 1)
+}
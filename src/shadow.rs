@@ -0,0 +1,107 @@
+use futures_util::stream::StreamExt;
+use poe_api_process::{EventType, QueryRequest};
+use tokio::sync::oneshot;
+use tracing::{debug, info};
+
+use crate::handlers::chat::EventStream;
+use crate::poe_client::PoeClientWrapper;
+use crate::routing::resolve_model;
+use crate::types::Config;
+
+/// 依取樣比例將請求複製到影子 bot，兩邊都完成後並列記錄輸出；客戶端收到的回應不受影響
+pub(crate) fn mirror(
+    config: &Config,
+    config_key: &str,
+    primary_client: &PoeClientWrapper,
+    build_request: impl Fn() -> QueryRequest,
+    primary: EventStream,
+) -> EventStream {
+    let Some(shadow) = config.models.get(config_key).and_then(|model_config| model_config.shadow.as_ref()) else {
+        return primary;
+    };
+    if !config.enable.unwrap_or(false) || rand::random::<f64>() >= shadow.sample_rate {
+        return primary;
+    }
+
+    let primary_bot = primary_client.model();
+    let shadow_bot = resolve_model(config, &shadow.model).bot;
+    debug!("👥 複製請求至影子 bot: {} -> {}", primary_bot, shadow_bot);
+    let (primary, primary_output) = capture_text(primary);
+    let client = PoeClientWrapper::new(&shadow_bot, primary_client.access_key());
+    let query_request = build_request();
+    let primary_bot = primary_bot.to_string();
+
+    tokio::spawn(async move {
+        let shadow_output = match client.stream_request(query_request).await {
+            Ok(event_stream) => collect_text(event_stream).await,
+            Err(e) => format!("(請求失敗: {})", e),
+        };
+        let primary_output = primary_output.await.unwrap_or_else(|_| "(未完成)".to_string());
+        info!("👥 影子流量比較 | 主要: {} | 影子: {}\n[{}]\n{}\n[{}]\n{}",
+            primary_bot, shadow_bot, primary_bot, primary_output, shadow_bot, shadow_output);
+    });
+    primary
+}
+
+/// 串流結束或被丟棄時送出目前收到的文字
+struct Capture {
+    text: String,
+    sender: Option<oneshot::Sender<String>>,
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(std::mem::take(&mut self.text));
+        }
+    }
+}
+
+/// 在不改變事件的情況下記錄主要回應的文字
+fn capture_text(event_stream: EventStream) -> (EventStream, oneshot::Receiver<String>) {
+    let (sender, receiver) = oneshot::channel();
+    let mut capture = Capture {
+        text: String::new(),
+        sender: Some(sender),
+    };
+    let event_stream = event_stream.map(move |item| {
+        if let Ok(event) = &item {
+            if let Some(data) = &event.data {
+                match event.event {
+                    EventType::Text => capture.text.push_str(&data.text),
+                    EventType::ReplaceResponse => capture.text = data.text.clone(),
+                    _ => {},
+                }
+            }
+        }
+        item
+    });
+    (Box::pin(event_stream), receiver)
+}
+
+/// 讀取影子 bot 的完整輸出
+async fn collect_text(mut event_stream: EventStream) -> String {
+    let mut text = String::new();
+    while let Some(Ok(event)) = event_stream.next().await {
+        match event.event {
+            EventType::Text => {
+                if let Some(data) = event.data {
+                    text.push_str(&data.text);
+                }
+            },
+            EventType::ReplaceResponse => {
+                if let Some(data) = event.data {
+                    text = data.text;
+                }
+            },
+            EventType::Error => {
+                let message = event.error.map(|error| error.text).unwrap_or_default();
+                text.push_str(&format!("(錯誤: {})", message));
+                break;
+            },
+            EventType::Done => break,
+            _ => {},
+        }
+    }
+    text
+}
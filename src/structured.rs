@@ -0,0 +1,111 @@
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::types::*;
+use crate::utils::strip_code_fence;
+
+/// 單次請求的結構化輸出要求（response_format 為 json_object 或 json_schema）
+pub struct StructuredOutput {
+    schema: Option<Value>,
+    schema_name: Option<String>,
+    validator: Option<jsonschema::Validator>,
+}
+
+impl StructuredOutput {
+    /// 從請求中取出結構化輸出設定，schema 本身無效時回傳錯誤訊息
+    pub fn from_request(request: &ChatCompletionRequest) -> Result<Option<Self>, String> {
+        match &request.response_format {
+            None | Some(ResponseFormat::Text) => Ok(None),
+            Some(ResponseFormat::JsonObject) => {
+                debug!("🧩 啟用 JSON 物件輸出");
+                Ok(Some(Self {
+                    schema: None,
+                    schema_name: None,
+                    validator: None,
+                }))
+            },
+            Some(ResponseFormat::JsonSchema { json_schema }) => {
+                let validator = match &json_schema.schema {
+                    Some(schema) => Some(jsonschema::validator_for(schema)
+                        .map_err(|e| format!("無效的 JSON Schema ({}): {}", json_schema.name, e))?),
+                    None => None,
+                };
+                debug!("🧩 啟用 JSON Schema 輸出 | 名稱: {} | strict: {:?}", json_schema.name, json_schema.strict);
+                Ok(Some(Self {
+                    schema: json_schema.schema.clone(),
+                    schema_name: Some(json_schema.name.clone()),
+                    validator,
+                }))
+            }
+        }
+    }
+
+    /// 產生要求模型只輸出 JSON 的 system 提示
+    fn instruction(&self) -> String {
+        let mut instruction = String::from(
+            "Respond with a single valid JSON value only. Do not wrap it in markdown code fences and do not add any explanation before or after it.",
+        );
+        match &self.schema {
+            Some(schema) => {
+                instruction.push_str(&format!(
+                    "\nThe JSON must conform to the following JSON Schema{}:\n{}",
+                    self.schema_name.as_ref().map(|name| format!(" named \"{}\"", name)).unwrap_or_default(),
+                    serde_json::to_string_pretty(schema).unwrap_or_default()
+                ));
+            },
+            None => instruction.push_str("\nThe top-level value must be a JSON object."),
+        }
+        instruction
+    }
+
+    /// 將 JSON 輸出要求插入到開頭的 system 訊息之後
+    pub fn inject_instruction(&self, messages: &mut Vec<Message>) {
+        let position = messages.iter().take_while(|message| message.role == "system").count();
+        messages.insert(position, Message::instruction(self.instruction()));
+    }
+
+    /// 清理並驗證模型輸出，成功時回傳整理後的 JSON 文字，失敗時回傳錯誤說明
+    pub fn validate(&self, text: &str) -> Result<String, String> {
+        let cleaned = strip_code_fence(text);
+        let value: Value = serde_json::from_str(cleaned)
+            .map_err(|e| format!("output is not valid JSON: {}", e))?;
+
+        match &self.validator {
+            Some(validator) => {
+                let errors: Vec<String> = validator.iter_errors(&value)
+                    .map(|error| {
+                        let path = error.instance_path.to_string();
+                        if path.is_empty() {
+                            error.to_string()
+                        } else {
+                            format!("{}: {}", path, error)
+                        }
+                    })
+                    .collect();
+                if !errors.is_empty() {
+                    warn!("⚠️ JSON Schema 驗證失敗 | 錯誤數量: {}", errors.len());
+                    return Err(format!("output does not match the JSON Schema: {}", errors.join("; ")));
+                }
+            },
+            None if !value.is_object() => {
+                return Err("output must be a JSON object".to_string());
+            },
+            None => {}
+        }
+
+        Ok(cleaned.to_string())
+    }
+
+    /// 重新詢問時附加的修正提示
+    pub fn retry_instruction(error: &str) -> String {
+        format!(
+            "Your previous reply was rejected because the {}. Reply again with only the corrected JSON.",
+            error
+        )
+    }
+}
+
+/// 驗證失敗時重新詢問的最大次數
+pub fn max_retries() -> usize {
+    crate::settings::get().limits.structured_output_max_retries
+}
@@ -0,0 +1,98 @@
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+use tracing::warn;
+
+use crate::types::{Message, ModelConfig};
+
+// 每則訊息的格式開銷及回覆前綴，與 OpenAI 的計算方式一致
+const TOKENS_PER_MESSAGE: usize = 3;
+const TOKENS_REPLY_PRIMING: usize = 3;
+// 低解析度圖片的固定 token 數
+const TOKENS_PER_IMAGE: usize = 85;
+
+/// Poe 不回傳用量，以 tiktoken 編碼估算 token 數量
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tokenizer {
+    Cl100k,
+    O200k,
+}
+
+impl Tokenizer {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "cl100k" | "cl100k_base" => Some(Tokenizer::Cl100k),
+            "o200k" | "o200k_base" => Some(Tokenizer::O200k),
+            _ => None,
+        }
+    }
+
+    /// 依 ModelConfig 的 tokenizer 設定選擇編碼，未設定時依模型名稱推測
+    pub fn for_model(model: &str, model_config: Option<&ModelConfig>) -> Self {
+        if let Some(name) = model_config.and_then(|config| config.tokenizer.as_deref()) {
+            match Self::from_name(name) {
+                Some(tokenizer) => return tokenizer,
+                None => warn!("⚠️ 未知的 tokenizer 設定: {} | 模型: {}", name, model),
+            }
+        }
+
+        let model = model.to_lowercase();
+        let uses_o200k = model.contains("gpt-4o")
+            || model.contains("gpt-4.1")
+            || model.contains("gpt-5")
+            || ["o1", "o3", "o4"].iter().any(|prefix| model.starts_with(prefix));
+        if uses_o200k {
+            Tokenizer::O200k
+        } else {
+            Tokenizer::Cl100k
+        }
+    }
+
+    fn encoder(self) -> &'static CoreBPE {
+        static CL100K: OnceLock<CoreBPE> = OnceLock::new();
+        static O200K: OnceLock<CoreBPE> = OnceLock::new();
+        match self {
+            Tokenizer::Cl100k => CL100K.get_or_init(|| tiktoken_rs::cl100k_base().expect("載入 cl100k_base 編碼失敗")),
+            Tokenizer::O200k => O200K.get_or_init(|| tiktoken_rs::o200k_base().expect("載入 o200k_base 編碼失敗")),
+        }
+    }
+
+    /// 估算文字的 token 數量
+    pub fn count_tokens(self, text: &str) -> usize {
+        if text.is_empty() {
+            return 0;
+        }
+        self.encoder().encode_with_special_tokens(text).len()
+    }
+
+    /// 估算對話訊息（prompt）的 token 數量
+    pub fn count_messages(self, messages: &[Message]) -> usize {
+        messages.iter()
+            .map(|message| {
+                TOKENS_PER_MESSAGE
+                    + self.count_tokens(&message.role)
+                    + self.count_tokens(&message.content.text())
+                    + message.content.image_urls().len() * TOKENS_PER_IMAGE
+            })
+            .sum::<usize>()
+            + TOKENS_REPLY_PRIMING
+    }
+
+    /// 截斷文字使其不超過指定的 token 數量
+    pub fn truncate_to_tokens(self, text: &str, max_tokens: usize) -> String {
+        let encoder = self.encoder();
+        let tokens = encoder.encode_with_special_tokens(text);
+        if tokens.len() <= max_tokens {
+            return text.to_string();
+        }
+
+        // 截斷點可能落在多位元組字元中間，往前退到能正確解碼為止
+        let mut end = max_tokens;
+        while end > 0 {
+            if let Ok(decoded) = encoder.decode(tokens[..end].to_vec()) {
+                return decoded;
+            }
+            end -= 1;
+        }
+        String::new()
+    }
+}
@@ -0,0 +1,276 @@
+use nanoid::nanoid;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tracing::{debug, warn};
+
+use crate::types::*;
+use crate::utils::{partial_suffix_len, strip_code_fence};
+
+const TOOL_CALLS_OPEN: &str = "<tool_calls>";
+const TOOL_CALLS_CLOSE: &str = "</tool_calls>";
+
+#[derive(Clone, PartialEq)]
+pub enum ToolChoice {
+    Auto,
+    Required,
+    Function(String),
+}
+
+/// 單次請求的工具呼叫設定
+#[derive(Clone)]
+pub struct ToolConfig {
+    pub tools: Vec<ChatTool>,
+    pub choice: ToolChoice,
+    pub parallel: bool,
+    /// 使用舊版 functions / function_call 欄位，回應時也需使用舊格式
+    pub legacy: bool,
+}
+
+impl ToolConfig {
+    /// 從請求中取出工具設定，沒有工具或 tool_choice 為 none 時回傳 None
+    pub fn from_request(request: &ChatCompletionRequest) -> Option<Self> {
+        let (tools, choice, legacy) = match (&request.tools, &request.functions) {
+            (Some(tools), _) if !tools.is_empty() => (tools.clone(), request.tool_choice.as_ref(), false),
+            (_, Some(functions)) if !functions.is_empty() => {
+                let tools = functions.iter()
+                    .map(|function| ChatTool {
+                        r#type: "function".to_string(),
+                        function: function.clone(),
+                    })
+                    .collect();
+                (tools, request.function_call.as_ref(), true)
+            },
+            _ => return None,
+        };
+
+        let choice = match choice {
+            None => ToolChoice::Auto,
+            Some(Value::String(choice)) => match choice.as_str() {
+                "none" => {
+                    debug!("🔧 tool_choice 為 none，不注入工具定義");
+                    return None;
+                },
+                "required" => ToolChoice::Required,
+                _ => ToolChoice::Auto,
+            },
+            Some(value) => {
+                // {"type": "function", "function": {"name": ...}} 或舊版的 {"name": ...}
+                let name = value.pointer("/function/name")
+                    .or_else(|| value.get("name"))
+                    .and_then(Value::as_str);
+                match name {
+                    Some(name) => ToolChoice::Function(name.to_string()),
+                    None => ToolChoice::Auto,
+                }
+            }
+        };
+
+        debug!("🔧 啟用工具呼叫 | 工具數量: {} | 舊版格式: {}", tools.len(), legacy);
+
+        Some(Self {
+            tools,
+            choice,
+            parallel: request.parallel_tool_calls.unwrap_or(true) && !legacy,
+            legacy,
+        })
+    }
+
+    /// 產生說明工具用法的 system 提示
+    fn build_prompt(&self) -> String {
+        let definitions: Vec<Value> = self.tools.iter()
+            .map(|tool| json!({
+                "name": tool.function.name,
+                "description": tool.function.description,
+                "parameters": tool.function.parameters,
+            }))
+            .collect();
+
+        let mut prompt = format!(
+            "You have access to the following tools. To call tools, reply with a block in exactly this format and write nothing after it:\n\
+             {}\n[{{\"name\": \"<tool name>\", \"arguments\": {{<JSON arguments>}}}}]\n{}\n",
+            TOOL_CALLS_OPEN, TOOL_CALLS_CLOSE
+        );
+        if self.parallel {
+            prompt.push_str("You may call several tools at once by adding more objects to the array.\n");
+        } else {
+            prompt.push_str("Call at most one tool per reply.\n");
+        }
+        match &self.choice {
+            ToolChoice::Auto => prompt.push_str("Only call a tool when it is needed; otherwise answer normally.\n"),
+            ToolChoice::Required => prompt.push_str("You MUST call at least one tool in this reply.\n"),
+            ToolChoice::Function(name) => prompt.push_str(&format!("You MUST call the tool `{}` in this reply.\n", name)),
+        }
+        prompt.push_str("Tool results are returned to you inside <tool_result> blocks.\n\nAvailable tools:\n");
+        prompt.push_str(&serde_json::to_string_pretty(&definitions).unwrap_or_default());
+        prompt
+    }
+}
+
+/// 將工具呼叫與工具結果的歷史訊息轉為 Poe 可理解的文字，並注入工具說明
+pub fn prepare_messages(messages: Vec<Message>, tool_config: Option<&ToolConfig>) -> Vec<Message> {
+    let mut tool_names: HashMap<String, String> = HashMap::new();
+    let mut prepared: Vec<Message> = Vec::with_capacity(messages.len() + 1);
+    let mut last_was_tool_result = false;
+
+    for message in messages {
+        let role = message.role.clone();
+        match role.as_str() {
+            "assistant" if message.tool_calls.is_some() || message.function_call.is_some() => {
+                let calls: Vec<FunctionCall> = match (message.tool_calls, message.function_call) {
+                    (Some(tool_calls), _) => tool_calls.into_iter()
+                        .map(|call| {
+                            tool_names.insert(call.id, call.function.name.clone());
+                            call.function
+                        })
+                        .collect(),
+                    (None, Some(function_call)) => vec![function_call],
+                    (None, None) => Vec::new(),
+                };
+                let mut text = message.content.text();
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+                text.push_str(&render_tool_calls(&calls));
+                prepared.push(Message::text("assistant", text));
+                last_was_tool_result = false;
+            },
+            "tool" | "function" => {
+                let name = message.name.clone()
+                    .or_else(|| message.tool_call_id.as_ref().and_then(|id| tool_names.get(id).cloned()))
+                    .unwrap_or_default();
+                let block = format!(
+                    "<tool_result tool_call_id=\"{}\" name=\"{}\">\n{}\n</tool_result>",
+                    message.tool_call_id.as_deref().unwrap_or(""),
+                    name,
+                    message.content.text()
+                );
+                // 連續的工具結果合併為同一則 user 訊息
+                match prepared.last_mut() {
+                    Some(last) if last_was_tool_result => {
+                        let mut text = last.content.text();
+                        text.push_str("\n\n");
+                        text.push_str(&block);
+                        last.content = MessageContent::Text(text);
+                    },
+                    _ => prepared.push(Message::text("user", block)),
+                }
+                last_was_tool_result = true;
+            },
+            _ => {
+                prepared.push(message);
+                last_was_tool_result = false;
+            }
+        }
+    }
+
+    if let Some(config) = tool_config {
+        // 工具說明放在開頭的 system 訊息之後
+        let position = prepared.iter().take_while(|message| message.role == "system").count();
+        prepared.insert(position, Message::instruction(config.build_prompt()));
+    }
+
+    prepared
+}
+
+fn render_tool_calls(calls: &[FunctionCall]) -> String {
+    let items: Vec<Value> = calls.iter()
+        .map(|call| json!({
+            "name": call.name,
+            "arguments": serde_json::from_str::<Value>(&call.arguments)
+                .unwrap_or_else(|_| Value::String(call.arguments.clone())),
+        }))
+        .collect();
+    format!("{}\n{}\n{}", TOOL_CALLS_OPEN, serde_json::to_string(&items).unwrap_or_default(), TOOL_CALLS_CLOSE)
+}
+
+/// 從模型輸出中解析工具呼叫，回傳區塊以外的文字與工具呼叫列表
+pub fn parse_tool_calls(text: &str) -> Option<(String, Vec<ToolCall>)> {
+    let start = text.find(TOOL_CALLS_OPEN)?;
+    let after = &text[start + TOOL_CALLS_OPEN.len()..];
+    let (body, rest) = match after.find(TOOL_CALLS_CLOSE) {
+        Some(end) => (&after[..end], &after[end + TOOL_CALLS_CLOSE.len()..]),
+        None => (after, ""),
+    };
+
+    let items = match serde_json::from_str::<Value>(strip_code_fence(body)) {
+        Ok(Value::Array(items)) => items,
+        Ok(item @ Value::Object(_)) => vec![item],
+        Ok(_) => return None,
+        Err(e) => {
+            warn!("⚠️ 工具呼叫 JSON 解析失敗: {}", e);
+            return None;
+        }
+    };
+
+    let calls: Vec<ToolCall> = items.into_iter()
+        .filter_map(|item| {
+            let name = item.get("name")?.as_str()?.to_string();
+            let arguments = match item.get("arguments") {
+                Some(Value::String(arguments)) => arguments.clone(),
+                Some(arguments) => arguments.to_string(),
+                None => "{}".to_string(),
+            };
+            Some(ToolCall {
+                id: format!("call_{}", nanoid!(24)),
+                r#type: "function".to_string(),
+                function: FunctionCall { name, arguments },
+            })
+        })
+        .collect();
+
+    if calls.is_empty() {
+        return None;
+    }
+
+    debug!("🔧 解析到工具呼叫 | 數量: {}", calls.len());
+    let before = text[..start].trim();
+    let rest = rest.trim();
+    let remaining = match (before.is_empty(), rest.is_empty()) {
+        (_, true) => before.to_string(),
+        (true, false) => rest.to_string(),
+        (false, false) => format!("{}\n\n{}", before, rest),
+    };
+    Some((remaining, calls))
+}
+
+/// 串流模式下偵測工具呼叫區塊：區塊之前的文字照常輸出，區塊本身暫存至結束時解析
+#[derive(Default)]
+pub struct ToolCallDetector {
+    pending: String,
+    captured: Option<String>,
+}
+
+impl ToolCallDetector {
+    /// 推入新的文字片段，回傳可以立即輸出的部分
+    pub fn push(&mut self, text: &str) -> String {
+        if let Some(captured) = &mut self.captured {
+            captured.push_str(text);
+            return String::new();
+        }
+
+        self.pending.push_str(text);
+        if let Some(position) = self.pending.find(TOOL_CALLS_OPEN) {
+            debug!("🔧 偵測到工具呼叫區塊開頭");
+            let emit = self.pending[..position].to_string();
+            self.captured = Some(self.pending[position..].to_string());
+            self.pending.clear();
+            return emit;
+        }
+
+        let split = self.pending.len() - partial_suffix_len(&self.pending, TOOL_CALLS_OPEN);
+        self.pending.drain(..split).collect()
+    }
+
+    /// 串流結束時呼叫，回傳剩餘未輸出的文字與解析出的工具呼叫
+    pub fn finish(self) -> (String, Vec<ToolCall>) {
+        match self.captured {
+            Some(captured) => match parse_tool_calls(&captured) {
+                // 區塊之前的文字已經輸出，只需補上區塊之後的文字
+                Some((rest, calls)) => (self.pending + &rest, calls),
+                // 解析失敗時當作一般文字輸出
+                None => (captured, Vec::new()),
+            },
+            None => (self.pending, Vec::new()),
+        }
+    }
+}
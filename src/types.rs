@@ -1,4 +1,4 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Deserialize)]
 pub struct ChatCompletionRequest {
@@ -6,12 +6,189 @@ pub struct ChatCompletionRequest {
     pub messages: Vec<Message>,
     pub temperature: Option<f32>,
     pub stream: Option<bool>,
+    pub n: Option<u32>,
+    pub tools: Option<Vec<ChatTool>>,
+    pub tool_choice: Option<serde_json::Value>,
+    pub parallel_tool_calls: Option<bool>,
+    pub functions: Option<Vec<FunctionDefinition>>,
+    pub function_call: Option<serde_json::Value>,
+    pub response_format: Option<ResponseFormat>,
+    pub stop: Option<StopSequences>,
+    pub max_tokens: Option<u32>,
+    pub max_completion_tokens: Option<u32>,
+    pub stream_options: Option<StreamOptions>,
+    pub user: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Clone)]
+pub struct StreamOptions {
+    pub include_usage: Option<bool>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum StopSequences {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl StopSequences {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            StopSequences::Single(sequence) => vec![sequence],
+            StopSequences::Multiple(sequences) => sequences,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Deserialize, Clone)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    pub description: Option<String>,
+    pub schema: Option<serde_json::Value>,
+    pub strict: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Message {
     pub role: String,
-    pub content: String,
+    #[serde(default, deserialize_with = "deserialize_nullable_content")]
+    pub content: MessageContent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
+    /// 由代理自行注入的提示（工具說明、JSON 輸出要求等），不受模型的 system_prompt replace 影響
+    #[serde(skip)]
+    pub internal: bool,
+}
+
+impl Message {
+    /// 建立只含純文字內容的訊息
+    pub fn text(role: &str, text: impl Into<String>) -> Self {
+        Message {
+            role: role.to_string(),
+            content: MessageContent::Text(text.into()),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            function_call: None,
+            internal: false,
+        }
+    }
+
+    /// 建立代理內部使用的 system 提示
+    pub fn instruction(text: impl Into<String>) -> Self {
+        Message {
+            internal: true,
+            ..Message::text("system", text)
+        }
+    }
+}
+
+// 帶有 tool_calls 的 assistant 訊息，content 可能為 null
+fn deserialize_nullable_content<'de, D: Deserializer<'de>>(deserializer: D) -> Result<MessageContent, D::Error> {
+    Ok(Option::<MessageContent>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ChatTool {
+    pub r#type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    pub r#type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Default for MessageContent {
+    fn default() -> Self {
+        MessageContent::Text(String::new())
+    }
+}
+
+impl MessageContent {
+    /// 取出所有文字內容，多段文字以換行連接
+    pub fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// 取出所有圖片網址（http(s) 或 data URI）
+    pub fn image_urls(&self) -> Vec<&str> {
+        match self {
+            MessageContent::Text(_) => Vec::new(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::ImageUrl { image_url } => Some(image_url.url.as_str()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -21,7 +198,14 @@ pub struct ChatCompletionResponse {
     pub created: i64,
     pub model: String,
     pub choices: Vec<CompletionChoice>,
-    pub usage: Option<serde_json::Value>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
 }
 
 #[derive(Serialize)]
@@ -35,8 +219,12 @@ pub struct CompletionChoice {
 #[derive(Serialize)]
 pub struct CompletionMessage {
     pub role: String,
-    pub content: String,
+    pub content: Option<String>,
     pub refusal: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
 }
 
 #[derive(Serialize)]
@@ -46,6 +234,8 @@ pub struct ChatCompletionChunk {
     pub created: i64,
     pub model: String,
     pub choices: Vec<Choice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
 }
 
 #[derive(Serialize)]
@@ -55,11 +245,23 @@ pub struct Choice {
     pub finish_reason: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 pub struct Delta {
     pub role: Option<String>,
     pub content: Option<String>,
     pub refusal: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
+}
+
+#[derive(Serialize)]
+pub struct ToolCallDelta {
+    pub index: u32,
+    pub id: String,
+    pub r#type: String,
+    pub function: FunctionCall,
 }
 
 #[derive(Serialize)]
@@ -75,13 +277,282 @@ pub struct OpenAIError {
     pub param: Option<String>,
 }
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Deserialize)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub prompt: Option<CompletionPrompt>,
+    pub suffix: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub stream: Option<bool>,
+    pub n: Option<u32>,
+    pub stop: Option<StopSequences>,
+    pub echo: Option<bool>,
+    pub stream_options: Option<StreamOptions>,
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum CompletionPrompt {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+#[derive(Serialize)]
+pub struct TextCompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<TextCompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize)]
+pub struct TextCompletionChoice {
+    pub text: String,
+    pub index: u32,
+    pub logprobs: Option<serde_json::Value>,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AnthropicMessagesRequest {
+    pub model: String,
+    pub messages: Vec<AnthropicMessage>,
+    pub system: Option<AnthropicContent>,
+    pub max_tokens: u32,
+    pub stop_sequences: Option<Vec<String>>,
+    pub temperature: Option<f32>,
+    pub stream: Option<bool>,
+    pub tools: Option<Vec<AnthropicTool>>,
+    pub tool_choice: Option<serde_json::Value>,
+    pub metadata: Option<AnthropicMetadata>,
+}
+
+#[derive(Deserialize)]
+pub struct AnthropicMetadata {
+    pub user_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AnthropicMessage {
+    pub role: String,
+    pub content: AnthropicContent,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum AnthropicContent {
+    Text(String),
+    Blocks(Vec<AnthropicContentBlock>),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicContentBlock {
+    Text { text: String },
+    Image { source: AnthropicImageSource },
+    ToolUse { id: String, name: String, input: serde_json::Value },
+    ToolResult {
+        tool_use_id: String,
+        content: Option<AnthropicContent>,
+        is_error: Option<bool>,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicImageSource {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+}
+
+impl AnthropicContent {
+    /// 取出所有文字區塊，多段文字以換行連接
+    pub fn text(&self) -> String {
+        match self {
+            AnthropicContent::Text(text) => text.clone(),
+            AnthropicContent::Blocks(blocks) => blocks
+                .iter()
+                .filter_map(|block| match block {
+                    AnthropicContentBlock::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AnthropicTool {
+    pub name: String,
+    pub description: Option<String>,
+    pub input_schema: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct AnthropicErrorResponse {
+    pub r#type: String,
+    pub error: AnthropicError,
+}
+
+#[derive(Serialize)]
+pub struct AnthropicError {
+    pub r#type: String,
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResponsesRequest {
+    pub model: String,
+    pub input: ResponsesInput,
+    pub instructions: Option<String>,
+    pub previous_response_id: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_output_tokens: Option<u32>,
+    pub stream: Option<bool>,
+    pub store: Option<bool>,
+    pub tools: Option<Vec<ResponsesTool>>,
+    pub tool_choice: Option<serde_json::Value>,
+    pub parallel_tool_calls: Option<bool>,
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ResponsesInput {
+    Text(String),
+    Items(Vec<ResponsesInputItem>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ResponsesInputItem {
+    Typed(ResponsesTypedItem),
+    // 省略 type 的簡易訊息格式 {"role": ..., "content": ...}
+    Message { role: String, content: ResponsesContent },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponsesTypedItem {
+    Message { role: String, content: ResponsesContent },
+    FunctionCall { call_id: String, name: String, arguments: String },
+    FunctionCallOutput { call_id: String, output: String },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ResponsesContent {
+    Text(String),
+    Parts(Vec<ResponsesContentPart>),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponsesContentPart {
+    InputText { text: String },
+    OutputText { text: String },
+    InputImage { image_url: Option<String> },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize)]
+pub struct ResponsesTool {
+    pub r#type: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub parameters: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+pub struct OllamaChatRequest {
+    pub model: String,
+    pub messages: Vec<OllamaMessage>,
+    pub stream: Option<bool>,
+    pub options: Option<OllamaOptions>,
+}
+
+#[derive(Deserialize)]
+pub struct OllamaGenerateRequest {
+    pub model: String,
+    #[serde(default)]
+    pub prompt: String,
+    pub system: Option<String>,
+    pub images: Option<Vec<String>>,
+    pub stream: Option<bool>,
+    pub options: Option<OllamaOptions>,
+}
+
+#[derive(Deserialize)]
+pub struct OllamaMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+    /// 不含 data URI 前綴的 base64 圖片
+    pub images: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct OllamaOptions {
+    pub temperature: Option<f32>,
+    /// -1 表示不限制
+    pub num_predict: Option<i64>,
+    pub stop: Option<Vec<String>>,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub(crate) struct Config {
     pub(crate) enable: Option<bool>,
     pub(crate) models: std::collections::HashMap<String, ModelConfig>,
+    /// 未列於 models 的模型名稱依序比對的規則
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) rules: Vec<ModelRule>,
+    /// 代理金鑰群組的模型存取設定，以金鑰的 group 欄位引用
+    #[serde(default, skip_serializing_if = "std::collections::HashMap::is_empty")]
+    pub(crate) groups: std::collections::HashMap<String, ModelAccess>,
+    /// 由 models 與 rules 建立的查詢索引，載入時產生
+    #[serde(skip)]
+    pub(crate) index: crate::routing::ModelIndex,
+}
+
+/// 金鑰可使用的模型；allow 與 deny 為 glob，比對請求的名稱、models.yaml 項目及 Poe bot
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ModelAccess {
+    /// 為空時允許所有模型
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) allow: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) deny: Vec<String>,
+    /// 只有此金鑰可用的模型名稱，對應到任何公開名稱或 Poe bot，不受 allow / deny 限制
+    #[serde(default, skip_serializing_if = "std::collections::HashMap::is_empty")]
+    pub(crate) mappings: std::collections::HashMap<String, String>,
+    /// 編譯後的 allow / deny，第一次比對時建立
+    #[serde(skip)]
+    pub(crate) patterns: crate::routing::AccessPatterns,
+}
+
+/// 以 glob 或正規表示式將模型名稱對應到 Poe bot，target 可用 `$1` 引用擷取群組
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct ModelRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pattern: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) regex: Option<String>,
+    pub(crate) target: String,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub(crate) struct ModelConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) mapping: Option<String>,
@@ -89,4 +560,210 @@ pub(crate) struct ModelConfig {
     pub(crate) replace_response: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) enable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tokenizer: Option<String>,
+    /// 實際請求的 Poe bot；設定後此項目為虛擬模型，未設定時使用項目名稱
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) target: Option<String>,
+    /// 額外公開的模型名稱
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) aliases: Option<Vec<String>>,
+    /// 上游在輸出任何內容前失敗時依序改用的模型
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) fallbacks: Option<Vec<String>>,
+    /// 依權重將流量分配到多個上游 bot
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) routing: Option<TrafficSplit>,
+    /// 依取樣比例將請求複製到另一個 bot 並記錄輸出，不影響回應
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) shadow: Option<ShadowTraffic>,
+    /// 請求未指定時使用的參數
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) defaults: Option<ModelParams>,
+    /// 無論請求如何指定都強制使用的參數
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) overrides: Option<ModelParams>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) system_prompt: Option<SystemPrompt>,
+    /// 參數允許的範圍，超出時夾回邊界
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) clamp: Option<ParamClamp>,
+    /// 每個客戶端金鑰使用此模型的速率上限
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) rate_limit: Option<RateLimits>,
+    /// 每次請求消耗的 Poe 運算點數，用於代理金鑰的點數配額
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) points: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub(crate) struct ModelParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_tokens: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct SystemPrompt {
+    #[serde(default)]
+    pub(crate) mode: SystemPromptMode,
+    pub(crate) content: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SystemPromptMode {
+    /// 放在所有訊息之前
+    #[default]
+    Prepend,
+    /// 放在開頭的 system 訊息之後
+    Append,
+    /// 取代請求中的 system 訊息
+    Replace,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub(crate) struct ParamClamp {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) temperature: Option<ParamRange<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_tokens: Option<ParamRange<u32>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct TrafficSplit {
+    pub(crate) targets: Vec<WeightedTarget>,
+    /// 未設定時每個請求隨機分配
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sticky: Option<StickyBy>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct WeightedTarget {
+    pub(crate) model: String,
+    pub(crate) weight: u32,
+}
+
+/// 固定分配結果的依據
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StickyBy {
+    /// 依請求的 API 金鑰
+    Key,
+    /// 依請求的 user 欄位，未提供時隨機分配
+    User,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct ShadowTraffic {
+    pub(crate) model: String,
+    /// 複製請求的比例，介於 0 到 1
+    pub(crate) sample_rate: f64,
+}
+
+/// 速率上限，未設定的項目不限制
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RateLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) requests_per_minute: Option<u32>,
+    /// 依請求大小及輸出上限估計的 token 數
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tokens_per_minute: Option<u32>,
+    /// 同時進行中的請求數，串流回應在結束前都會佔用
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) concurrent_streams: Option<u32>,
+}
+
+impl RateLimits {
+    /// 逐項以 fallback 補上未設定的上限
+    pub(crate) fn or(self, fallback: RateLimits) -> RateLimits {
+        RateLimits {
+            requests_per_minute: self.requests_per_minute.or(fallback.requests_per_minute),
+            tokens_per_minute: self.tokens_per_minute.or(fallback.tokens_per_minute),
+            concurrent_streams: self.concurrent_streams.or(fallback.concurrent_streams),
+        }
+    }
+
+    pub(crate) fn is_unlimited(&self) -> bool {
+        *self == RateLimits::default()
+    }
+
+    /// 設為 0 的項目名稱，0 會擋下所有請求因此視為無效
+    pub(crate) fn zero_field(&self) -> Option<&'static str> {
+        [
+            ("requests_per_minute", self.requests_per_minute),
+            ("tokens_per_minute", self.tokens_per_minute),
+            ("concurrent_streams", self.concurrent_streams),
+        ]
+        .into_iter()
+        .find(|(_, value)| *value == Some(0))
+        .map(|(name, _)| name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub(crate) struct ParamRange<T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) min: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max: Option<T>,
+}
+
+impl<T: PartialOrd + Copy> ParamRange<T> {
+    pub(crate) fn apply(&self, value: T) -> T {
+        match (self.min, self.max) {
+            (Some(min), _) if value < min => min,
+            (_, Some(max)) if value > max => max,
+            _ => value,
+        }
+    }
+}
+
+impl ModelConfig {
+    /// 依 overrides > 請求 > defaults 的順序決定 temperature，再套用 clamp
+    pub(crate) fn resolve_temperature(&self, requested: Option<f32>) -> Option<f32> {
+        let value = self.overrides.as_ref().and_then(|params| params.temperature)
+            .or(requested)
+            .or_else(|| self.defaults.as_ref().and_then(|params| params.temperature))?;
+        Some(match self.clamp.as_ref().and_then(|clamp| clamp.temperature) {
+            Some(range) => range.apply(value),
+            None => value,
+        })
+    }
+
+    /// 依 overrides > 請求 > defaults 的順序決定 max_tokens，再套用 clamp
+    pub(crate) fn resolve_max_tokens(&self, requested: Option<u32>) -> Option<u32> {
+        let value = self.overrides.as_ref().and_then(|params| params.max_tokens)
+            .or(requested)
+            .or_else(|| self.defaults.as_ref().and_then(|params| params.max_tokens));
+        let range = self.clamp.as_ref().and_then(|clamp| clamp.max_tokens);
+        match (value, range) {
+            (Some(value), Some(range)) => Some(range.apply(value)),
+            // 未指定但有上限時以上限為準
+            (None, Some(range)) => range.max,
+            (value, None) => value,
+        }
+    }
+
+    /// 依 system_prompt 設定插入或取代 system 訊息
+    pub(crate) fn apply_system_prompt(&self, messages: &mut Vec<Message>) {
+        let Some(system_prompt) = &self.system_prompt else {
+            return;
+        };
+        match system_prompt.mode {
+            SystemPromptMode::Prepend => {
+                messages.insert(0, Message::text("system", system_prompt.content.clone()));
+            },
+            SystemPromptMode::Append => {
+                let position = messages.iter().take_while(|message| message.role == "system").count();
+                messages.insert(position, Message::text("system", system_prompt.content.clone()));
+            },
+            SystemPromptMode::Replace => {
+                messages.retain(|message| message.role != "system" || message.internal);
+                messages.insert(0, Message::text("system", system_prompt.content.clone()));
+            },
+        }
+    }
 }
\ No newline at end of file
@@ -22,4 +22,92 @@ pub fn format_duration(duration: std::time::Duration) -> String {
     } else {
         format!("{}ms", duration.as_millis())
     }
-}
\ No newline at end of file
+}
+
+/// 移除包住整段文字的 markdown 程式碼區塊標記（例如 ```json ... ```）
+pub fn strip_code_fence(text: &str) -> &str {
+    let trimmed = text.trim();
+    let Some(rest) = trimmed.strip_prefix("```") else {
+        return trimmed;
+    };
+    let Some(body) = rest.strip_suffix("```") else {
+        return trimmed;
+    };
+    // 去掉語言標記所在的第一行
+    match body.split_once('\n') {
+        Some((_, content)) => content.trim(),
+        None => body.trim(),
+    }
+}
+
+/// 計算 text 結尾有多少位元組可能是 pattern 的開頭，串流時需暫時保留不輸出
+pub fn partial_suffix_len(text: &str, pattern: &str) -> usize {
+    (1..pattern.len())
+        .rev()
+        .find(|&len| pattern.is_char_boundary(len) && text.ends_with(&pattern[..len]))
+        .unwrap_or(0)
+}
+
+/// 先寫入同目錄的暫存檔再改名，寫到一半中斷時不會留下損毀的檔案
+pub fn write_atomic(path: &std::path::Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    write_then_rename(path, contents.as_ref(), false)
+}
+
+/// 同 write_atomic，但檔案只有擁有者可以讀寫（Unix 上為 0600），用於保存金鑰或密碼雜湊
+pub fn write_atomic_private(path: &std::path::Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    write_then_rename(path, contents.as_ref(), true)
+}
+
+/// 將無法載入的檔案另存一份（`<檔名>.corrupt-<Unix 時間>`），回傳備份的路徑
+pub fn backup_file(path: &std::path::Path) -> std::io::Result<std::path::PathBuf> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".corrupt-{}", timestamp));
+    let backup = path.with_file_name(file_name);
+    std::fs::copy(path, &backup)?;
+    Ok(backup)
+}
+
+/// 資料檔載入失敗時先備份，回傳附上備份位置的錯誤訊息，供啟動時結束程式
+pub fn load_failure(what: &str, path: &std::path::Path, error: &str) -> String {
+    let backup = match backup_file(path) {
+        Ok(backup) => format!("已備份至 {}", backup.display()),
+        Err(e) => format!("備份失敗: {}", e),
+    };
+    format!("載入{}檔案 {} 失敗: {}（{}），請修正或移除該檔案後再啟動", what, path.display(), error, backup)
+}
+
+fn write_then_rename(path: &std::path::Path, contents: &[u8], private: bool) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    let temp = path.with_file_name(file_name);
+    // 權限只在建立檔案時設定，先移除上次殘留的暫存檔
+    let _ = std::fs::remove_file(&temp);
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    let mut file = options.open(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp, path)
+}
+
+/// 比對時間與內容無關，只取決於長度，用於比對 token 等機密值
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}